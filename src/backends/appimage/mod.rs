@@ -0,0 +1,505 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::{env, fs};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const URL_KEY: &str = "url";
+const PATH_KEY: &str = "path";
+const SHA256_KEY: &str = "sha256";
+const DESKTOP_ENTRY_KEY: &str = "desktop_entry";
+const HOOK_KEY: &str = "post_hook";
+
+const EXTENSION: &str = ".AppImage";
+const PARTIAL_EXTENSION: &str = ".part";
+
+// Fetches the image next to its destination and only moves it in place once
+// the checksum matches. Arguments: source, destination, sha256.
+const INSTALL_SCRIPT: &str = concat!(
+    r#"set -e; mkdir -p "$(dirname "$2")"; "#,
+    r#"case "$1" in http://*|https://*) curl --fail --location --silent --show-error --output "$2.part" "$1";; "#,
+    r#"*) cp "$1" "$2.part";; esac; "#,
+    r#"echo "$3  $2.part" | sha256sum --check --status || "#,
+    r#"{ rm -f "$2.part"; echo "sha256 mismatch for $1" >&2; exit 1; }; "#,
+    r#"chmod +x "$2.part"; mv "$2.part" "$2""#,
+);
+
+// Arguments: desktop file, name, image
+const DESKTOP_SCRIPT: &str = concat!(
+    r#"mkdir -p "$(dirname "$1")" && "#,
+    r#"printf '[Desktop Entry]\nType=Application\nName=%s\nExec="%s" %%U\nTerminal=false\n' "$2" "$3" > "$1""#,
+);
+
+#[derive(Clone, Debug)]
+pub struct AppImageOpts {
+    source: String,
+    sha256: String,
+    desktop_entry: bool,
+    post_hook: Option<Closure>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AppImage {
+    dir: String,
+    desktop_dir: String,
+    images: HashMap<String, AppImageOpts>,
+}
+
+impl Backend for AppImage {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let data_dir = get_data_dir()?;
+
+        let images = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for AppImage"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The package list in AppImage is not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed appimages");
+        Ok(AppImage {
+            dir: data_dir.clone() + "/supac/appimages",
+            desktop_dir: data_dir + "/applications",
+            images,
+        })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed = self.get_installed_images()?;
+
+        Ok(self
+            .get_extra_images(&installed)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, selection: Selection) -> Result<Plan> {
+        let installed = self.get_installed_images()?;
+        // Hashing every image is only needed to tell if it is up to date
+        let checksums = get_checksums(
+            installed
+                .iter()
+                .filter(|_| selection.additions())
+                .filter(|(name, _)| self.images.contains_key(*name))
+                .map(|(_, path)| path.as_str()),
+        )?;
+
+        let mut plan = Plan::default();
+
+        for (name, spec) in self.images.iter().collect::<BTreeMap<_, _>>() {
+            let image = self.image_path(name);
+            let desktop_file = self.desktop_path(name);
+            let has_desktop_file = Path::new(&desktop_file).exists();
+
+            let up_to_date = checksums
+                .get(&image)
+                .is_some_and(|sha256| *sha256 == spec.sha256);
+
+            if !up_to_date {
+                plan.push(
+                    Action::Install,
+                    [name],
+                    [
+                        "sh",
+                        "-c",
+                        INSTALL_SCRIPT,
+                        "sh",
+                        &spec.source,
+                        &image,
+                        &spec.sha256,
+                    ],
+                    Perms::User,
+                );
+                plan.push_hook(spec.post_hook.as_ref());
+            }
+
+            let desktop_label = [name, ".desktop"].concat();
+
+            match (spec.desktop_entry, has_desktop_file) {
+                (true, false) => plan.push(
+                    Action::Install,
+                    [desktop_label],
+                    [
+                        "sh",
+                        "-c",
+                        DESKTOP_SCRIPT,
+                        "sh",
+                        &desktop_file,
+                        name,
+                        &image,
+                    ],
+                    Perms::User,
+                ),
+                (false, true) => plan.push(
+                    Action::Remove,
+                    [desktop_label],
+                    ["rm", "--force", &desktop_file],
+                    Perms::User,
+                ),
+                _ => {}
+            }
+        }
+
+        let extra = self.get_extra_images(&installed);
+
+        // Desktop files that were never created are skipped by --force
+        plan.push(
+            Action::Remove,
+            extra.iter().copied(),
+            ["rm".to_owned(), "--force".to_owned()]
+                .into_iter()
+                .chain(extra.iter().map(|name| installed[*name].clone()))
+                .chain(extra.iter().map(|name| self.desktop_path(name))),
+            Perms::User,
+        );
+
+        Ok(plan)
+    }
+
+    fn update(&self, _opts: &UpdateCommand) -> Result<()> {
+        log::info!("AppImages are pinned by their sha256, change it along with the url to update");
+        Ok(())
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "AppImage",
+            &[
+                PACKAGE_KEY,
+                URL_KEY,
+                PATH_KEY,
+                SHA256_KEY,
+                DESKTOP_ENTRY_KEY,
+                HOOK_KEY,
+            ],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "AppImage")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("curl".to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.images.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    // Interrupted downloads leave their partial files behind
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let partial: BTreeSet<_> = list_dir(&self.dir)?
+            .into_iter()
+            .filter(|file| file.ends_with(PARTIAL_EXTENSION))
+            .map(|file| [self.dir.as_str(), file.as_str()].join("/"))
+            .collect();
+
+        if partial.is_empty() {
+            log::info!("No partial downloads to remove");
+            return Ok(());
+        }
+
+        if !opts.no_confirm
+            && !confirmation_prompt("Do you want to remove partial downloads?", &partial)?
+        {
+            return Ok(());
+        }
+
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        command_action(
+            ["rm".to_owned(), "--force".to_owned()]
+                .into_iter()
+                .chain(partial),
+            Perms::User,
+        )
+        .inspect(|_| log::info!("Removed partial downloads"))
+        .map_err(|e| nest_errors!("Failed to remove partial downloads", e))
+    }
+}
+
+impl AppImage {
+    fn image_path(&self, name: &str) -> String {
+        [self.dir.as_str(), "/", name, EXTENSION].concat()
+    }
+
+    // Prefixed so that desktop files not created by supac are never touched
+    fn desktop_path(&self, name: &str) -> String {
+        [self.desktop_dir.as_str(), "/supac-", name, ".desktop"].concat()
+    }
+
+    /// Maps the name of every image in the managed directory to its path
+    fn get_installed_images(&self) -> Result<HashMap<String, String>> {
+        Ok(list_dir(&self.dir)?
+            .into_iter()
+            .filter_map(|file| {
+                let name = file.strip_suffix(EXTENSION)?.to_owned();
+                let path = [self.dir.as_str(), file.as_str()].join("/");
+
+                Some((name, path))
+            })
+            .collect())
+    }
+
+    fn get_extra_images<'a>(&self, installed: &'a HashMap<String, String>) -> BTreeSet<&'a str> {
+        installed
+            .keys()
+            .filter(|name| !self.images.contains_key(*name))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+fn list_dir(dir: &str) -> Result<Box<[String]>> {
+    if !Path::new(dir).is_dir() {
+        log::info!("{dir} does not exist. Assuming no appimages are installed.");
+        return Ok(Box::new([]));
+    }
+
+    fs::read_dir(dir)
+        .map_err(|e| nest_errors!("Failed to read {dir}", e))?
+        .map(|entry| {
+            entry
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .map_err(|e| nest_errors!("Failed to read an entry of {dir}", e))
+        })
+        .collect()
+}
+
+/// Maps every given file to its sha256
+fn get_checksums<'a, I>(files: I) -> Result<HashMap<String, String>>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let files: Vec<_> = files.into_iter().collect();
+
+    if files.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let stdout = run_command_for_stdout(["sha256sum"].into_iter().chain(files), Perms::User, false)
+        .map_err(|e| nest_errors!("Failed to compute checksums of appimages", e))?;
+
+    Ok(parse_checksums(&stdout))
+}
+
+fn parse_checksums(stdout: &str) -> HashMap<String, String> {
+    stdout
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(sha256, file)| (file.to_owned(), sha256.to_owned()))
+        .collect()
+}
+
+fn get_data_dir() -> Result<String> {
+    env::var("XDG_DATA_HOME").or_else(|e| -> Result<String> {
+        log::debug!("Encountered error: {e}");
+        log::debug!("Using the default: ~/.local/share");
+        let home = env::var("HOME")?;
+        Ok(home + "/.local/share")
+    })
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, AppImageOpts)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The package-spec is not a record", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No appimage name mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The appimage name was not a string", e))?
+        .to_owned();
+
+    if package.is_empty() || package.contains('/') {
+        return Err(mod_err!(
+            "{package} is not a valid file name for an appimage"
+        ));
+    }
+
+    let url = match record.get(URL_KEY) {
+        Some(url) => Some(
+            url.as_str()
+                .map_err(|e| nest_errors!("url for {package} is not a string", e))?,
+        ),
+        None => None,
+    };
+
+    let path = match record.get(PATH_KEY) {
+        Some(path) => Some(
+            path.as_str()
+                .map_err(|e| nest_errors!("path for {package} is not a string", e))?,
+        ),
+        None => None,
+    };
+
+    let source = match (url, path) {
+        (Some(_), Some(_)) => return Err(mod_err!("{package} has both a url and a path")),
+        (Some(source), None) | (None, Some(source)) => source.to_owned(),
+        (None, None) => return Err(mod_err!("{package} has neither a url nor a path")),
+    };
+
+    let sha256 = record
+        .get(SHA256_KEY)
+        .ok_or_else(|| mod_err!("No sha256 mentioned for {package}"))?
+        .as_str()
+        .map_err(|e| nest_errors!("sha256 for {package} is not a string", e))?
+        .to_lowercase();
+
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(mod_err!(
+            "sha256 for {package} is not a valid sha256 checksum"
+        ));
+    }
+
+    let desktop_entry = match record.get(DESKTOP_ENTRY_KEY) {
+        Some(desktop_entry) => desktop_entry
+            .as_bool()
+            .map_err(|e| nest_errors!("desktop_entry for {package} is not a bool", e))?,
+        None => false,
+    };
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {package} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((
+        package,
+        AppImageOpts {
+            source,
+            sha256,
+            desktop_entry,
+            post_hook,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    const SHA256: &str = "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08";
+
+    #[test]
+    fn value_to_pkgspec_url() {
+        let value = Value::record(
+            Record::from_raw_cols_vals(
+                ["package", "url", "sha256", "desktop_entry"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("nvim", Span::test_data()),
+                    Value::string("https://example.com/nvim.appimage", Span::test_data()),
+                    Value::string(SHA256, Span::test_data()),
+                    Value::bool(true, Span::test_data()),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        let (name, spec) = value_to_pkgspec(&value).unwrap();
+        assert_eq!(name, "nvim");
+        assert_eq!(spec.source, "https://example.com/nvim.appimage");
+        assert_eq!(spec.sha256, SHA256.to_lowercase());
+        assert!(spec.desktop_entry);
+    }
+
+    #[test]
+    fn value_to_pkgspec_invalid() {
+        let both = Value::record(
+            Record::from_raw_cols_vals(
+                ["package", "url", "path", "sha256"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("nvim", Span::test_data()),
+                    Value::string("https://example.com/nvim.appimage", Span::test_data()),
+                    Value::string("/tmp/nvim.appimage", Span::test_data()),
+                    Value::string(SHA256, Span::test_data()),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+        let bad_sha256 = Value::record(
+            Record::from_raw_cols_vals(
+                ["package", "path", "sha256"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("nvim", Span::test_data()),
+                    Value::string("/tmp/nvim.appimage", Span::test_data()),
+                    Value::string("abc", Span::test_data()),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        assert!(value_to_pkgspec(&both).is_err());
+        assert!(value_to_pkgspec(&bad_sha256).is_err());
+    }
+
+    #[test]
+    fn parse_checksums_by_file() {
+        let stdout = [
+            SHA256,
+            "  /home/user/.local/share/supac/appimages/nvim.AppImage\n",
+        ]
+        .concat();
+
+        let checksums = parse_checksums(&stdout);
+        assert_eq!(
+            checksums["/home/user/.local/share/supac/appimages/nvim.AppImage"],
+            SHA256
+        );
+    }
+}
@@ -0,0 +1,546 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::commands::{Perms, dry_run_command, run_command, run_command_for_stdout};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::{Backend, needed_packages};
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const VERSION_KEY: &str = "version";
+const RELEASE_KEY: &str = "release";
+const HOOK_KEY: &str = "post_hook";
+
+#[derive(Clone, Debug)]
+pub struct AptOpts {
+    version: Option<String>,
+    release: Option<String>,
+    post_hook: Option<Closure>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Apt {
+    packages: HashMap<String, AptOpts>,
+}
+
+impl Backend for Apt {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for Apt"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The package list in Apt is not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed apt packages");
+        Ok(Apt { packages })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let manual = get_manual_packages()?;
+
+        Ok(self
+            .get_extra_packages(&manual)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, no_confirm: bool, selection: Selection) -> Result<Plan> {
+        let manual = get_manual_packages()?;
+        let installed = get_installed_packages()?;
+
+        let mut plan = Plan::default();
+
+        // Packages are installed per target release, since `-t` applies to
+        // the whole command
+        let mut missing: BTreeMap<Option<&str>, Vec<(&str, String)>> = BTreeMap::new();
+        let mut reason_change = BTreeSet::new();
+
+        for (package, spec) in self.packages.iter().collect::<BTreeMap<_, _>>() {
+            let up_to_date = match (installed.get(package), &spec.version) {
+                (None, _) => false,
+                (Some(installed), Some(version)) => installed == version,
+                (Some(_), None) => true,
+            };
+
+            if !up_to_date {
+                missing
+                    .entry(spec.release.as_deref())
+                    .or_default()
+                    .push((package, install_target(package, spec)));
+                plan.push_hook(spec.post_hook.as_ref());
+            } else if !manual.contains(package) {
+                // The hook runs even if the package status was only changed
+                // from automatic to manual
+                reason_change.insert(package.as_str());
+                plan.push_hook(spec.post_hook.as_ref());
+            }
+        }
+
+        let assume_yes: Option<&'static str> = no_confirm.then_some("--assume-yes");
+
+        for (release, packages) in missing {
+            let release = ["--target-release"]
+                .into_iter()
+                .chain(release)
+                .filter(|_| release.is_some());
+
+            plan.push(
+                Action::Install,
+                packages.iter().map(|(package, _)| *package),
+                ["apt-get", "install"]
+                    .into_iter()
+                    .chain(assume_yes)
+                    .chain(release)
+                    .chain(packages.iter().map(|(_, target)| target.as_str())),
+                Perms::Root,
+            );
+        }
+
+        plan.push(
+            Action::MarkExplicit,
+            reason_change.iter().copied(),
+            ["apt-mark", "manual"]
+                .into_iter()
+                .chain(reason_change.iter().copied()),
+            Perms::Root,
+        );
+
+        // Finding out what depends on the extra packages takes a while, and
+        // is only needed to remove them
+        if !selection.removals() {
+            return Ok(plan);
+        }
+
+        let extra = self.get_extra_packages(&manual);
+
+        // Like pacman's --unneeded, packages that something else still
+        // depends on are marked as automatically installed instead of taking
+        // their dependents down with them
+        let dependents = get_dependents(&extra)?;
+        let needed = needed_packages(&dependents);
+        let (needed, unneeded): (Vec<_>, Vec<_>) = extra
+            .iter()
+            .copied()
+            .partition(|package| needed.contains(package));
+
+        // Purging also takes down whatever depends on the packages, which
+        // the simulation shows on top of them
+        let simulated = get_purged_packages(&unneeded, false)?;
+        let purged: BTreeSet<_> = unneeded
+            .iter()
+            .copied()
+            .chain(simulated.iter().map(String::as_str))
+            .collect();
+        let autoremoved: BTreeSet<_> = get_purged_packages(&unneeded, true)?
+            .into_iter()
+            .filter(|package| !purged.contains(package.as_str()))
+            .collect();
+
+        plan.push(
+            Action::Remove,
+            purged.iter().copied(),
+            ["apt-get", "purge"]
+                .into_iter()
+                .chain(assume_yes)
+                .chain(unneeded.iter().copied()),
+            Perms::Root,
+        );
+
+        plan.push(
+            Action::MarkDependency,
+            needed.iter().copied(),
+            ["apt-mark", "auto"]
+                .into_iter()
+                .chain(needed.iter().copied()),
+            Perms::Root,
+        );
+
+        // Dependencies only the purged packages needed are left behind, and
+        // autoremove takes them along with anything else nothing needs
+        plan.push(
+            Action::Remove,
+            autoremoved.iter().map(String::as_str),
+            ["apt-get", "autoremove", "--purge"]
+                .into_iter()
+                .chain(assume_yes),
+            Perms::Root,
+        );
+
+        Ok(plan)
+    }
+
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        let assume_yes = ["--assume-yes"].into_iter().filter(|_| opts.no_confirm);
+
+        command_action(
+            ["apt-get", "update"].into_iter().chain(assume_yes.clone()),
+            Perms::Root,
+        )
+        .map_err(|e| nest_errors!("Failed to update apt package lists", e))?;
+
+        command_action(
+            ["apt-get", "upgrade"].into_iter().chain(assume_yes),
+            Perms::Root,
+        )
+        .inspect(|_| log::info!("Successfully upgraded apt packages"))
+        .map_err(|e| nest_errors!("Failed to upgrade apt packages", e))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Apt",
+            &[PACKAGE_KEY, VERSION_KEY, RELEASE_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "Apt")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("apt-get".to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        command_action(["apt-get", "clean"], Perms::Root)
+            .inspect(|_| log::info!("Removed apt's cache"))
+            .map_err(|e| nest_errors!("Failed to clean cache for apt", e))
+    }
+}
+
+impl Apt {
+    fn get_extra_packages<'a>(&self, manual: &'a HashSet<String>) -> BTreeSet<&'a str> {
+        manual
+            .iter()
+            .filter(|package| !self.packages.contains_key(*package))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+fn install_target(package: &str, spec: &AptOpts) -> String {
+    match &spec.version {
+        Some(version) => [package, "=", version].concat(),
+        None => package.to_owned(),
+    }
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, AptOpts)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The package-spec is not a record", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No package mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The package was not a string", e))?
+        .to_owned();
+
+    let version = match record.get(VERSION_KEY) {
+        Some(version) => Some(
+            version
+                .as_str()
+                .map_err(|e| nest_errors!("Version for {package} is not a string", e))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let release = match record.get(RELEASE_KEY) {
+        Some(release) => Some(
+            release
+                .as_str()
+                .map_err(|e| nest_errors!("Target release for {package} is not a string", e))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {package} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((
+        package,
+        AptOpts {
+            version,
+            release,
+            post_hook,
+        },
+    ))
+}
+
+fn get_manual_packages() -> Result<HashSet<String>> {
+    let packages = run_command_for_stdout(["apt-mark", "showmanual"], Perms::User, false)
+        .map_err(|e| nest_errors!("Failed to get manually installed packages", e))?;
+
+    let packages = packages
+        .lines()
+        .map(str::trim)
+        .filter(|package| !package.is_empty())
+        .map(ToOwned::to_owned)
+        .collect();
+
+    Ok(packages)
+}
+
+/// Maps every one of the packages to the installed packages that depend on or
+/// recommend it, since autoremove keeps recommended packages too
+fn get_dependents<'a>(packages: &BTreeSet<&'a str>) -> Result<BTreeMap<&'a str, HashSet<String>>> {
+    if packages.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let rdepends = run_command_for_stdout(
+        [
+            "apt-cache",
+            "rdepends",
+            "--installed",
+            "--no-suggests",
+            "--no-conflicts",
+            "--no-breaks",
+            "--no-replaces",
+            "--no-enhances",
+        ]
+        .into_iter()
+        .chain(packages.iter().copied()),
+        Perms::User,
+        false,
+    )
+    .map_err(|e| {
+        nest_errors!(
+            "Failed to get the packages depending on the undeclared ones",
+            e
+        )
+    })?;
+
+    Ok(parse_rdepends(&rdepends, packages))
+}
+
+// Every package is printed unindented, followed by a header and one
+// dependent per indented line, with a leading `|` for alternatives
+fn parse_rdepends<'a>(
+    stdout: &str,
+    packages: &BTreeSet<&'a str>,
+) -> BTreeMap<&'a str, HashSet<String>> {
+    let mut dependents: BTreeMap<_, HashSet<_>> = packages
+        .iter()
+        .map(|package| (*package, HashSet::new()))
+        .collect();
+    let mut current = None;
+
+    for line in stdout.lines().filter(|line| *line != "Reverse Depends:") {
+        if !line.starts_with(char::is_whitespace) {
+            current = packages.get(line.trim()).copied();
+            continue;
+        }
+
+        let dependent = line.trim().trim_start_matches('|');
+
+        if let Some(package) = current
+            && dependent != package
+        {
+            dependents
+                .entry(package)
+                .or_default()
+                .insert(dependent.to_owned());
+        }
+    }
+
+    dependents
+}
+
+/// Everything purging the packages takes with it, as simulated by apt-get,
+/// along with whatever is left unneeded afterwards when autoremoving
+fn get_purged_packages(packages: &[&str], autoremove: bool) -> Result<Box<[String]>> {
+    if packages.is_empty() && !autoremove {
+        return Ok(Box::new([]));
+    }
+
+    let simulation = run_command_for_stdout(
+        ["apt-get", "--simulate"]
+            .into_iter()
+            .chain(["--autoremove"].into_iter().filter(|_| autoremove))
+            .chain(["purge"])
+            .chain(packages.iter().copied()),
+        Perms::User,
+        false,
+    )
+    .map_err(|e| nest_errors!("Failed to simulate purging the undeclared packages", e))?;
+
+    Ok(parse_simulation(&simulation))
+}
+
+// Simulated removals look like `Purg foo [1.0-1]`
+fn parse_simulation(stdout: &str) -> Box<[String]> {
+    stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("Purg ").or(line.strip_prefix("Remv ")))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Maps every installed package to its version
+fn get_installed_packages() -> Result<HashMap<String, String>> {
+    let packages = run_command_for_stdout(
+        [
+            "dpkg-query",
+            "--show",
+            "--showformat",
+            "${Package}\t${Version}\t${db:Status-Abbrev}\n",
+        ],
+        Perms::User,
+        false,
+    )
+    .map_err(|e| nest_errors!("Failed to get installed packages", e))?;
+
+    Ok(parse_installed_packages(&packages))
+}
+
+// Removed packages whose configuration is still around are listed too, so
+// only the ones marked as installed (`ii`) are kept
+fn parse_installed_packages(stdout: &str) -> HashMap<String, String> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let (package, version, status) = (fields.next()?, fields.next()?, fields.next()?);
+
+            status
+                .trim()
+                .eq("ii")
+                .then(|| (package.to_owned(), version.to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    #[test]
+    fn value_to_pkgspec_pinned() {
+        let record = Record::from_raw_cols_vals(
+            ["package", "version", "release"]
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+            vec![
+                Value::string("neovim", Span::test_data()),
+                Value::string("0.9.5-6", Span::test_data()),
+                Value::string("bookworm-backports", Span::test_data()),
+            ],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let (package, spec) = value_to_pkgspec(&Value::record(record, Span::test_data())).unwrap();
+        assert_eq!(install_target(&package, &spec), "neovim=0.9.5-6");
+        assert_eq!(spec.release.as_deref(), Some("bookworm-backports"));
+        assert!(spec.post_hook.is_none());
+    }
+
+    #[test]
+    fn value_to_pkgspec_release_not_string() {
+        let record = Record::from_raw_cols_vals(
+            ["package", "release"]
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+            vec![
+                Value::string("neovim", Span::test_data()),
+                Value::bool(true, Span::test_data()),
+            ],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        assert!(value_to_pkgspec(&Value::record(record, Span::test_data())).is_err());
+    }
+
+    #[test]
+    fn parse_installed_packages_skips_removed() {
+        let stdout = "bash\t5.2.15-2\tii \nvim\t2:9.0\trc \ncurl\t7.88.1\tii \n";
+
+        let installed = parse_installed_packages(stdout);
+        assert_eq!(installed.len(), 2);
+        assert_eq!(installed["bash"], "5.2.15-2");
+        assert!(!installed.contains_key("vim"));
+    }
+
+    #[test]
+    fn parse_rdepends_alternatives() {
+        let stdout = concat!(
+            "libfoo1\nReverse Depends:\n  foo\n |bar\n  libfoo1\n",
+            "htop\nReverse Depends:\n",
+            "libbar1\nReverse Depends:\n  bar\n",
+        );
+        let packages = BTreeSet::from(["libfoo1", "htop", "libbar1"]);
+
+        let dependents = parse_rdepends(stdout, &packages);
+        assert_eq!(
+            dependents["libfoo1"],
+            HashSet::from(["foo".to_owned(), "bar".to_owned()])
+        );
+        assert!(dependents["htop"].is_empty());
+        assert_eq!(dependents["libbar1"], HashSet::from(["bar".to_owned()]));
+    }
+
+    #[test]
+    fn parse_simulation_removals() {
+        let stdout = concat!(
+            "NOTE: This is only a simulation!\n",
+            "Purg foo [1.0-1]\n",
+            "Remv bar [2.0] [foo:amd64 ]\n",
+            "Purg foo-data [1.0-1]\n",
+        );
+
+        assert_eq!(*parse_simulation(stdout), ["foo", "bar", "foo-data"]);
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 use anyhow::{Result, anyhow};
 use nu_protocol::Value;
@@ -6,8 +6,9 @@ use nu_protocol::{Record, engine::Closure};
 
 use crate::commands::{Perms, dry_run_command, run_command, run_command_for_stdout};
 use crate::config::{ARCH_PACKAGE_MANAGER_KEY, DEFAULT_PACKAGE_MANAGER};
-use crate::parser::Engine;
-use crate::{CleanCacheCommand, CleanCommand, SyncCommand, function, mod_err, nest_errors};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
 
 use super::Backend;
 
@@ -43,175 +44,145 @@ impl Backend for Arch {
         })
     }
 
-    fn install(&self, engine: &mut Engine, opts: &SyncCommand) -> Result<()> {
-        let package_manager = &self.package_manager;
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let extra = self.get_extra_packages()?;
+
+        Ok(extra.into_iter().collect())
+    }
+
+    fn plan(&self, no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let package_manager = self.package_manager.as_str();
         let perms = self.perms;
 
         let explicit_installed = get_installed_packages(package_manager, true)?;
         let dependencies = get_installed_packages(package_manager, false)?;
+        let groups = get_groups(package_manager, perms)?;
 
-        let mut configured: HashSet<_> = self.packages.keys().map(String::as_str).collect();
-
-        let groups = run_command_for_stdout(
-            [package_manager, "--sync", "--quiet", "--groups"],
-            self.perms,
-            false,
-        )
-        .map_err(|e| nest_errors!("Failed to get group packages", e))?;
-
-        let mut closures = Vec::new();
-
-        let configured_group_packages: Box<_> = groups
-            .lines()
-            .filter(|group| configured.remove(group))
-            .inspect(|group| {
-                if let Some(closure) = self.packages.get(*group).unwrap().as_ref() {
-                    closures.push(closure);
-                }
-            })
-            .map(|group| get_installed_group_packages(group, package_manager))
-            .collect::<Result<_>>()?;
-
-        let missing = &mut configured
-            .into_iter()
-            .chain(
-                configured_group_packages
-                    .iter()
-                    .flatten()
-                    .map(String::as_str),
-            )
-            .filter(|package| !explicit_installed.contains(*package))
-            .inspect(|package| {
-                // some packages may not have a corresponding entry in the
-                // map since we're also going over packages that are not there
-                // in the config (the packages resolved from package groups)
-                if let Some(closure) = self.packages.get(*package).unwrap_or(&None).as_ref() {
-                    // The closure will be executed even if the package status was only
-                    // changed from dependency to explicit
-                    closures.push(closure);
-                }
-            })
-            .peekable();
-
-        log::info!("Successfully found all missing arch packages");
-
-        if missing.peek().is_none() {
-            log::info!("Nothing to install!");
-            return Ok(());
-        }
-
-        let (reason_change, missing): (Vec<_>, Vec<_>) =
-            missing.partition(|package| dependencies.contains(*package));
-
-        let (install_result, reason_result) = if opts.dry_run {
-            (
-                dry_run_command(
-                    [package_manager, "--sync"]
-                        .into_iter()
-                        .chain(["--noconfirm"].into_iter().filter(|_| opts.no_confirm))
-                        .chain(missing),
-                    perms,
-                ),
-                dry_run_command(
-                    [package_manager, "--database", "--asexplicit"]
-                        .into_iter()
-                        .chain(reason_change),
-                    perms,
-                ),
-            )
-        } else {
-            (
-                run_command(
-                    [package_manager, "--sync"]
-                        .into_iter()
-                        .chain(["--noconfirm"].into_iter().filter(|_| opts.no_confirm))
-                        .chain(missing),
-                    perms,
-                ),
-                run_command(
-                    [package_manager, "--database", "--asexplicit"]
-                        .into_iter()
-                        .chain(reason_change),
-                    perms,
-                ),
-            )
+        // AUR helpers run sudo themselves, so every step may ask for a password
+        let mut plan = Plan {
+            elevated: true,
+            ..Plan::default()
         };
+        let mut configured = BTreeSet::new();
+
+        for (package, hook) in &self.packages {
+            let packages = if groups.contains(package) {
+                get_installed_group_packages(package, package_manager)?
+            } else {
+                Box::new([package.to_owned()])
+            };
+
+            // The hook runs even if the package status was only changed
+            // from dependency to explicit
+            if packages
+                .iter()
+                .any(|member| !explicit_installed.contains(member))
+            {
+                plan.push_hook(hook.as_ref());
+            }
 
-        install_result
-            .inspect(|_| log::info!("Successfully installed arch packages"))
-            .map_err(|e| nest_errors!("Failed to install packages", e))?;
-
-        reason_result
-            .inspect(|_| log::info!("Successfully set dependencies as explicits"))
-            .map_err(|e| nest_errors!("Failed to set dependencies as explicits", e))?;
+            configured.extend(packages);
+        }
 
-        closures
+        let (reason_change, missing): (Vec<_>, Vec<_>) = configured
             .iter()
-            .try_for_each(|closure| {
-                if opts.dry_run {
-                    engine.dry_run_closure(closure)
-                } else {
-                    engine.execute_closure(closure)
-                }
-            })
-            .inspect(|_| log::info!("Successfully executed all closures"))
-            .map_err(|e| nest_errors!("Failed to execute closures", e))
-    }
-
-    fn remove(&self, opts: &CleanCommand) -> Result<()> {
-        let package_manager = &self.package_manager;
-        let perms = self.perms;
+            .filter(|package| !explicit_installed.contains(*package))
+            .map(String::as_str)
+            .partition(|package| dependencies.contains(*package));
 
-        let installed = get_installed_packages(package_manager, true)?;
+        let extra: BTreeSet<_> = explicit_installed
+            .iter()
+            .filter(|package| !configured.contains(*package))
+            .map(String::as_str)
+            .collect();
 
-        let mut configured: HashSet<_> = self.packages.keys().map(String::as_str).collect();
+        let no_confirm = ["--noconfirm"].into_iter().filter(|_| no_confirm);
 
-        let groups = run_command_for_stdout(
-            [package_manager, "--sync", "--quiet", "--groups"],
-            self.perms,
-            false,
-        )?;
+        plan.push(
+            Action::Install,
+            missing.iter().copied(),
+            [package_manager, "--sync"]
+                .into_iter()
+                .chain(no_confirm.clone())
+                .chain(missing.iter().copied()),
+            perms,
+        );
 
-        let configured_packages: Box<[_]> = groups
-            .lines()
-            .filter(|group| configured.remove(group))
-            .map(|group| get_installed_group_packages(group, package_manager))
-            .collect::<Result<_>>()?;
+        plan.push(
+            Action::MarkExplicit,
+            reason_change.iter().copied(),
+            [package_manager, "--database", "--asexplicit"]
+                .into_iter()
+                .chain(reason_change.iter().copied()),
+            perms,
+        );
 
-        let configured_packages: HashSet<_> = configured_packages
+        plan.push(
+            Action::Remove,
+            extra.iter().copied(),
+            [
+                package_manager,
+                "--remove",
+                "--nosave",
+                "--recursive",
+                "--unneeded",
+            ]
             .into_iter()
-            .flatten()
-            .chain(configured.iter().map(|package| package.to_string()))
-            .collect();
+            .chain(no_confirm)
+            .chain(extra.iter().copied()),
+            perms,
+        );
 
-        let mut extra = installed.difference(&configured_packages).peekable();
+        Ok(plan)
+    }
 
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
         let command_action = if opts.dry_run {
             dry_run_command
         } else {
             run_command
         };
 
-        if extra.peek().is_none() {
-            log::info!("No extra packages to remove!");
-            Ok(())
-        } else {
-            command_action(
-                [
-                    package_manager,
-                    "--remove",
-                    "--nosave",
-                    "--recursive",
-                    "--unneeded",
-                ]
+        command_action(
+            [&self.package_manager, "--sync", "--refresh", "--sysupgrade"]
                 .into_iter()
-                .chain(["--noconfirm"].into_iter().filter(|_| opts.no_confirm))
-                .chain(extra.map(String::as_str)),
-                perms,
-            )
-            .inspect(|_| log::info!("Removed extra packages"))
-            .map_err(|e| nest_errors!("Failed to remove packages", e))
-        }
+                .chain(["--noconfirm"].into_iter().filter(|_| opts.no_confirm)),
+            self.perms,
+        )
+        .inspect(|_| log::info!("Successfully upgraded arch packages"))
+        .map_err(|e| nest_errors!("Failed to upgrade arch packages", e))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Arch",
+            &[PACKAGE_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "Arch")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(config: &Record) -> Result<String> {
+        get_package_manager(config).map(|(package_manager, _)| package_manager.to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn settings(config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        let (package_manager, _) = get_package_manager(config)?;
+
+        Ok(Box::new([(
+            ARCH_PACKAGE_MANAGER_KEY,
+            package_manager.to_owned(),
+        )]))
     }
 
     fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
@@ -265,6 +236,41 @@ impl Backend for Arch {
     }
 }
 
+impl Arch {
+    fn get_extra_packages(&self) -> Result<BTreeSet<String>> {
+        let package_manager = &self.package_manager;
+
+        let installed = get_installed_packages(package_manager, true)?;
+
+        let mut configured: HashSet<_> = self.packages.keys().map(String::as_str).collect();
+
+        let groups = run_command_for_stdout(
+            [package_manager, "--sync", "--quiet", "--groups"],
+            self.perms,
+            false,
+        )?;
+
+        let configured_packages: Box<[_]> = groups
+            .lines()
+            .filter(|group| configured.remove(group))
+            .map(|group| get_installed_group_packages(group, package_manager))
+            .collect::<Result<_>>()?;
+
+        let configured_packages: HashSet<_> = configured_packages
+            .into_iter()
+            .flatten()
+            .chain(configured.iter().map(|package| package.to_string()))
+            .collect();
+
+        let extra = installed
+            .difference(&configured_packages)
+            .map(ToOwned::to_owned)
+            .collect();
+
+        Ok(extra)
+    }
+}
+
 fn value_to_pkgspec(value: &Value) -> Result<(String, Option<Closure>)> {
     let record = value
         .as_record()
@@ -327,6 +333,17 @@ fn get_installed_group_packages(group: &str, package_manager: &str) -> Result<Bo
     Ok(packages)
 }
 
+fn get_groups(package_manager: &str, perms: Perms) -> Result<HashSet<String>> {
+    let groups = run_command_for_stdout(
+        [package_manager, "--sync", "--quiet", "--groups"],
+        perms,
+        false,
+    )
+    .map_err(|e| nest_errors!("Failed to get group packages", e))?;
+
+    Ok(groups.lines().map(ToOwned::to_owned).collect())
+}
+
 fn get_package_manager(config: &Record) -> Result<(&str, Perms)> {
     let pacman = match config.get(ARCH_PACKAGE_MANAGER_KEY) {
         Some(pacman) => pacman.as_str().map_err(|e| {
@@ -0,0 +1,408 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const TAP_LIST_KEY: &str = "taps";
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const URL_KEY: &str = "url";
+const HOOK_KEY: &str = "post_hook";
+
+// Tapped by brew itself
+const DEFAULT_TAPS: [&str; 2] = ["homebrew/core", "homebrew/cask"];
+
+#[derive(Clone, Debug)]
+pub struct Brew {
+    taps: HashMap<String, Option<String>>,
+    packages: HashMap<String, Option<Closure>>,
+}
+
+impl Backend for Brew {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let taps = match value.get(TAP_LIST_KEY) {
+            Some(taps) => taps
+                .as_list()
+                .map_err(|e| nest_errors!("The tap list in Brew is not a list", e))?
+                .iter()
+                .map(value_to_tap)
+                .collect::<Result<_>>()?,
+            None => {
+                log::info!("No taps declared for Brew");
+                HashMap::new()
+            }
+        };
+
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for Brew"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The package list in Brew is not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed brew packages");
+        Ok(Brew { taps, packages })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let leaves = get_lines(["brew", "leaves", "--installed-on-request"])?;
+        let taps = get_lines(["brew", "tap"])?;
+
+        Ok(self
+            .get_extra_taps(&taps)
+            .into_iter()
+            .map(|tap| [TAP_LIST_KEY, tap].join("/"))
+            .chain(
+                self.get_extra_packages(&leaves)
+                    .into_iter()
+                    .map(ToOwned::to_owned),
+            )
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, selection: Selection) -> Result<Plan> {
+        let taps = get_lines(["brew", "tap"])?;
+        let installed = get_installed_formulae()?;
+
+        let mut plan = Plan::default();
+
+        self.taps
+            .iter()
+            .filter(|(tap, _)| !taps.contains(tap))
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .for_each(|(tap, url)| {
+                plan.push(
+                    Action::AddRemote,
+                    [tap],
+                    ["brew", "tap", tap].into_iter().chain(url.as_deref()),
+                    Perms::User,
+                );
+            });
+
+        let missing: BTreeMap<_, _> = self
+            .packages
+            .iter()
+            .filter(|(formula, _)| !installed.contains(short_name(formula)))
+            .collect();
+
+        plan.push(
+            Action::Install,
+            missing.keys().map(|formula| formula.as_str()),
+            ["brew", "install"]
+                .into_iter()
+                .chain(missing.keys().map(|formula| formula.as_str())),
+            Perms::User,
+        );
+        missing
+            .values()
+            .for_each(|hook| plan.push_hook(hook.as_ref()));
+
+        if !selection.removals() {
+            return Ok(plan);
+        }
+
+        let leaves = get_lines(["brew", "leaves", "--installed-on-request"])?;
+        let extra = self.get_extra_packages(&leaves);
+
+        plan.push(
+            Action::Remove,
+            extra.iter().copied(),
+            ["brew", "uninstall"]
+                .into_iter()
+                .chain(extra.iter().copied()),
+            Perms::User,
+        );
+
+        // Untapping fails while formulae from the tap are installed, so taps
+        // go last
+        self.get_extra_taps(&taps).into_iter().for_each(|tap| {
+            plan.push(
+                Action::RemoveRemote,
+                [tap],
+                ["brew", "untap", tap],
+                Perms::User,
+            );
+        });
+
+        Ok(plan)
+    }
+
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        let installed = get_installed_formulae()?;
+
+        // brew upgrade fails on formulae that are not installed yet
+        let formulae: BTreeSet<_> = self
+            .packages
+            .keys()
+            .filter(|formula| installed.contains(short_name(formula)))
+            .map(String::as_str)
+            .collect();
+
+        if formulae.is_empty() {
+            log::info!("No installed formulae to upgrade");
+            return Ok(());
+        }
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to upgrade the following formulae for brew?: ",
+                &formulae,
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(vec!["brew", "update"], Perms::User)
+            .map_err(|e| nest_errors!("Failed to update brew", e))?;
+
+        command_action(
+            ["brew", "upgrade"]
+                .into_iter()
+                .chain(formulae)
+                .collect::<Vec<_>>(),
+            Perms::User,
+        )
+        .inspect(|_| log::info!("Successfully upgraded brew formulae"))
+        .map_err(|e| nest_errors!("Failed to upgrade brew formulae", e))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, taps) = check_records(
+            value,
+            TAP_LIST_KEY,
+            "Brew",
+            &[PACKAGE_KEY, URL_KEY],
+            value_to_tap,
+        );
+
+        let (_, packages) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Brew",
+            &[PACKAGE_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[TAP_LIST_KEY, PACKAGE_LIST_KEY], "Brew")
+            .into_iter()
+            .chain(taps)
+            .chain(packages)
+            .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("brew".to_owned())
+    }
+
+    /// Counts every tap and formula
+    fn package_count(&self) -> usize {
+        self.taps.len() + self.packages.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to clean brew cache?",
+                ["Removing old versions and stale downloads"],
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(["brew", "cleanup"], Perms::User)
+            .inspect(|_| log::info!("Cleaned brew's cache"))
+            .map_err(|e| nest_errors!("Failed to clean cache", e))
+    }
+}
+
+impl Brew {
+    fn get_extra_packages<'a>(&self, leaves: &'a [String]) -> BTreeSet<&'a str> {
+        let configured: HashSet<_> = self
+            .packages
+            .keys()
+            .map(|formula| short_name(formula))
+            .collect();
+
+        leaves
+            .iter()
+            .filter(|formula| !configured.contains(short_name(formula)))
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn get_extra_taps<'a>(&self, taps: &'a [String]) -> BTreeSet<&'a str> {
+        taps.iter()
+            .filter(|tap| !self.taps.contains_key(*tap))
+            .map(String::as_str)
+            .filter(|tap| !DEFAULT_TAPS.contains(tap))
+            .collect()
+    }
+}
+
+// Formulae from third party taps are listed with their tap as
+// `user/repo/formula`, while they may be declared either way
+fn short_name(formula: &str) -> &str {
+    formula.rsplit('/').next().unwrap_or(formula)
+}
+
+/// The installed formulae, without the tap they come from
+fn get_installed_formulae() -> Result<HashSet<String>> {
+    Ok(get_lines(["brew", "list", "--formula", "-1"])?
+        .iter()
+        .map(|formula| short_name(formula).to_owned())
+        .collect())
+}
+
+fn get_lines<const N: usize>(command: [&str; N]) -> Result<Box<[String]>> {
+    let stdout = run_command_for_stdout(command, Perms::User, true)
+        .map_err(|e| nest_errors!("Failed to query brew", e))?;
+
+    Ok(stdout
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+fn value_to_tap(value: &Value) -> Result<(String, Option<String>)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("tap value was not a record", e))?;
+
+    let name = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("tap name was not found"))?
+        .as_str()
+        .map_err(|e| nest_errors!("tap name was not a string", e))?
+        .to_owned();
+
+    let url = match record.get(URL_KEY) {
+        Some(url) => Some(
+            url.as_str()
+                .map_err(|e| nest_errors!("tap url was not a string for {name}", e))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    Ok((name, url))
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, Option<Closure>)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The package-spec is not a record", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No package mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The package was not a string", e))?
+        .to_owned();
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {package} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((package, post_hook))
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    #[test]
+    fn value_to_tap_url() {
+        let record = Record::from_raw_cols_vals(
+            ["package", "url"]
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+            vec![
+                Value::string("user/repo", Span::test_data()),
+                Value::string("https://example.com/repo", Span::test_data()),
+            ],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let (name, url) = value_to_tap(&Value::record(record, Span::test_data())).unwrap();
+        assert_eq!(name, "user/repo");
+        assert_eq!(url.as_deref(), Some("https://example.com/repo"));
+    }
+
+    #[test]
+    fn extra_packages_by_short_name() {
+        let brew = Brew {
+            taps: HashMap::from([("oven-sh/bun".to_owned(), None)]),
+            packages: HashMap::from([
+                ("ripgrep".to_owned(), None),
+                ("oven-sh/bun/bun".to_owned(), None),
+            ]),
+        };
+        let leaves = ["bun", "ripgrep", "jq"].map(ToOwned::to_owned);
+
+        assert_eq!(
+            brew.get_extra_packages(&leaves)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["jq"]
+        );
+    }
+
+    #[test]
+    fn extra_taps_skip_defaults() {
+        let brew = Brew {
+            taps: HashMap::from([("oven-sh/bun".to_owned(), None)]),
+            packages: HashMap::from([
+                ("ripgrep".to_owned(), None),
+                ("oven-sh/bun/bun".to_owned(), None),
+            ]),
+        };
+        let taps = ["homebrew/core", "oven-sh/bun", "user/old"].map(ToOwned::to_owned);
+
+        assert_eq!(
+            brew.get_extra_taps(&taps).into_iter().collect::<Vec<_>>(),
+            ["user/old"]
+        );
+    }
+}
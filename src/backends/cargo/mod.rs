@@ -1,4 +1,4 @@
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
 use std::path::Path;
 
@@ -6,11 +6,13 @@ use anyhow::{Result, anyhow};
 use nu_protocol::{Record, engine::Closure};
 
 use crate::commands::{
-    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+    Perms, confirmation_prompt, dry_run_command, find_executable, run_command,
+    run_command_for_stdout,
 };
 use crate::config::{CARGO_USE_BINSTALL_KEY, DEFAULT_CARGO_USE_BINSTALL};
-use crate::parser::Engine;
-use crate::{CleanCacheCommand, CleanCommand, SyncCommand, function, mod_err, nest_errors};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
 
 use super::Backend;
 
@@ -63,90 +65,129 @@ impl Backend for Cargo {
         })
     }
 
-    fn install(&self, engine: &mut Engine, opts: &SyncCommand) -> Result<()> {
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let extra_packages = self.get_extra_packages()?;
+
+        Ok(extra_packages.into_iter().collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, _selection: Selection) -> Result<Plan> {
         let packages = self.get_installed_packages()?;
 
-        let configured_packages = &self.packages;
-        let missing_packages: HashMap<_, _> = configured_packages
+        let mut plan = Plan::default();
+
+        self.packages
             .iter()
             .filter(|(name, _)| !packages.contains(*name))
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .for_each(|(name, spec)| {
+                plan.push(
+                    Action::Install,
+                    [name],
+                    install_command(name, spec, self.installopt),
+                    Perms::User,
+                );
+                plan.push_hook(spec.post_hook.as_ref());
+            });
+
+        packages
+            .iter()
+            .filter(|package| !self.packages.contains_key(*package))
+            .for_each(|package| {
+                plan.push(
+                    Action::Remove,
+                    [package],
+                    ["cargo", "uninstall", package],
+                    Perms::User,
+                );
+            });
+
+        Ok(plan)
+    }
+
+    // Both cargo install and cargo binstall skip crates that are already at
+    // their latest version, so reinstalling every declared crate only
+    // rebuilds the outdated ones.
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let packages = self.get_installed_packages()?;
+
+        let installed_packages: BTreeSet<_> = self
+            .packages
+            .keys()
+            .filter(|name| packages.contains(*name))
             .collect();
 
-        if missing_packages.is_empty() {
-            log::info!("No missing packages to install");
+        if installed_packages.is_empty() {
+            log::info!("No installed packages to update");
             return Ok(());
         }
 
-        let mut post_hooks = Vec::new();
-
         if !opts.no_confirm
             && !confirmation_prompt(
-                "Do you want to install the following packages for cargo?: ",
-                missing_packages.keys(),
+                "Do you want to update the following packages for cargo?: ",
+                &installed_packages,
             )?
         {
             return Ok(());
         }
 
-        missing_packages.iter().try_for_each(|(name, spec)| {
-            if let Some(hook) = spec.post_hook.as_ref() {
-                post_hooks.push(hook);
-            }
-            install_package(name, spec, self.installopt, opts)
-        })?;
-
-        log::info!("Successfully installed missing packages");
-
-        post_hooks
+        installed_packages
             .into_iter()
-            .try_for_each(|hook| {
-                if opts.dry_run {
-                    engine.dry_run_closure(hook)
-                } else {
-                    engine.execute_closure(hook)
-                }
+            .try_for_each(|name| {
+                install_package(
+                    name,
+                    self.packages.get(name).unwrap(),
+                    self.installopt,
+                    opts.dry_run,
+                )
             })
-            .inspect(|_| log::info!("Successfully executed all the post hooks"))
+            .inspect(|_| log::info!("Successfully updated cargo packages"))
     }
 
-    fn remove(&self, opts: &CleanCommand) -> Result<()> {
-        let packages = self.get_installed_packages()?;
-        log::info!("Successfully parsed installed packages");
-
-        let configured_packages = &self.packages;
+    fn validate(value: &Record, config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Cargo",
+            &[
+                PACKAGE_KEY,
+                ALL_FEATURES_KEY,
+                NO_DEFAULT_FEATURES_KEY,
+                FEATURES_KEY,
+                GIT_REMOTE_KEY,
+                HOOK_KEY,
+            ],
+            value_to_pkgspec,
+        );
 
-        let command_action: fn([&str; 3], Perms) -> Result<()> = if opts.dry_run {
-            |args, perms| dry_run_command(args, perms)
-        } else {
-            |args, perms| run_command(args, perms)
+        let binstall = match get_binstall_opt(config) {
+            Ok(true) if find_executable("cargo-binstall").is_none() => Some(Diagnostic::error(
+                "Cargo",
+                "cargo-binstall is enabled but not found in PATH",
+            )),
+            _ => None,
         };
 
-        let extra_packages: HashSet<_> = packages
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "Cargo")
             .into_iter()
-            .filter(|package| !configured_packages.contains_key(package))
-            .collect();
+            .chain(diagnostics)
+            .chain(binstall)
+            .collect()
+    }
 
-        if extra_packages.is_empty() {
-            log::info!("No extra packages to remove");
-            return Ok(());
-        }
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("cargo".to_owned())
+    }
 
-        if !opts.no_confirm
-            && !confirmation_prompt(
-                "Do you want to remove the following packages from cargo?: ",
-                &extra_packages,
-            )?
-        {
-            return Ok(());
-        }
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
 
-        extra_packages
-            .iter()
-            .try_for_each(|package| {
-                command_action(["cargo", "uninstall", package.as_str()], Perms::User)
-                    .map_err(|e| nest_errors!("Failed to uninstall {package}", e))
-            })
-            .inspect(|_| log::info!("Successfully removed extraneous packages"))
+    fn settings(config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        let binstall = get_binstall_opt(config)?;
+
+        Ok(Box::new([(CARGO_USE_BINSTALL_KEY, binstall.to_string())]))
     }
 
     fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
@@ -226,6 +267,20 @@ impl Cargo {
 
         Ok(final_packages)
     }
+
+    fn get_extra_packages(&self) -> Result<BTreeSet<String>> {
+        let packages = self.get_installed_packages()?;
+        log::info!("Successfully parsed installed packages");
+
+        let configured_packages = &self.packages;
+
+        let extra_packages = packages
+            .into_iter()
+            .filter(|package| !configured_packages.contains_key(package))
+            .collect();
+
+        Ok(extra_packages)
+    }
 }
 
 fn value_to_pkgspec(value: &nu_protocol::Value) -> Result<(String, CargoOpts)> {
@@ -328,12 +383,18 @@ fn get_binstall_opt(config: &Record) -> Result<bool> {
     }
 }
 
-fn install_package(
-    name: &str,
-    spec: &CargoOpts,
-    installer: &str,
-    opts: &SyncCommand,
-) -> Result<()> {
+fn install_package(name: &str, spec: &CargoOpts, installer: &str, dry_run: bool) -> Result<()> {
+    let command_action = if dry_run {
+        dry_run_command
+    } else {
+        run_command
+    };
+
+    command_action(install_command(name, spec, installer), Perms::User)
+        .map_err(|e| nest_errors!("Failed to install {name}", e))
+}
+
+fn install_command<'a>(name: &'a str, spec: &'a CargoOpts, installer: &'a str) -> Vec<&'a str> {
     let git = ["--git"]
         .into_iter()
         .chain(spec.git_remote.as_deref())
@@ -354,22 +415,15 @@ fn install_package(
         .into_iter()
         .filter(|_| installer == "binstall");
 
-    let command = ["cargo", installer]
+    ["cargo", installer]
         .into_iter()
         .chain(git)
         .chain(all_features)
         .chain(no_default_features)
         .chain(features)
         .chain(no_confirm)
-        .chain([name]);
-
-    let command_action = if opts.dry_run {
-        dry_run_command
-    } else {
-        run_command
-    };
-
-    command_action(command, Perms::User).map_err(|e| nest_errors!("Failed to install {name}", e))
+        .chain([name])
+        .collect()
 }
 
 fn get_cargo_path() -> Result<String> {
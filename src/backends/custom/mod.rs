@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Span, Value, engine::Closure};
+
+use crate::commands::confirmation_prompt;
+use crate::parser::Engine;
+use crate::plan::{Action, Call, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const LIST_INSTALLED_KEY: &str = "list_installed";
+const INSTALL_KEY: &str = "install";
+const REMOVE_KEY: &str = "remove";
+const CLEAN_CACHE_KEY: &str = "clean_cache";
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const HOOK_KEY: &str = "post_hook";
+
+/// A backend whose package manager is driven by closures from the package
+/// spec, while the diffing is left to supac like for every other backend
+#[derive(Clone, Debug)]
+pub struct Custom {
+    list_installed: Closure,
+    install: Closure,
+    remove: Closure,
+    clean_cache: Option<Closure>,
+    packages: HashMap<String, Option<Closure>>,
+    /// The engine that parsed the package spec, as the closures only make
+    /// sense to it. Set after parsing with [`Custom::set_engine`].
+    engine: Option<Arc<Engine>>,
+}
+
+impl Backend for Custom {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let list_installed = get_closure(value, LIST_INSTALLED_KEY)?
+            .ok_or_else(|| mod_err!("No {LIST_INSTALLED_KEY} closure for Custom"))?;
+        let install = get_closure(value, INSTALL_KEY)?
+            .ok_or_else(|| mod_err!("No {INSTALL_KEY} closure for Custom"))?;
+        let remove = get_closure(value, REMOVE_KEY)?
+            .ok_or_else(|| mod_err!("No {REMOVE_KEY} closure for Custom"))?;
+        let clean_cache = get_closure(value, CLEAN_CACHE_KEY)?;
+
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for Custom"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The package list in Custom is not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed custom packages");
+        Ok(Custom {
+            list_installed,
+            install,
+            remove,
+            clean_cache,
+            packages,
+            engine: None,
+        })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed = self.get_installed_packages()?;
+
+        Ok(self
+            .get_extra_packages(&installed)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let engine = self.engine()?;
+        let installed = self.get_installed_packages()?;
+
+        let mut plan = Plan::default();
+
+        let missing: BTreeMap<_, _> = self
+            .packages
+            .iter()
+            .filter(|(package, _)| !installed.contains(*package))
+            .collect();
+
+        plan.push_call(
+            Action::Install,
+            missing.keys().map(|package| package.as_str()),
+            INSTALL_KEY,
+            Call::Closure {
+                engine: Arc::clone(engine),
+                closure: self.install.clone(),
+                args: Box::new([string_list(missing.keys())]),
+            },
+        );
+        missing
+            .values()
+            .for_each(|hook| plan.push_hook(hook.as_ref()));
+
+        let extra = self.get_extra_packages(&installed);
+
+        plan.push_call(
+            Action::Remove,
+            extra.iter().copied(),
+            REMOVE_KEY,
+            Call::Closure {
+                engine: Arc::clone(engine),
+                closure: self.remove.clone(),
+                args: Box::new([string_list(&extra)]),
+            },
+        );
+
+        Ok(plan)
+    }
+
+    fn update(&self, _opts: &UpdateCommand) -> Result<()> {
+        log::info!("Custom has no way to update packages, nothing to do");
+        Ok(())
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let required = [LIST_INSTALLED_KEY, INSTALL_KEY, REMOVE_KEY]
+            .into_iter()
+            .filter_map(|key| match get_closure(value, key) {
+                Ok(Some(_)) => None,
+                Ok(None) => Some(Diagnostic::error(
+                    "Custom",
+                    format!("missing the `{key}` closure"),
+                )),
+                Err(e) => Some(Diagnostic::error(["Custom", key].join("."), e)),
+            });
+
+        let clean_cache = get_closure(value, CLEAN_CACHE_KEY)
+            .err()
+            .map(|e| Diagnostic::error(["Custom", CLEAN_CACHE_KEY].join("."), e));
+
+        let (_, packages) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Custom",
+            &[PACKAGE_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(
+            value,
+            &[
+                LIST_INSTALLED_KEY,
+                INSTALL_KEY,
+                REMOVE_KEY,
+                CLEAN_CACHE_KEY,
+                PACKAGE_LIST_KEY,
+            ],
+            "Custom",
+        )
+        .into_iter()
+        .chain(required)
+        .chain(clean_cache)
+        .chain(packages)
+        .collect()
+    }
+
+    // The closures run on supac's own engine, so supac is all that is needed
+    fn executable(_config: &Record) -> Result<String> {
+        env::current_exe()
+            .map(|path| path.to_string_lossy().into_owned())
+            .map_err(|e| nest_errors!("Failed to find the supac executable", e))
+    }
+
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let Some(clean_cache) = &self.clean_cache else {
+            log::info!("No {CLEAN_CACHE_KEY} closure for Custom, nothing to do");
+            return Ok(());
+        };
+
+        let engine = self.engine()?;
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to clean the cache for Custom?",
+                ["Running the clean_cache closure"],
+            )?
+        {
+            return Ok(());
+        }
+
+        if opts.dry_run {
+            engine.dry_run_closure(clean_cache, &[])
+        } else {
+            engine
+                .execute_closure(clean_cache, &[])
+                .map(|_| log::info!("Cleaned the cache for Custom"))
+                .map_err(|e| nest_errors!("Failed to clean cache", e))
+        }
+    }
+}
+
+impl Custom {
+    pub fn set_engine(&mut self, engine: &Engine) {
+        self.engine = Some(Arc::new(engine.clone()));
+    }
+
+    fn engine(&self) -> Result<&Arc<Engine>> {
+        self.engine
+            .as_ref()
+            .ok_or_else(|| mod_err!("No engine to run the closures of Custom with"))
+    }
+
+    fn get_installed_packages(&self) -> Result<BTreeSet<String>> {
+        let installed = self
+            .engine()?
+            .execute_closure(&self.list_installed, &[])
+            .map_err(|e| nest_errors!("Failed to run {LIST_INSTALLED_KEY}", e))?;
+
+        parse_installed_packages(&installed)
+    }
+
+    fn get_extra_packages<'a>(&self, installed: &'a BTreeSet<String>) -> BTreeSet<&'a str> {
+        installed
+            .iter()
+            .filter(|package| !self.packages.contains_key(*package))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+fn parse_installed_packages(installed: &Value) -> Result<BTreeSet<String>> {
+    installed
+        .as_list()
+        .map_err(|e| nest_errors!("{LIST_INSTALLED_KEY} did not return a list", e))?
+        .iter()
+        .map(|package| {
+            package
+                .as_str()
+                .map(ToOwned::to_owned)
+                .map_err(|e| nest_errors!("{LIST_INSTALLED_KEY} returned a non-string", e))
+        })
+        .collect()
+}
+
+fn string_list<I>(packages: I) -> Value
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    Value::list(
+        packages
+            .into_iter()
+            .map(|package| Value::string(package.as_ref(), Span::unknown()))
+            .collect(),
+        Span::unknown(),
+    )
+}
+
+fn get_closure(value: &Record, key: &str) -> Result<Option<Closure>> {
+    match value.get(key) {
+        Some(closure) => closure
+            .as_closure()
+            .map(|closure| Some(closure.to_owned()))
+            .map_err(|e| nest_errors!("{key} is not a closure", e)),
+        None => Ok(None),
+    }
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, Option<Closure>)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The package-spec is not a record", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No package mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The package was not a string", e))?
+        .to_owned();
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {package} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((package, post_hook))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_installed_packages_strings() {
+        let installed = string_list(["foo", "bar"]);
+
+        assert_eq!(
+            parse_installed_packages(&installed)
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["bar", "foo"]
+        );
+        assert!(parse_installed_packages(&Value::string("foo", Span::unknown())).is_err());
+        assert!(
+            parse_installed_packages(&Value::list(
+                vec![Value::int(1, Span::unknown())],
+                Span::unknown()
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn get_closure_optional() {
+        let record = Record::from_raw_cols_vals(
+            vec![INSTALL_KEY.to_owned()],
+            vec![Value::string("not a closure", Span::unknown())],
+            Span::unknown(),
+            Span::unknown(),
+        )
+        .unwrap();
+
+        assert!(get_closure(&record, INSTALL_KEY).is_err());
+        assert!(get_closure(&record, CLEAN_CACHE_KEY).unwrap().is_none());
+    }
+}
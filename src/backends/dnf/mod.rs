@@ -0,0 +1,558 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::Value;
+use nu_protocol::{Record, engine::Closure};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::{Backend, needed_packages};
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const HOOK_KEY: &str = "post_hook";
+
+const GROUP_PREFIX: char = '@';
+
+#[derive(Clone, Debug)]
+pub struct Dnf {
+    packages: HashMap<String, Option<Closure>>,
+}
+
+impl Backend for Dnf {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for Dnf"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The package list in Dnf is not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed dnf packages");
+        Ok(Dnf { packages })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let explicit_installed = get_installed_packages(true)?;
+        let configured = self.get_configured_packages()?;
+
+        Ok(explicit_installed
+            .into_iter()
+            .filter(|package| !configured.contains(package))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect())
+    }
+
+    fn plan(&self, no_confirm: bool, selection: Selection) -> Result<Plan> {
+        let explicit_installed = get_installed_packages(true)?;
+        let installed = get_installed_packages(false)?;
+
+        let mut plan = Plan::default();
+        let mut configured = BTreeSet::new();
+
+        for (package, hook) in &self.packages {
+            let packages = match package.strip_prefix(GROUP_PREFIX) {
+                Some(group) => get_group_packages(group)?,
+                None => Box::new([package.to_owned()]),
+            };
+
+            // The hook runs even if the package status was only changed
+            // from dependency to user installed
+            if packages
+                .iter()
+                .any(|member| !explicit_installed.contains(member))
+            {
+                plan.push_hook(hook.as_ref());
+            }
+
+            configured.extend(packages);
+        }
+
+        let (reason_change, missing): (Vec<_>, Vec<_>) = configured
+            .iter()
+            .filter(|package| !explicit_installed.contains(*package))
+            .map(String::as_str)
+            .partition(|package| installed.contains(*package));
+
+        let extra: BTreeSet<_> = explicit_installed
+            .iter()
+            .filter(|package| !configured.contains(*package))
+            .map(String::as_str)
+            .collect();
+
+        let assume_yes = ["--assumeyes"].into_iter().filter(|_| no_confirm);
+
+        plan.push(
+            Action::Install,
+            missing.iter().copied(),
+            ["dnf", "install"]
+                .into_iter()
+                .chain(assume_yes.clone())
+                .chain(missing.iter().copied()),
+            Perms::Root,
+        );
+
+        plan.push(
+            Action::MarkExplicit,
+            reason_change.iter().copied(),
+            ["dnf", "mark", "user"]
+                .into_iter()
+                .chain(reason_change.iter().copied()),
+            Perms::Root,
+        );
+
+        // Finding out what depends on the extra packages takes a while, and
+        // is only needed to remove them
+        if !selection.removals() {
+            return Ok(plan);
+        }
+
+        // Like pacman's --unneeded, packages that something else still
+        // depends on are kept around as dependencies instead of taking their
+        // dependents down with them
+        let dependents = get_dependents(&extra)?;
+        let needed = needed_packages(&dependents);
+        let (needed, unneeded): (Vec<_>, Vec<_>) = extra
+            .iter()
+            .copied()
+            .partition(|package| needed.contains(package));
+
+        plan.push(
+            Action::Remove,
+            unneeded.iter().copied(),
+            ["dnf", "remove"]
+                .into_iter()
+                .chain(assume_yes)
+                .chain(unneeded.iter().copied()),
+            Perms::Root,
+        );
+
+        plan.push(
+            Action::MarkDependency,
+            needed.iter().copied(),
+            ["dnf", "mark", "dependency"]
+                .into_iter()
+                .chain(needed.iter().copied()),
+            Perms::Root,
+        );
+
+        Ok(plan)
+    }
+
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        command_action(
+            ["dnf", "upgrade", "--refresh"]
+                .into_iter()
+                .chain(["--assumeyes"].into_iter().filter(|_| opts.no_confirm)),
+            Perms::Root,
+        )
+        .inspect(|_| log::info!("Successfully upgraded dnf packages"))
+        .map_err(|e| nest_errors!("Failed to upgrade dnf packages", e))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Dnf",
+            &[PACKAGE_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "Dnf")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("dnf".to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to remove all unused dnf dependencies?",
+                ["dnf", "autoremove"],
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(
+            ["dnf", "autoremove"]
+                .into_iter()
+                .chain(["--assumeyes"].into_iter().filter(|_| opts.no_confirm)),
+            Perms::Root,
+        )
+        .inspect(|_| log::info!("Successfully removed all unused dependencies"))
+        .map_err(|e| nest_errors!("Failed to clean cache for dnf", e))
+    }
+}
+
+impl Dnf {
+    fn get_configured_packages(&self) -> Result<HashSet<String>> {
+        let mut configured = HashSet::new();
+
+        for package in self.packages.keys() {
+            match package.strip_prefix(GROUP_PREFIX) {
+                Some(group) => configured.extend(get_group_packages(group)?),
+                None => {
+                    configured.insert(package.to_owned());
+                }
+            }
+        }
+
+        Ok(configured)
+    }
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, Option<Closure>)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The package-spec is not a record", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No package mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The package was not a string", e))?
+        .to_owned();
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {package} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((package, post_hook))
+}
+
+fn get_installed_packages(explicit: bool) -> Result<HashSet<String>> {
+    let flag = if explicit {
+        "--userinstalled"
+    } else {
+        "--installed"
+    };
+
+    let packages = run_command_for_stdout(
+        [
+            "dnf",
+            "repoquery",
+            "--quiet",
+            flag,
+            "--queryformat",
+            "%{name}\n",
+        ],
+        Perms::User,
+        false,
+    )
+    .map_err(|e| nest_errors!("Failed to get installed packages for dnf", e))?;
+
+    let packages = packages
+        .lines()
+        .map(str::trim)
+        .filter(|package| !package.is_empty())
+        .map(ToOwned::to_owned)
+        .collect();
+
+    Ok(packages)
+}
+
+/// Maps every one of the packages to the installed packages requiring it,
+/// through any of the capabilities or files it provides. rpm lists these for
+/// all of them at once, where dnf would need a query per package.
+fn get_dependents<'a>(packages: &BTreeSet<&'a str>) -> Result<BTreeMap<&'a str, HashSet<String>>> {
+    if packages.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let provides = run_command_for_stdout(
+        [
+            "rpm",
+            "--query",
+            "--queryformat",
+            "[%{NAME}\t%{PROVIDENAME}\n][%{NAME}\t%{FILENAMES}\n]",
+        ]
+        .into_iter()
+        .chain(packages.iter().copied()),
+        Perms::User,
+        false,
+    )
+    .map_err(|e| nest_errors!("Failed to get what the undeclared packages provide", e))?;
+
+    let requires = run_command_for_stdout(
+        [
+            "rpm",
+            "--query",
+            "--all",
+            "--queryformat",
+            "[%{NAME}\t%{REQUIRENAME}\n]",
+        ],
+        Perms::User,
+        false,
+    )
+    .map_err(|e| nest_errors!("Failed to get what the installed packages require", e))?;
+
+    Ok(parse_dependents(packages, &provides, &requires))
+}
+
+// Both queries print one `package<TAB>capability` line per provided or
+// required capability
+fn parse_dependents<'a>(
+    packages: &BTreeSet<&'a str>,
+    provides: &str,
+    requires: &str,
+) -> BTreeMap<&'a str, HashSet<String>> {
+    let mut by_capability: HashMap<&str, Vec<&str>> = HashMap::new();
+    provides
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .for_each(|(package, capability)| {
+            by_capability.entry(capability).or_default().push(package);
+        });
+
+    let mut dependents: BTreeMap<_, HashSet<_>> = packages
+        .iter()
+        .map(|package| (*package, HashSet::new()))
+        .collect();
+
+    for (dependent, capability) in requires.lines().filter_map(|line| line.split_once('\t')) {
+        for provider in by_capability.get(capability).into_iter().flatten() {
+            if let Some(provider_dependents) = dependents.get_mut(*provider)
+                && *provider != dependent
+            {
+                provider_dependents.insert(dependent.to_owned());
+            }
+        }
+    }
+
+    dependents
+}
+
+/// Resolves a group or an environment to the packages it installs by default.
+/// Environments list groups instead of packages, so those are resolved in
+/// turn.
+fn get_group_packages(group: &str) -> Result<Box<[String]>> {
+    let mut info = run_command_for_stdout(
+        ["dnf", "group", "info", "--quiet", group],
+        Perms::User,
+        false,
+    )
+    .map_err(|e| nest_errors!("Failed to get packages of group {group}", e))?;
+
+    // dnf5 only describes environments through their own subcommand
+    if info.trim().is_empty() {
+        info = run_command_for_stdout(
+            ["dnf", "environment", "info", "--quiet", group],
+            Perms::User,
+            false,
+        )
+        .map_err(|e| nest_errors!("Failed to get groups of environment {group}", e))?;
+    }
+
+    let (mut packages, groups) = parse_group_info(&info);
+
+    for member in groups {
+        let (member_packages, _) = parse_group_info(
+            &run_command_for_stdout(
+                ["dnf", "group", "info", "--quiet", member.as_str()],
+                Perms::User,
+                false,
+            )
+            .map_err(|e| nest_errors!("Failed to get packages of group {member}", e))?,
+        );
+
+        packages.extend(member_packages);
+    }
+
+    Ok(packages.into())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Packages,
+    Groups,
+    Other,
+}
+
+// dnf4 prints each section header on its own line followed by indented
+// members, while dnf5 aligns `key : value` pairs and continues lists with
+// lines starting with a colon. Only mandatory and default members are
+// installed along with the group.
+fn parse_group_info(info: &str) -> (Vec<String>, Vec<String>) {
+    let mut section = Section::Other;
+    let mut packages = Vec::new();
+    let mut groups = Vec::new();
+
+    for line in info.lines() {
+        let member = match line.split_once(':') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                let key = key.to_lowercase();
+                let installed = ["mandatory", "default", "required"]
+                    .iter()
+                    .any(|kind| key.contains(kind));
+
+                section = match (installed, key.contains("package"), key.contains("group")) {
+                    (true, true, _) => Section::Packages,
+                    (true, _, true) => Section::Groups,
+                    _ => Section::Other,
+                };
+
+                value
+            }
+            Some((_, value)) => value,
+            None => line,
+        };
+
+        let member = member.trim().trim_start_matches(['=', '+', '-']);
+
+        match section {
+            _ if member.is_empty() => {}
+            Section::Packages => packages.push(member.to_owned()),
+            Section::Groups => groups.push(member.to_owned()),
+            Section::Other => {}
+        }
+    }
+
+    (packages, groups)
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    #[test]
+    fn dnf_construction_ok() {
+        let pkg_record = Record::from_raw_cols_vals(
+            vec!["package".to_owned()],
+            vec![Value::string("@development-tools", Span::test_data())],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+        let record = Record::from_raw_cols_vals(
+            vec!["packages".to_owned()],
+            vec![Value::list(
+                vec![Value::record(pkg_record, Span::test_data())],
+                Span::test_data(),
+            )],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let dnf = Dnf::new(&record, &Record::new());
+        assert!(dnf.is_ok());
+        assert!(dnf.unwrap().packages.contains_key("@development-tools"));
+    }
+
+    #[test]
+    fn parse_group_info_dnf4() {
+        let info = concat!(
+            "Group: Development Tools\n",
+            " Description: A basic development environment.\n",
+            " Mandatory Packages:\n",
+            "   =autoconf\n",
+            "    automake\n",
+            " Default Packages:\n",
+            "   asciidoc\n",
+            " Optional Packages:\n",
+            "   cmake\n",
+        );
+
+        let (packages, groups) = parse_group_info(info);
+        assert_eq!(packages, ["autoconf", "automake", "asciidoc"]);
+        assert!(groups.is_empty());
+    }
+
+    #[test]
+    fn parse_group_info_dnf5() {
+        let info = concat!(
+            "Id                   : development-tools\n",
+            "Description          : A basic development environment.\n",
+            "Mandatory packages   : autoconf\n",
+            "                     : automake\n",
+            "Default packages     : asciidoc\n",
+            "Optional packages    : cmake\n",
+        );
+
+        let (packages, groups) = parse_group_info(info);
+        assert_eq!(packages, ["autoconf", "automake", "asciidoc"]);
+        assert!(groups.is_empty());
+    }
+
+    #[test]
+    fn parse_group_info_environment() {
+        let info = concat!(
+            "Environment Group: Fedora Workstation\n",
+            " Mandatory Groups:\n",
+            "   Common NetworkManager Submodules\n",
+            "   Core\n",
+            " Optional Groups:\n",
+            "   LibreOffice\n",
+        );
+
+        let (packages, groups) = parse_group_info(info);
+        assert!(packages.is_empty());
+        assert_eq!(groups, ["Common NetworkManager Submodules", "Core"]);
+    }
+
+    #[test]
+    fn parse_dependents_through_capabilities() {
+        let packages = BTreeSet::from(["libfoo", "python3", "htop"]);
+        let provides = concat!(
+            "libfoo\tlibfoo.so.1()(64bit)\n",
+            "libfoo\tlibfoo\n",
+            "python3\tpython3\n",
+            "python3\t/usr/bin/python3\n",
+            "htop\thtop\n",
+        );
+        let requires = concat!(
+            "foo\tlibfoo.so.1()(64bit)\n",
+            "libfoo\tlibfoo\n",
+            "meson\t/usr/bin/python3\n",
+            "meson\tninja-build\n",
+        );
+
+        let dependents = parse_dependents(&packages, provides, requires);
+        assert_eq!(dependents["libfoo"], HashSet::from(["foo".to_owned()]));
+        assert_eq!(dependents["python3"], HashSet::from(["meson".to_owned()]));
+        assert!(dependents["htop"].is_empty());
+    }
+}
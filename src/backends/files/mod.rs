@@ -0,0 +1,1003 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+use serde_json::Value as Json;
+
+use crate::commands::{Perms, run_command_for_stdout};
+use crate::output::nu_to_json;
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const FILE_LIST_KEY: &str = "files";
+const PATH_KEY: &str = "path";
+const CONTENT_KEY: &str = "content";
+const SOURCE_KEY: &str = "source";
+const VALUE_KEY: &str = "value";
+const FORMAT_KEY: &str = "format";
+const MODE_KEY: &str = "mode";
+const OWNER_KEY: &str = "owner";
+const HOOK_KEY: &str = "post_hook";
+
+const DEFAULT_MODE: u32 = 0o644;
+/// Lines of unchanged context kept around every change in a diff
+const DIFF_CONTEXT: usize = 2;
+/// Above this many pairs of lines a diff takes too long to be worth showing
+const DIFF_LIMIT: usize = 1_000_000;
+
+// Records the file as created by supac only if it was not there before, so
+// that clean only ever removes files supac wrote. Arguments: source, target,
+// mode, owner, state file and STATE_SCRIPT.
+const INSTALL_SCRIPT: &str = concat!(
+    r#"set -e; new=; if [ ! -e "$2" ]; then new=1; fi; "#,
+    r#"install -D -m "$3" "$1" "$2"; "#,
+    r#"if [ -n "$4" ]; then chown "$4" "$2"; fi; "#,
+    r#"if [ -n "$new" ]; then "#,
+    r##"${SUDO_UID:+sudo -u "#$SUDO_UID" --} sh -c "$6" sh "$5" "$2"; fi"##,
+);
+
+// Arguments: state file, STATE_SCRIPT and the files to remove
+const REMOVE_SCRIPT: &str = concat!(
+    r#"set -e; state="$1"; script="$2"; shift 2; rm --force -- "$@"; "#,
+    r##"${SUDO_UID:+sudo -u "#$SUDO_UID" --} sh -c "$script" sh "$state""##,
+);
+
+// Forgets the files that are gone, keeping the ones in directories the user
+// cannot look into, and adds the ones given. Run as the user even when the
+// step runs with sudo, so that the state file stays theirs. Arguments: state
+// file and the files to add.
+const STATE_SCRIPT: &str = concat!(
+    r#"set -e; state="$1"; shift; mkdir -p "${state%/*}"; touch "$state"; "#,
+    r#"{ while IFS= read -r path; do if [ -e "$path" ] || "#,
+    r#"{ [ -d "${path%/*}" ] && [ ! -x "${path%/*}" ]; }; then printf '%s\n' "$path"; fi; "#,
+    r#"done < "$state"; if [ $# -gt 0 ]; then printf '%s\n' "$@"; fi; } "#,
+    r#"| sort -u > "$state.new"; mv -- "$state.new" "$state""#,
+);
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum Format {
+    Toml,
+    Json,
+    Yaml,
+    Ini,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "ini" => Ok(Format::Ini),
+            _ => Err(mod_err!(
+                "Unsupported format {name}, expected toml, json, yaml or ini"
+            )),
+        }
+    }
+
+    fn from_path(path: &str) -> Option<Self> {
+        Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| Format::from_name(extension).ok())
+    }
+
+    fn render(self, value: &Value) -> Result<String> {
+        let json = nu_to_json(value)?;
+
+        match self {
+            Format::Toml => toml::to_string_pretty(&json).map_err(|e| mod_err!(e)),
+            Format::Json => serde_json::to_string_pretty(&json)
+                .map(|json| json + "\n")
+                .map_err(|e| mod_err!(e)),
+            Format::Yaml => serde_yaml::to_string(&json).map_err(|e| mod_err!(e)),
+            Format::Ini => to_ini(&json),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Content {
+    Literal(String),
+    /// Relative to the config dir unless absolute
+    Source(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct FileOpts {
+    content: Content,
+    mode: u32,
+    owner: Option<String>,
+    post_hook: Option<Closure>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Files {
+    files: HashMap<String, FileOpts>,
+    /// Lists every file supac has written, one per line
+    state_file: String,
+    /// Set after parsing with [`Files::set_config_dir`]
+    config_dir: Option<PathBuf>,
+}
+
+impl Backend for Files {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let files = value
+            .get(FILE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get files for Files"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The file list in Files is not a list", e))?
+            .iter()
+            .map(value_to_filespec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed files");
+        Ok(Files {
+            files,
+            state_file: get_state_dir()? + "/supac/files",
+            config_dir: None,
+        })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let managed = self.get_managed_files()?;
+
+        Ok(self
+            .get_extra_files(&managed)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, selection: Selection) -> Result<Plan> {
+        let managed = self.get_managed_files()?;
+
+        let mut plan = Plan::default();
+
+        // Reading the declared files is only needed to tell if they are up
+        // to date
+        let declared = self.files.iter().filter(|_| selection.additions());
+
+        for (path, spec) in declared.collect::<BTreeMap<_, _>>() {
+            let content = self.read_content(spec)?;
+            let perms = get_perms(path, spec.owner.is_some());
+            let current = read_existing(path, perms)?;
+
+            let up_to_date = match &current {
+                Existing::Content(current) => {
+                    *current == content
+                        && get_mode(path)? == spec.mode
+                        && match &spec.owner {
+                            Some(owner) => get_owner(path, owner)? == *owner,
+                            None => true,
+                        }
+                }
+                Existing::Missing | Existing::Unreadable => false,
+            };
+
+            if up_to_date {
+                continue;
+            }
+
+            let source = match &spec.content {
+                Content::Source(source) => self.resolve_source(source)?,
+                Content::Literal(_) => "/dev/stdin".to_owned(),
+            };
+
+            let command = [
+                "sh",
+                "-c",
+                INSTALL_SCRIPT,
+                "sh",
+                &source,
+                path,
+                &format!("{:o}", spec.mode),
+                spec.owner.as_deref().unwrap_or_default(),
+                &self.state_file,
+                STATE_SCRIPT,
+            ];
+
+            // Literal content is piped to the step, so that nothing is
+            // written before the plan is applied
+            match &spec.content {
+                Content::Source(_) => plan.push(Action::Install, [path], command, perms),
+                Content::Literal(literal) => plan.push_with_input(
+                    Action::Install,
+                    [path],
+                    command,
+                    perms,
+                    literal.to_owned(),
+                ),
+            }
+
+            match current {
+                Existing::Content(current) => plan.push_diff(
+                    path,
+                    diff_lines(
+                        &String::from_utf8_lossy(&current),
+                        &String::from_utf8_lossy(&content),
+                    ),
+                ),
+                Existing::Unreadable => plan.push_diff(
+                    path,
+                    Box::new(["~ only readable by root, written without comparing".to_owned()]),
+                ),
+                Existing::Missing => {}
+            }
+
+            plan.push_hook(spec.post_hook.as_ref());
+        }
+
+        let (user, root): (Vec<_>, Vec<_>) = self
+            .get_extra_files(&managed)
+            .into_iter()
+            .partition(|path| get_perms(path, false) == Perms::User);
+
+        for (extra, perms) in [(user, Perms::User), (root, Perms::Root)] {
+            plan.push(
+                Action::Remove,
+                extra.iter().copied(),
+                [
+                    "sh",
+                    "-c",
+                    REMOVE_SCRIPT,
+                    "sh",
+                    &self.state_file,
+                    STATE_SCRIPT,
+                ]
+                .into_iter()
+                .chain(extra.iter().copied()),
+                perms,
+            );
+        }
+
+        Ok(plan)
+    }
+
+    fn update(&self, _opts: &UpdateCommand) -> Result<()> {
+        log::info!("Files are brought up to date by sync, nothing to do");
+        Ok(())
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            FILE_LIST_KEY,
+            "Files",
+            &[
+                PATH_KEY,
+                CONTENT_KEY,
+                SOURCE_KEY,
+                VALUE_KEY,
+                FORMAT_KEY,
+                MODE_KEY,
+                OWNER_KEY,
+                HOOK_KEY,
+            ],
+            value_to_filespec,
+        );
+
+        unknown_keys(value, &[FILE_LIST_KEY], "Files")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("install".to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.files.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    fn clean_cache(&self, _config: &Record, _opts: &CleanCacheCommand) -> Result<()> {
+        log::info!("Files keep no cache, nothing to do");
+        Ok(())
+    }
+}
+
+impl Files {
+    pub fn set_config_dir(&mut self, config_dir: &Path) {
+        self.config_dir = Some(config_dir.to_owned());
+    }
+
+    fn resolve_source(&self, source: &str) -> Result<String> {
+        let source = expand_home(source)?;
+
+        if Path::new(&source).is_absolute() {
+            return Ok(source);
+        }
+
+        self.config_dir
+            .as_ref()
+            .map(|config_dir| config_dir.join(&source).to_string_lossy().into_owned())
+            .ok_or_else(|| mod_err!("No config dir to find {source} in"))
+    }
+
+    fn read_content(&self, spec: &FileOpts) -> Result<Vec<u8>> {
+        match &spec.content {
+            Content::Literal(content) => Ok(content.as_bytes().to_owned()),
+            Content::Source(source) => {
+                let source = self.resolve_source(source)?;
+                fs::read(&source).map_err(|e| nest_errors!("Failed to read {source}", e))
+            }
+        }
+    }
+
+    fn get_managed_files(&self) -> Result<BTreeSet<String>> {
+        let state_file = &self.state_file;
+
+        match fs::read_to_string(state_file) {
+            Ok(state) => Ok(state.lines().map(ToOwned::to_owned).collect()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(e) => Err(nest_errors!("Failed to read {state_file}", e)),
+        }
+    }
+
+    fn get_extra_files<'a>(&self, managed: &'a BTreeSet<String>) -> BTreeSet<&'a str> {
+        managed
+            .iter()
+            // Files in directories only root can look into might still be
+            // there
+            .filter(|path| {
+                !self.files.contains_key(*path)
+                    && !matches!(Path::new(path).try_exists(), Ok(false))
+            })
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// What is at the path of a declared file before it is written
+#[derive(PartialEq, Eq, Debug)]
+enum Existing {
+    Missing,
+    Content(Vec<u8>),
+    /// Only root can read it, so it cannot be compared
+    Unreadable,
+}
+
+fn read_existing(path: &str, perms: Perms) -> Result<Existing> {
+    to_existing(fs::read(path), path, perms)
+}
+
+// Files written with root may well be unreadable without it, like the ones in
+// /etc/sudoers.d. Planning runs as the user, so those are always written.
+fn to_existing(read: io::Result<Vec<u8>>, path: &str, perms: Perms) -> Result<Existing> {
+    match read {
+        Ok(content) => Ok(Existing::Content(content)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Existing::Missing),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied && perms == Perms::Root => {
+            Ok(Existing::Unreadable)
+        }
+        Err(e) => Err(nest_errors!("Failed to read {path}", e)),
+    }
+}
+
+fn get_mode(path: &str) -> Result<u32> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.permissions().mode() & 0o7777),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(nest_errors!("Failed to read the mode of {path}", e)),
+    }
+}
+
+/// The owner of the file in the same form as the declared one, with the
+/// group only if that has it
+fn get_owner(path: &str, declared: &str) -> Result<String> {
+    let format = if declared.contains(':') {
+        "%U:%G"
+    } else {
+        "%U"
+    };
+
+    run_command_for_stdout(["stat", "--format", format, path], Perms::User, true)
+        .map(|owner| owner.trim().to_owned())
+        .map_err(|e| nest_errors!("Failed to get the owner of {path}", e))
+}
+
+// Files outside the home directory, or given away to someone else, are
+// only writable with root
+fn get_perms(path: &str, has_owner: bool) -> Perms {
+    let in_home =
+        env::var("HOME").is_ok_and(|home| !home.is_empty() && Path::new(path).starts_with(home));
+
+    if in_home && !has_owner {
+        Perms::User
+    } else {
+        Perms::Root
+    }
+}
+
+fn expand_home(path: &str) -> Result<String> {
+    match path.strip_prefix("~/") {
+        Some(rest) => {
+            let home = env::var("HOME").map_err(|e| nest_errors!("Failed to expand {path}", e))?;
+            Ok([home.as_str(), rest].join("/"))
+        }
+        None => Ok(path.to_owned()),
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| mod_err!("Invalid mode {mode}, expected octal digits like 644"))
+}
+
+/// Top level records become sections, anything else goes before them
+fn to_ini(json: &Json) -> Result<String> {
+    let Json::Object(fields) = json else {
+        return Err(mod_err!("An ini file can only be made from a record"));
+    };
+
+    let (sections, globals): (Vec<_>, Vec<_>) =
+        fields.iter().partition(|(_, field)| field.is_object());
+
+    let mut lines = globals
+        .into_iter()
+        .map(|(key, field)| ini_entry(key, field))
+        .collect::<Result<Vec<_>>>()?;
+
+    for (name, section) in sections {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+
+        lines.push(format!("[{name}]"));
+
+        if let Json::Object(entries) = section {
+            for (key, entry) in entries {
+                lines.push(ini_entry(key, entry)?);
+            }
+        }
+    }
+
+    Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+fn ini_entry(key: &str, value: &Json) -> Result<String> {
+    let value = match value {
+        Json::String(value) => value.to_owned(),
+        Json::Bool(_) | Json::Number(_) => value.to_string(),
+        Json::Null => String::new(),
+        Json::Array(_) | Json::Object(_) => {
+            return Err(mod_err!("{key} is nested too deep for an ini file"));
+        }
+    };
+
+    Ok(format!("{key} = {value}"))
+}
+
+/// A line diff of the two texts, with a few lines of context around every
+/// change and `...` where unchanged lines are left out
+fn diff_lines(old: &str, new: &str) -> Box<[String]> {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    if old.len().saturating_mul(new.len()) > DIFF_LIMIT {
+        return Box::new([format!(
+            "~ {} lines replaced by {} lines",
+            old.len(),
+            new.len()
+        )]);
+    }
+
+    // The length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0_usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            ops.push(('-', old[i]));
+            i += 1;
+        } else {
+            ops.push(('+', new[j]));
+            j += 1;
+        }
+    }
+
+    let changes: Vec<_> = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, (sign, _))| *sign != ' ')
+        .map(|(index, _)| index)
+        .collect();
+
+    let mut lines = Vec::new();
+    let mut last = None;
+
+    for (index, (sign, line)) in ops.iter().enumerate() {
+        if !changes
+            .iter()
+            .any(|change| change.abs_diff(index) <= DIFF_CONTEXT)
+        {
+            continue;
+        }
+
+        if last.is_some_and(|last| index > last + 1) {
+            lines.push("...".to_owned());
+        }
+
+        lines.push(format!("{sign} {line}"));
+        last = Some(index);
+    }
+
+    lines.into()
+}
+
+fn get_state_dir() -> Result<String> {
+    env::var("XDG_STATE_HOME").or_else(|e| -> Result<String> {
+        log::debug!("Encountered error: {e}");
+        log::debug!("Using the default: ~/.local/state");
+        let home = env::var("HOME")?;
+        Ok(home + "/.local/state")
+    })
+}
+
+fn value_to_filespec(value: &Value) -> Result<(String, FileOpts)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The file-spec is not a record", e))?;
+
+    let path = record
+        .get(PATH_KEY)
+        .ok_or_else(|| mod_err!("No path mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The path was not a string", e))?;
+    let path = expand_home(path)?;
+
+    if !Path::new(&path).is_absolute() {
+        return Err(mod_err!("{path} is not an absolute path"));
+    }
+
+    let format = match record.get(FORMAT_KEY) {
+        Some(format) => {
+            Some(Format::from_name(format.as_str().map_err(|e| {
+                nest_errors!("Format for {path} is not a string", e)
+            })?)?)
+        }
+        None => None,
+    };
+
+    let content = match (
+        record.get(CONTENT_KEY),
+        record.get(SOURCE_KEY),
+        record.get(VALUE_KEY),
+    ) {
+        (Some(content), None, None) => Content::Literal(
+            content
+                .as_str()
+                .map_err(|e| nest_errors!("Content for {path} is not a string", e))?
+                .to_owned(),
+        ),
+        (None, Some(source), None) => Content::Source(
+            source
+                .as_str()
+                .map_err(|e| nest_errors!("Source for {path} is not a string", e))?
+                .to_owned(),
+        ),
+        (None, None, Some(structured)) => {
+            let format = format
+                .or_else(|| Format::from_path(&path))
+                .ok_or_else(|| mod_err!("No format for {path}, and none in its extension"))?;
+
+            Content::Literal(
+                format
+                    .render(structured)
+                    .map_err(|e| nest_errors!("Failed to render {path}", e))?,
+            )
+        }
+        _ => {
+            return Err(mod_err!(
+                "{path} needs exactly one of {CONTENT_KEY}, {SOURCE_KEY} or {VALUE_KEY}"
+            ));
+        }
+    };
+
+    if format.is_some() && record.get(VALUE_KEY).is_none() {
+        return Err(mod_err!(
+            "{FORMAT_KEY} for {path} only applies to {VALUE_KEY}"
+        ));
+    }
+
+    let mode = match record.get(MODE_KEY) {
+        Some(mode) => parse_mode(
+            mode.as_str()
+                .map_err(|e| nest_errors!("Mode for {path} is not a string", e))?,
+        )?,
+        None => DEFAULT_MODE,
+    };
+
+    let owner = match record.get(OWNER_KEY) {
+        Some(owner) => Some(
+            owner
+                .as_str()
+                .map_err(|e| nest_errors!("Owner for {path} is not a string", e))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {path} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((
+        path,
+        FileOpts {
+            content,
+            mode,
+            owner,
+            post_hook,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use crate::commands::run_command_with_stdin;
+
+    use super::*;
+
+    #[test]
+    fn render_formats() {
+        let settings = Value::record(
+            Record::from_raw_cols_vals(
+                ["name", "core"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("foo", Span::test_data()),
+                    Value::record(
+                        Record::from_raw_cols_vals(
+                            ["editor", "autocrlf"]
+                                .into_iter()
+                                .map(ToOwned::to_owned)
+                                .collect(),
+                            vec![
+                                Value::string("nvim", Span::test_data()),
+                                Value::bool(false, Span::test_data()),
+                            ],
+                            Span::test_data(),
+                            Span::test_data(),
+                        )
+                        .unwrap(),
+                        Span::test_data(),
+                    ),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        assert_eq!(
+            Format::Toml.render(&settings).unwrap(),
+            "name = \"foo\"\n\n[core]\neditor = \"nvim\"\nautocrlf = false\n"
+        );
+        assert_eq!(
+            Format::Yaml.render(&settings).unwrap(),
+            "name: foo\ncore:\n  editor: nvim\n  autocrlf: false\n"
+        );
+        assert_eq!(
+            Format::Ini.render(&settings).unwrap(),
+            "name = foo\n\n[core]\neditor = nvim\nautocrlf = false\n"
+        );
+        assert!(
+            Format::Json
+                .render(&settings)
+                .unwrap()
+                .starts_with("{\n  \"name\": \"foo\",")
+        );
+    }
+
+    #[test]
+    fn ini_too_deep() {
+        let settings = Value::record(
+            Record::from_raw_cols_vals(
+                ["name", "core"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("foo", Span::test_data()),
+                    Value::record(
+                        Record::from_raw_cols_vals(
+                            ["editor", "autocrlf"]
+                                .into_iter()
+                                .map(ToOwned::to_owned)
+                                .collect(),
+                            vec![
+                                Value::string("nvim", Span::test_data()),
+                                Value::bool(false, Span::test_data()),
+                            ],
+                            Span::test_data(),
+                            Span::test_data(),
+                        )
+                        .unwrap(),
+                        Span::test_data(),
+                    ),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        let value = Value::record(
+            Record::from_raw_cols_vals(
+                ["core"].into_iter().map(ToOwned::to_owned).collect(),
+                vec![settings],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        assert!(Format::Ini.render(&value).is_err());
+        assert!(
+            Format::Ini
+                .render(&Value::string("foo", Span::test_data()))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn parse_mode_octal() {
+        assert_eq!(parse_mode("644").unwrap(), 0o644);
+        assert_eq!(parse_mode("0755").unwrap(), 0o755);
+        assert_eq!(parse_mode("0o600").unwrap(), 0o600);
+        assert!(parse_mode("rw-r--r--").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn value_to_filespec_content() {
+        let settings = Value::record(
+            Record::from_raw_cols_vals(
+                ["name", "core"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("foo", Span::test_data()),
+                    Value::record(
+                        Record::from_raw_cols_vals(
+                            ["editor", "autocrlf"]
+                                .into_iter()
+                                .map(ToOwned::to_owned)
+                                .collect(),
+                            vec![
+                                Value::string("nvim", Span::test_data()),
+                                Value::bool(false, Span::test_data()),
+                            ],
+                            Span::test_data(),
+                            Span::test_data(),
+                        )
+                        .unwrap(),
+                        Span::test_data(),
+                    ),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        let (path, spec) = value_to_filespec(&Value::record(
+            Record::from_raw_cols_vals(
+                [PATH_KEY, VALUE_KEY, MODE_KEY]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("/etc/foo.toml", Span::test_data()),
+                    settings.clone(),
+                    Value::string("600", Span::test_data()),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        ))
+        .unwrap();
+
+        assert_eq!(path, "/etc/foo.toml");
+        assert_eq!(spec.mode, 0o600);
+        assert!(matches!(spec.content, Content::Literal(content) if content.starts_with("name")));
+
+        // No way to tell the format
+        assert!(
+            value_to_filespec(&Value::record(
+                Record::from_raw_cols_vals(
+                    [PATH_KEY, VALUE_KEY]
+                        .into_iter()
+                        .map(ToOwned::to_owned)
+                        .collect(),
+                    vec![Value::string("/etc/foo.conf", Span::test_data()), settings],
+                    Span::test_data(),
+                    Span::test_data()
+                )
+                .unwrap(),
+                Span::test_data()
+            ))
+            .is_err()
+        );
+
+        // Both content and source
+        assert!(
+            value_to_filespec(&Value::record(
+                Record::from_raw_cols_vals(
+                    [PATH_KEY, CONTENT_KEY, SOURCE_KEY]
+                        .into_iter()
+                        .map(ToOwned::to_owned)
+                        .collect(),
+                    vec![
+                        Value::string("/etc/foo", Span::test_data()),
+                        Value::string("foo", Span::test_data()),
+                        Value::string("foo", Span::test_data())
+                    ],
+                    Span::test_data(),
+                    Span::test_data()
+                )
+                .unwrap(),
+                Span::test_data()
+            ))
+            .is_err()
+        );
+
+        assert!(
+            value_to_filespec(&Value::record(
+                Record::from_raw_cols_vals(
+                    [PATH_KEY, CONTENT_KEY]
+                        .into_iter()
+                        .map(ToOwned::to_owned)
+                        .collect(),
+                    vec![
+                        Value::string("foo", Span::test_data()),
+                        Value::string("foo", Span::test_data())
+                    ],
+                    Span::test_data(),
+                    Span::test_data()
+                )
+                .unwrap(),
+                Span::test_data()
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn clean_only_removes_created_files() {
+        let dir = env::temp_dir().join(format!("supac-files-{}", std::process::id()));
+        let dir = dir.to_string_lossy();
+        let (created, existing) = (format!("{dir}/created"), format!("{dir}/existing"));
+        let state_file = format!("{dir}/state/files");
+
+        fs::create_dir_all(&*dir).unwrap();
+        fs::write(&existing, "old").unwrap();
+
+        let spec = |content: &str| FileOpts {
+            content: Content::Literal(content.to_owned()),
+            mode: DEFAULT_MODE,
+            owner: None,
+            post_hook: None,
+        };
+        let mut files = Files {
+            files: HashMap::from([
+                (created.to_owned(), spec("foo")),
+                (existing.to_owned(), spec("bar")),
+            ]),
+            state_file: state_file.to_owned(),
+            config_dir: None,
+        };
+
+        // Run as the user, the paths are outside the home directory
+        let apply = |plan: Plan| {
+            for step in plan.steps {
+                run_command_with_stdin(
+                    step.command,
+                    Perms::User,
+                    step.input.as_deref().unwrap_or_default(),
+                )
+                .unwrap();
+            }
+        };
+
+        let plan = files.plan(true, Selection::All).unwrap();
+        assert_eq!(plan.steps.len(), 2);
+        assert!(!Path::new(&created).exists());
+        assert!(!Path::new(&state_file).exists());
+
+        apply(plan);
+        assert_eq!(fs::read_to_string(&created).unwrap(), "foo");
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "bar");
+        assert_eq!(
+            fs::read_to_string(&state_file).unwrap(),
+            created.to_owned() + "\n"
+        );
+
+        files.files.clear();
+        assert_eq!(*files.unmanaged().unwrap(), [created.as_str()]);
+
+        apply(files.plan(true, Selection::All).unwrap());
+        assert!(!Path::new(&created).exists());
+        assert!(Path::new(&existing).exists());
+        assert_eq!(fs::read_to_string(&state_file).unwrap(), "");
+
+        fs::remove_dir_all(&*dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_only_with_root() {
+        let denied = || Err(io::Error::from(ErrorKind::PermissionDenied));
+        let missing = Err(io::Error::from(ErrorKind::NotFound));
+
+        assert_eq!(
+            to_existing(denied(), "/etc/sudoers.d/foo", Perms::Root).unwrap(),
+            Existing::Unreadable
+        );
+        assert!(to_existing(denied(), "/home/foo/.bashrc", Perms::User).is_err());
+        assert_eq!(
+            to_existing(missing, "/etc/foo", Perms::Root).unwrap(),
+            Existing::Missing
+        );
+    }
+
+    #[test]
+    fn diff_lines_with_context() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\n";
+
+        assert_eq!(
+            *diff_lines(old, new),
+            [
+                "  a", "- b", "+ B", "  c", "  d", "...", "  g", "  h", "+ i"
+            ]
+        );
+        assert!(diff_lines(old, old).is_empty());
+    }
+}
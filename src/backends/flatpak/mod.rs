@@ -1,15 +1,14 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
 use anyhow::{Result, anyhow};
 use nu_protocol::Value;
 use nu_protocol::{Record, engine::Closure};
 
-use crate::commands::{
-    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
-};
+use crate::commands::{Perms, dry_run_command, run_command, run_command_for_stdout};
 use crate::config::{DEFAULT_FLATPAK_SYSTEMWIDE, FLATPAK_DEFAULT_SYSTEMWIDE_KEY};
-use crate::parser::Engine;
-use crate::{CleanCacheCommand, CleanCommand, SyncCommand, function, mod_err, nest_errors};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
 
 use super::Backend;
 
@@ -38,9 +37,18 @@ pub struct PinOpts {
     post_hook: Option<Closure>,
 }
 
+#[derive(Clone, Debug)]
+pub struct RemoteOpts {
+    url: String,
+    systemwide: bool,
+}
+
 #[derive(Clone, Debug)]
 pub struct Flatpak {
-    _remotes: HashMap<String, String>,
+    /// Remotes are only added and removed once a remotes list is declared
+    manage_remotes: bool,
+    user_remotes: HashMap<String, RemoteOpts>,
+    system_remotes: HashMap<String, RemoteOpts>,
     user_pinned: HashMap<String, PinOpts>,
     system_pinned: HashMap<String, PinOpts>,
     user_packages: HashMap<String, FlatpakOpts>,
@@ -49,25 +57,15 @@ pub struct Flatpak {
 
 impl Backend for Flatpak {
     fn new(value: &Record, config: &Record) -> Result<Self> {
-        let default_systemwide = match config.get(FLATPAK_DEFAULT_SYSTEMWIDE_KEY) {
-            Some(val) => val.as_bool().map_err(|e| {
-                nest_errors!(
-                    "value for {FLATPAK_DEFAULT_SYSTEMWIDE_KEY} not a boolean",
-                    e
-                )
-            })?,
-            None => {
-                log::info!("Value not specified in config, using default false");
-                DEFAULT_FLATPAK_SYSTEMWIDE
-            }
-        };
+        let default_systemwide = get_default_systemwide(config)?;
 
-        let remotes = match value.get(REMOTE_LIST_KEY) {
+        let manage_remotes = value.contains(REMOTE_LIST_KEY);
+        let (user_remotes, system_remotes) = match value.get(REMOTE_LIST_KEY) {
             Some(remotes) => remotes
                 .as_list()
-                .map(values_to_remotes)
-                .map_err(|e| nest_errors!("Remotes specified were not a list", e))?,
-            None => HashMap::new(),
+                .map_err(|e| nest_errors!("Remotes specified were not a list", e))
+                .and_then(|values| values_to_remotes(values, default_systemwide))?,
+            None => (HashMap::new(), HashMap::new()),
         };
 
         let (user_pinned, system_pinned) = match value.get(PINNED_KEY) {
@@ -92,7 +90,9 @@ impl Backend for Flatpak {
         log::info!("Successfully parsed flatpak packages");
 
         Ok(Flatpak {
-            _remotes: remotes,
+            manage_remotes,
+            user_remotes,
+            system_remotes,
             user_pinned,
             system_pinned,
             user_packages,
@@ -100,50 +100,146 @@ impl Backend for Flatpak {
         })
     }
 
-    fn install(&self, engine: &mut Engine, opts: &SyncCommand) -> Result<()> {
-        let mut closures = Vec::new();
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        [false, true]
+            .into_iter()
+            .map(|systemwide| -> Result<Vec<String>> {
+                let scope = if systemwide { "system" } else { "user" };
+
+                let pins = self.get_extra_pins(systemwide)?;
+                let packages = self.get_extra_packages(systemwide)?;
+                let remotes = self
+                    .get_extra_remotes(systemwide)?
+                    .iter()
+                    .map(|remote| ["remotes", remote].join("/"))
+                    .collect::<Box<[_]>>();
+
+                Ok(packages
+                    .iter()
+                    .chain(pins.iter())
+                    .chain(remotes.iter())
+                    .map(|package| [scope, package].join("/"))
+                    .collect())
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|unmanaged| unmanaged.into_iter().flatten().collect())
+    }
 
-        let installed_user_packages = run_command_for_stdout(
-            ["flatpak", "list", "--user", "--columns=application"],
-            Perms::User,
-            false,
-        )
-        .map_err(|e| nest_errors!("Failed to find listed user flatpak packages", e))?;
-        let installed_user_packages: HashSet<_> = installed_user_packages.lines().collect();
+    fn plan(&self, no_confirm: bool, selection: Selection) -> Result<Plan> {
+        let mut plan = Plan::default();
 
-        self.install_pins(&installed_user_packages, &mut closures, false, opts)?;
-        self.install_packages(&installed_user_packages, &mut closures, false, opts)?;
+        self.plan_scope(false, no_confirm, selection, &mut plan)?;
+        self.plan_scope(true, no_confirm, selection, &mut plan)?;
 
-        let installed_system_packages = run_command_for_stdout(
-            ["flatpak", "list", "--system", "--columns=application"],
-            Perms::User,
-            false,
-        )
-        .map_err(|e| nest_errors!("Failed to find listed user flatpak packages", e))?;
-        let installed_system_packages: HashSet<_> = installed_system_packages.lines().collect();
+        Ok(plan)
+    }
 
-        self.install_pins(&installed_system_packages, &mut closures, true, opts)?;
-        self.install_packages(&installed_system_packages, &mut closures, true, opts)?;
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
 
-        closures
+        ["--user", "--system"]
+            .into_iter()
+            .try_for_each(|systemwide_flag| {
+                command_action(
+                    ["flatpak", "update", systemwide_flag]
+                        .into_iter()
+                        .chain(["--assumeyes"].into_iter().filter(|_| opts.no_confirm)),
+                    Perms::User,
+                )
+                .map_err(|e| nest_errors!("Failed to update {systemwide_flag} packages", e))
+            })
+            .inspect(|_| log::info!("Successfully updated flatpak packages"))
+    }
+
+    fn validate(value: &Record, config: &Record) -> Vec<Diagnostic> {
+        let default_systemwide = get_default_systemwide(config).unwrap_or_default();
+
+        let (remotes, remote_diagnostics) = check_records(
+            value,
+            REMOTE_LIST_KEY,
+            "Flatpak",
+            &[PACKAGE_KEY, URL_KEY, SYSTEMWIDE_KEY],
+            // The same remote is usually needed in both scopes, so names are
+            // only unique within one
+            |value| {
+                value_to_remote(value, default_systemwide)
+                    .map(|(name, opts)| (scoped_name(&name, opts.systemwide), opts))
+            },
+        );
+
+        let (_, pin_diagnostics) = check_records(
+            value,
+            PINNED_KEY,
+            "Flatpak",
+            &[PACKAGE_KEY, BRANCH_KEY, ARCH_KEY, SYSTEMWIDE_KEY, HOOK_KEY],
+            |value| value_to_pinspec(value, default_systemwide),
+        );
+
+        let (packages, package_diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Flatpak",
+            &[PACKAGE_KEY, REMOTE_KEY, SYSTEMWIDE_KEY, HOOK_KEY],
+            |value| value_to_pkgspec(value, default_systemwide),
+        );
+
+        let declared_remotes: HashSet<_> = remotes.iter().map(|(name, _)| name.as_str()).collect();
+
+        let undeclared_remotes = packages
             .iter()
-            .try_for_each(|closure| {
-                if opts.dry_run {
-                    engine.dry_run_closure(closure)
-                } else {
-                    engine.execute_closure(closure)
-                }
+            .filter_map(|(package, opts)| {
+                Some((
+                    package,
+                    scoped_name(opts.remote.as_deref()?, opts.systemwide),
+                ))
             })
-            .inspect(|_| log::info!("Successful flatpak closure execution"))
-            .map_err(|e| nest_errors!("Failed to execute post hooks", e))
+            .filter(|(_, remote)| !declared_remotes.contains(remote.as_str()))
+            .map(|(package, remote)| {
+                Diagnostic::error(
+                    "Flatpak.packages",
+                    format!("remote `{remote}` used by {package} is not declared in remotes"),
+                )
+            });
+
+        unknown_keys(
+            value,
+            &[REMOTE_LIST_KEY, PINNED_KEY, PACKAGE_LIST_KEY],
+            "Flatpak",
+        )
+        .into_iter()
+        .chain(remote_diagnostics)
+        .chain(pin_diagnostics)
+        .chain(package_diagnostics)
+        .chain(undeclared_remotes)
+        .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("flatpak".to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        [
+            self.user_pinned.len(),
+            self.system_pinned.len(),
+            self.user_packages.len(),
+            self.system_packages.len(),
+        ]
+        .iter()
+        .sum()
     }
 
-    fn remove(&self, opts: &CleanCommand) -> Result<()> {
-        self.remove_pins(false, opts)?;
-        self.remove_pins(true, opts)?;
+    fn settings(config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        let default_systemwide = get_default_systemwide(config)?;
 
-        self.remove_packages(false, opts)?;
-        self.remove_packages(true, opts)
+        Ok(Box::new([(
+            FLATPAK_DEFAULT_SYSTEMWIDE_KEY,
+            default_systemwide.to_string(),
+        )]))
     }
 
     fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
@@ -169,266 +265,433 @@ impl Backend for Flatpak {
 }
 
 impl Flatpak {
-    fn install_pins<'a>(
-        &'a self,
-        installed_packages: &HashSet<&str>,
-        closures: &mut Vec<&'a Closure>,
+    fn plan_scope(
+        &self,
         systemwide: bool,
-        command_opts: &SyncCommand,
+        no_confirm: bool,
+        selection: Selection,
+        plan: &mut Plan,
     ) -> Result<()> {
-        let (systemwide_flag, configured_pins) = if systemwide {
-            ("--system", &self.system_pinned)
-        } else {
-            ("--user", &self.user_pinned)
-        };
-
-        let installed_pins =
-            run_command_for_stdout(["flatpak", "pin", systemwide_flag], Perms::User, true)
-                .map_err(|e| nest_errors!("Failed to check for pinned packages", e))?;
-
-        let installed_pins: HashMap<_, _> = installed_pins
-            .lines()
-            .map(|runtime| runtime.trim())
-            .map(|runtime| parse_runtime_format(runtime, false))
-            .filter(|runtime| installed_packages.contains(runtime.0))
-            .collect();
-
-        let missing_pins: Box<[_]> = configured_pins
-            .iter()
-            .filter(|(package, _)| !installed_pins.contains_key(package.as_str()))
-            .inspect(|(_, opts)| {
-                if let Some(hook) = opts.post_hook.as_ref() {
-                    closures.push(hook);
-                }
-            })
-            .map(|(pin, opts)| {
+        let (systemwide_flag, configured_remotes, configured_pins, configured_packages) =
+            if systemwide {
                 (
-                    pin,
-                    opts.arch
-                        .as_ref()
-                        .map(|s| "/".to_owned() + s)
-                        .unwrap_or_else(|| "".to_owned()),
-                    opts.branch
-                        .as_ref()
-                        .map(|s| "/".to_owned() + s)
-                        .unwrap_or_else(|| "".to_owned()),
+                    "--system",
+                    &self.system_remotes,
+                    &self.system_pinned,
+                    &self.system_packages,
                 )
-            })
-            .map(|s| [s.0.as_str(), s.1.as_str(), s.2.as_str()].join(""))
-            .collect();
+            } else {
+                (
+                    "--user",
+                    &self.user_remotes,
+                    &self.user_pinned,
+                    &self.user_packages,
+                )
+            };
 
-        let command_action = if command_opts.dry_run {
-            dry_run_command
-        } else {
-            run_command
-        };
+        let installed_remotes = get_installed_remotes(systemwide_flag)?;
+        check_remote_urls(configured_remotes, &installed_remotes)?;
 
-        if missing_pins.is_empty() {
-            log::info!("No missing pins to install");
-            return Ok(());
-        }
+        let installed_refs = get_installed_refs(systemwide_flag)?;
+        let installed_refs = parse_refs(&installed_refs);
+        let installed_packages: HashSet<_> =
+            installed_refs.iter().map(|(package, _)| *package).collect();
 
-        if !command_opts.no_confirm
-            && !confirmation_prompt(
-                "Do you want to install the following pins for flatpak?: ",
-                &missing_pins,
-            )?
-        {
-            return Ok(());
-        }
+        let installed_pins = get_installed_pins(systemwide_flag)?;
 
-        missing_pins
+        let assume_yes: Option<&'static str> = no_confirm.then_some("--assumeyes");
+
+        configured_remotes
             .iter()
-            .try_for_each(|pin| {
-                run_command(
-                    ["flatpak", "pin", systemwide_flag, pin.as_str()],
+            .filter(|(remote, _)| !installed_remotes.contains_key(*remote))
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .for_each(|(remote, opts)| {
+                plan.push(
+                    Action::AddRemote,
+                    [remote],
+                    [
+                        "flatpak",
+                        "remote-add",
+                        "--if-not-exists",
+                        systemwide_flag,
+                        remote,
+                        &opts.url,
+                    ],
                     Perms::User,
-                )
-                .map_err(|e| nest_errors!("Failed to pin packages", e))
+                );
+            });
+
+        let missing_pins: Box<[_]> = configured_pins
+            .iter()
+            .filter(|(pin, opts)| {
+                !is_pinned(&installed_pins, pin, opts, systemwide)
+                    || !installed_packages.contains(pin.as_str())
             })
-            .inspect(|_| log::info!("Pinned the missing runtime patterns"))?;
+            .inspect(|(_, opts)| plan.push_hook(opts.post_hook.as_ref()))
+            .map(|(pin, opts)| pin_pattern(pin, opts))
+            .collect();
 
-        command_action(
+        missing_pins.iter().for_each(|pin| {
+            plan.push(
+                Action::Pin,
+                [pin],
+                ["flatpak", "pin", systemwide_flag, pin],
+                Perms::User,
+            );
+        });
+        plan.push(
+            Action::Install,
+            missing_pins.iter(),
             ["flatpak", "install", systemwide_flag]
                 .into_iter()
-                .chain(missing_pins.iter().map(|s| s.as_str())),
+                .chain(assume_yes)
+                .chain(missing_pins.iter().map(String::as_str)),
             Perms::User,
-        )
-        .inspect(|_| log::info!("Installed the missing runtime patterns"))
-        .map_err(|e| nest_errors!("Failed to install packages", e))
-    }
+        );
 
-    fn install_packages<'a>(
-        &'a self,
-        installed_packages: &HashSet<&str>,
-        closures: &mut Vec<&'a Closure>,
-        systemwide: bool,
-        command_opts: &SyncCommand,
-    ) -> Result<()> {
-        let (systemwide_flag, configured_packages) = if systemwide {
-            ("--system", &self.system_packages)
-        } else {
-            ("--user", &self.user_packages)
-        };
+        let missing_packages: BTreeMap<_, _> = configured_packages
+            .iter()
+            .filter(|(package, _)| !installed_packages.contains(package.as_str()))
+            .inspect(|(_, opts)| plan.push_hook(opts.post_hook.as_ref()))
+            .collect();
 
-        let mut free_packages = configured_packages
+        let free_packages: Box<[_]> = missing_packages
             .iter()
             .filter(|(_, opts)| opts.remote.is_none())
-            .filter(|(package, _)| !installed_packages.contains(package.as_str()))
-            .inspect(|(_, opt)| {
-                if let Some(hook) = opt.post_hook.as_ref() {
-                    closures.push(hook);
-                }
-            })
             .map(|(package, _)| package.as_str())
-            .peekable();
+            .collect();
 
-        if free_packages.peek().is_some() {
-            run_command(
-                ["flatpak", "install", systemwide_flag]
-                    .into_iter()
-                    .chain(free_packages),
-                Perms::User,
-            )
-            .map_err(|e| nest_errors!("failed to install remote-agnostic packages", e))?;
-        }
+        plan.push(
+            Action::Install,
+            free_packages.iter().copied(),
+            ["flatpak", "install", systemwide_flag]
+                .into_iter()
+                .chain(assume_yes)
+                .chain(free_packages.iter().copied()),
+            Perms::User,
+        );
 
-        log::info!("Installed remote-agnostic packages");
+        missing_packages
+            .iter()
+            .filter_map(|(package, opts)| Some((package, opts.remote.as_deref()?)))
+            .for_each(|(package, remote)| {
+                plan.push(
+                    Action::Install,
+                    [*package],
+                    ["flatpak", "install", systemwide_flag]
+                        .into_iter()
+                        .chain(assume_yes)
+                        .chain([remote, package]),
+                    Perms::User,
+                );
+            });
 
-        let ref_packages = configured_packages
+        if !selection.removals() {
+            return Ok(());
+        }
+
+        extra_pins(configured_pins, &installed_pins, systemwide)
             .iter()
-            .filter(|(package, _)| !installed_packages.contains(package.as_str()))
-            .filter_map(|(package, opts)| {
-                opts.remote
-                    .as_ref()
-                    .map(|remote| (package, remote, opts.post_hook.as_ref()))
+            .for_each(|pin| {
+                plan.push(
+                    Action::Unpin,
+                    [*pin],
+                    ["flatpak", "pin", "--remove", systemwide_flag, pin],
+                    Perms::User,
+                );
             });
 
-        let command_action = if command_opts.dry_run {
-            dry_run_command
-        } else {
-            run_command
-        };
+        let installed_apps = get_installed_apps(systemwide_flag)?;
+
+        let extra_packages: BTreeSet<_> = installed_apps
+            .lines()
+            .filter(|package| !configured_packages.contains_key(*package))
+            .collect();
 
-        for (package, remote, hook) in ref_packages {
-            if let Some(hook) = hook {
-                closures.push(hook);
-            }
+        plan.push(
+            Action::Remove,
+            extra_packages.iter().copied(),
+            ["flatpak", "remove", systemwide_flag, "--delete-data"]
+                .into_iter()
+                .chain(assume_yes)
+                .chain(extra_packages.iter().copied()),
+            Perms::User,
+        );
 
-            command_action(
-                ["flatpak", "install", systemwide_flag, remote, package],
-                Perms::User,
-            )
-            .map_err(|e| {
-                nest_errors!(
-                    "Failed to install package {package} from remote {remote}",
-                    e
-                )
-            })?;
-        }
+        let used_remotes = used_remotes(&installed_refs, &extra_packages, configured_packages);
 
-        log::info!("Installed remote-specific packages");
+        self.removable_remotes(configured_remotes, &installed_remotes, &used_remotes)
+            .into_iter()
+            .for_each(|remote| {
+                plan.push(
+                    Action::RemoveRemote,
+                    [remote],
+                    ["flatpak", "remote-delete", systemwide_flag, remote],
+                    Perms::User,
+                );
+            });
 
         Ok(())
     }
 
-    fn remove_pins(&self, systemwide: bool, opts: &CleanCommand) -> Result<()> {
-        let (systemwide_flag, configured_pins) = if systemwide {
-            ("--system", &self.system_pinned)
+    fn get_extra_remotes(&self, systemwide: bool) -> Result<Box<[String]>> {
+        let (systemwide_flag, configured_remotes, configured_packages) = if systemwide {
+            ("--system", &self.system_remotes, &self.system_packages)
         } else {
-            ("--user", &self.user_pinned)
+            ("--user", &self.user_remotes, &self.user_packages)
         };
 
-        let pins = run_command_for_stdout(["flatpak", "pin", systemwide_flag], Perms::User, true)
-            .map_err(|e| nest_errors!("Failed to find pinned packages", e))?;
+        if !self.manage_remotes {
+            return Ok(Box::new([]));
+        }
 
-        let pins: HashMap<_, _> = pins
-            .lines()
-            .map(|runtime| runtime.trim())
-            .map(|runtime| (runtime, parse_runtime_format(runtime, false)))
-            .collect();
+        let installed_remotes = get_installed_remotes(systemwide_flag)?;
+        let installed_refs = get_installed_refs(systemwide_flag)?;
+        let installed_refs = parse_refs(&installed_refs);
+        let extra_packages = self.get_extra_packages(systemwide)?;
+        let extra_packages: BTreeSet<_> = extra_packages.iter().map(String::as_str).collect();
 
-        let command_action = if opts.dry_run {
-            dry_run_command
-        } else {
-            run_command
-        };
+        let used_remotes = used_remotes(&installed_refs, &extra_packages, configured_packages);
 
-        if pins.is_empty() {
-            log::info!("No pins to remove");
-            return Ok(());
-        }
+        Ok(self
+            .removable_remotes(configured_remotes, &installed_remotes, &used_remotes)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
 
-        if !opts.no_confirm
-            && !confirmation_prompt(
-                "Do you want to remove the following pins for flatpak?: ",
-                pins.keys(),
-            )?
-        {
-            return Ok(());
+    /// The installed remotes that are neither declared nor used by anything
+    /// installed, or none at all when no remotes list is declared
+    fn removable_remotes<'a>(
+        &self,
+        configured_remotes: &HashMap<String, RemoteOpts>,
+        installed_remotes: &'a HashMap<String, String>,
+        used_remotes: &HashSet<&str>,
+    ) -> BTreeSet<&'a str> {
+        if !self.manage_remotes {
+            return BTreeSet::new();
         }
 
-        pins.iter()
-            .filter(|(_, (runtime, _))| !configured_pins.contains_key(*runtime))
-            .try_for_each(|(pin, _)| {
-                command_action(
-                    ["flatpak", "pin", "--remove", systemwide_flag, pin],
-                    Perms::User,
-                )
-            })
-            .inspect(|_| log::info!("Removed extra flatpak pins"))
-            .map_err(|e| nest_errors!("Failed to remove pinned packages", e))
+        installed_remotes
+            .keys()
+            .map(String::as_str)
+            .filter(|remote| !configured_remotes.contains_key(*remote))
+            .filter(|remote| !used_remotes.contains(remote))
+            .collect()
     }
 
-    fn remove_packages(&self, systemwide: bool, opts: &CleanCommand) -> Result<()> {
+    fn get_extra_pins(&self, systemwide: bool) -> Result<Box<[String]>> {
+        let (systemwide_flag, configured_pins) = if systemwide {
+            ("--system", &self.system_pinned)
+        } else {
+            ("--user", &self.user_pinned)
+        };
+
+        let pins = get_installed_pins(systemwide_flag)?;
+
+        Ok(extra_pins(configured_pins, &pins, systemwide)
+            .iter()
+            .map(|pin| (*pin).to_owned())
+            .collect())
+    }
+
+    fn get_extra_packages(&self, systemwide: bool) -> Result<Box<[String]>> {
         let (systemwide_flag, configured_packages) = if systemwide {
             ("--system", &self.system_packages)
         } else {
             ("--user", &self.user_packages)
         };
 
-        let installed_package = run_command_for_stdout(
-            [
-                "flatpak",
-                "list",
-                systemwide_flag,
-                "--app",
-                "--columns=application",
-            ],
-            Perms::User,
-            false,
-        )
-        .map_err(|e| nest_errors!("Failed to find installed packages", e))?;
+        let installed_packages = get_installed_apps(systemwide_flag)?;
 
-        let mut extra_packages = installed_package
+        let extra_packages = installed_packages
             .lines()
             .filter(|package| !configured_packages.contains_key(*package))
-            .peekable();
+            .map(ToOwned::to_owned)
+            .collect();
 
-        let command_action = if opts.dry_run {
-            dry_run_command
-        } else {
-            run_command
-        };
+        Ok(extra_packages)
+    }
+}
 
-        if extra_packages.peek().is_none() {
-            log::info!("No extra packages to remove");
-            Ok(())
-        } else {
-            command_action(
-                ["flatpak", "remove", systemwide_flag, "--delete-data"]
-                    .into_iter()
-                    .chain(extra_packages),
-                Perms::User,
-            )
-            .inspect(|_| log::info!("Successfully removed extra flatpak packages"))
-            .map_err(|e| nest_errors!("Failed to remove extra packages", e))
-        }
+fn values_to_remotes(
+    remotes: &[Value],
+    default_systemwide: bool,
+) -> Result<(HashMap<String, RemoteOpts>, HashMap<String, RemoteOpts>)> {
+    let remotes: Box<[_]> = remotes
+        .iter()
+        .map(|remote| value_to_remote(remote, default_systemwide))
+        .collect::<Result<_>>()?;
+
+    Ok(remotes.into_iter().partition(|remote| !remote.1.systemwide))
+}
+
+fn get_installed_refs(systemwide_flag: &str) -> Result<String> {
+    run_command_for_stdout(
+        [
+            "flatpak",
+            "list",
+            systemwide_flag,
+            "--columns=application,origin",
+        ],
+        Perms::User,
+        false,
+    )
+    .map_err(|e| nest_errors!("Failed to find installed flatpak packages", e))
+}
+
+fn get_installed_apps(systemwide_flag: &str) -> Result<String> {
+    run_command_for_stdout(
+        [
+            "flatpak",
+            "list",
+            systemwide_flag,
+            "--app",
+            "--columns=application",
+        ],
+        Perms::User,
+        false,
+    )
+    .map_err(|e| nest_errors!("Failed to find installed packages", e))
+}
+
+fn get_installed_pins(systemwide_flag: &str) -> Result<String> {
+    run_command_for_stdout(["flatpak", "pin", systemwide_flag], Perms::User, true)
+        .map_err(|e| nest_errors!("Failed to check for pinned packages", e))
+}
+
+/// Whether one of the installed pins is the declared one
+fn is_pinned(installed_pins: &str, runtime: &str, declared: &PinOpts, systemwide: bool) -> bool {
+    installed_pins.lines().map(str::trim).any(|pin| {
+        let (pinned_runtime, opts) = parse_runtime_format(pin, systemwide);
+        pinned_runtime == runtime && pin_matches(declared, &opts)
+    })
+}
+
+/// The installed pins no declared pin matches. A runtime can be pinned on
+/// several branches at once, so every pin is compared on its own.
+fn extra_pins<'a>(
+    configured_pins: &HashMap<String, PinOpts>,
+    installed_pins: &'a str,
+    systemwide: bool,
+) -> Box<[&'a str]> {
+    installed_pins
+        .lines()
+        .map(str::trim)
+        .filter(|pin| !pin.is_empty())
+        .filter(|pin| {
+            let (runtime, opts) = parse_runtime_format(pin, systemwide);
+            configured_pins
+                .get(runtime)
+                .is_none_or(|declared| !pin_matches(declared, &opts))
+        })
+        .collect()
+}
+
+// An arch or branch left out of the declaration matches any
+fn pin_matches(declared: &PinOpts, installed: &PinOpts) -> bool {
+    [
+        (&declared.arch, &installed.arch),
+        (&declared.branch, &installed.branch),
+    ]
+    .into_iter()
+    .all(|(declared, installed)| declared.is_none() || declared == installed)
+}
+
+/// The remotes that anything kept is installed from or declared to come from.
+/// Removing them would fail, and would stop declared packages from getting
+/// updates.
+fn used_remotes<'a>(
+    installed_refs: &[(&'a str, &'a str)],
+    extra_packages: &BTreeSet<&str>,
+    configured_packages: &'a HashMap<String, FlatpakOpts>,
+) -> HashSet<&'a str> {
+    installed_refs
+        .iter()
+        .filter(|(package, _)| !extra_packages.contains(package))
+        .map(|(_, origin)| *origin)
+        .chain(
+            configured_packages
+                .values()
+                .filter_map(|opts| opts.remote.as_deref()),
+        )
+        .collect()
+}
+
+/// Pairs every installed ref with the remote it was installed from
+fn parse_refs(refs: &str) -> Box<[(&str, &str)]> {
+    refs.lines()
+        .map(|line| {
+            line.split_once(char::is_whitespace)
+                .map(|(package, origin)| (package.trim(), origin.trim()))
+                .unwrap_or((line.trim(), ""))
+        })
+        .collect()
+}
+
+fn get_installed_remotes(systemwide_flag: &str) -> Result<HashMap<String, String>> {
+    let remotes = run_command_for_stdout(
+        ["flatpak", "remotes", systemwide_flag, "--columns=name,url"],
+        Perms::User,
+        false,
+    )
+    .map_err(|e| nest_errors!("Failed to find configured flatpak remotes", e))?;
+
+    let remotes = remotes
+        .lines()
+        .filter_map(|remote| remote.split_once(char::is_whitespace))
+        .map(|(name, url)| (name.trim().to_owned(), url.trim().to_owned()))
+        .collect();
+
+    Ok(remotes)
+}
+
+/// Fails if a declared remote is already present with a different url, since
+/// packages would otherwise be silently pulled from somewhere else
+fn check_remote_urls(
+    configured_remotes: &HashMap<String, RemoteOpts>,
+    installed_remotes: &HashMap<String, String>,
+) -> Result<()> {
+    let mismatches: BTreeSet<_> = configured_remotes
+        .iter()
+        .filter_map(|(remote, opts)| {
+            let installed_url = installed_remotes.get(remote)?;
+            (!same_url(installed_url, &opts.url)).then(|| {
+                format!(
+                    "remote {remote} points to {installed_url} but is declared with {}",
+                    opts.url
+                )
+            })
+        })
+        .collect();
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        let mismatches = mismatches.into_iter().collect::<Box<[_]>>().join("\n");
+        Err(mod_err!("Mismatched flatpak remote urls:\n{mismatches}"))
     }
 }
 
-fn values_to_remotes(remotes: &[Value]) -> HashMap<String, String> {
-    remotes.iter().flat_map(extract_remote).collect()
+fn same_url(installed: &str, declared: &str) -> bool {
+    // A .flatpakrepo file only names the repository url inside of it, so
+    // there is nothing to compare against without fetching it
+    declared.ends_with(".flatpakrepo")
+        || installed.trim_end_matches('/') == declared.trim_end_matches('/')
+}
+
+fn scoped_name(name: &str, systemwide: bool) -> String {
+    let scope = if systemwide { "system" } else { "user" };
+    [scope, name].join("/")
+}
+
+fn pin_pattern(pin: &str, opts: &PinOpts) -> String {
+    [Some(pin), opts.arch.as_deref(), opts.branch.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect::<Box<[_]>>()
+        .join("/")
 }
 
 fn values_to_pins(
@@ -579,39 +842,56 @@ fn parse_runtime_format(runtime_string: &str, systemwide: bool) -> (&str, PinOpt
     )
 }
 
-fn extract_remote(remote: &Value) -> Option<(String, String)> {
-    let record = remote.as_record().ok().or_else(|| {
-        log::warn!("remote value was not a record, ignoring");
-        None
-    })?;
+fn value_to_remote(remote: &Value, default_systemwide: bool) -> Result<(String, RemoteOpts)> {
+    let record = remote
+        .as_record()
+        .map_err(|e| nest_errors!("remote value was not a record", e))?;
 
     let name = record
         .get(PACKAGE_KEY)
-        .or_else(|| {
-            log::warn!("remote name was not found, ignoring");
-            None
-        })?
+        .ok_or_else(|| mod_err!("remote name was not found"))?
         .as_str()
-        .ok()
-        .or_else(|| {
-            log::warn!("remote name was not a string, ignoring");
-            None
-        })?;
+        .map_err(|e| nest_errors!("remote name was not a string", e))?;
 
     let url = record
         .get(URL_KEY)
-        .or_else(|| {
-            log::warn!("remote url was not found, ignoring");
-            None
-        })?
+        .ok_or_else(|| mod_err!("remote url was not found for {name}"))?
         .as_str()
-        .ok()
-        .or_else(|| {
-            log::warn!("remote url was not a string, ignoring");
-            None
+        .map_err(|e| nest_errors!("remote url was not a string for {name}", e))?;
+
+    let systemwide = record
+        .get(SYSTEMWIDE_KEY)
+        .map(|val| {
+            val.as_bool()
+                .map_err(|e| nest_errors!("systemwide for {name} not a boolean", e))
+        })
+        .unwrap_or_else(|| {
+            log::debug!("systemwide not specified for {name}, using config default");
+            Ok(default_systemwide)
         })?;
 
-    Some((name.to_owned(), url.to_owned()))
+    Ok((
+        name.to_owned(),
+        RemoteOpts {
+            url: url.to_owned(),
+            systemwide,
+        },
+    ))
+}
+
+fn get_default_systemwide(config: &Record) -> Result<bool> {
+    match config.get(FLATPAK_DEFAULT_SYSTEMWIDE_KEY) {
+        Some(val) => val.as_bool().map_err(|e| {
+            nest_errors!(
+                "value for {FLATPAK_DEFAULT_SYSTEMWIDE_KEY} not a boolean",
+                e
+            )
+        }),
+        None => {
+            log::info!("Value not specified in config, using default false");
+            Ok(DEFAULT_FLATPAK_SYSTEMWIDE)
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1294,18 +1574,20 @@ mod test {
         .unwrap();
         let value = Value::record(value, Span::test_data());
 
-        let res = extract_remote(&value);
-        let check = Some(("a".to_owned(), "b".to_owned()));
+        let res = value_to_remote(&value, false);
+        assert!(res.is_ok());
 
-        assert_eq!(check, res);
+        let (name, opts) = res.unwrap();
+        assert_eq!(name, "a");
+        assert_eq!(opts.url, "b");
+        assert!(!opts.systemwide);
     }
 
     #[test]
     fn value_to_remote_not_records() {
         let value = Value::string("a", Span::test_data());
-        let res = extract_remote(&value);
-        let check = None;
-        assert_eq!(check, res);
+        let res = value_to_remote(&value, false);
+        assert!(res.is_err());
     }
 
     #[test]
@@ -1322,8 +1604,170 @@ mod test {
         .unwrap();
         let value = Value::record(value, Span::test_data());
 
-        let res = extract_remote(&value);
-        let check = None;
-        assert_eq!(check, res);
+        let res = value_to_remote(&value, false);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn check_remote_urls_mismatch() {
+        let configured = HashMap::from([
+            (
+                "a".to_owned(),
+                RemoteOpts {
+                    url: "https://a.org/repo".to_owned(),
+                    systemwide: false,
+                },
+            ),
+            (
+                "b".to_owned(),
+                RemoteOpts {
+                    url: "https://b.org/repo".to_owned(),
+                    systemwide: false,
+                },
+            ),
+        ]);
+        let installed = HashMap::from([
+            ("a".to_owned(), "https://a.org/repo/".to_owned()),
+            ("b".to_owned(), "https://c.org/repo/".to_owned()),
+        ]);
+
+        let res = check_remote_urls(&configured, &installed);
+        assert!(res.is_err());
+        let message = res.unwrap_err().to_string();
+        assert!(message.contains("remote b points to https://c.org/repo/"));
+        assert!(!message.contains("remote a"));
+    }
+
+    #[test]
+    fn check_remote_urls_flatpakrepo() {
+        let configured = HashMap::from([(
+            "flathub".to_owned(),
+            RemoteOpts {
+                url: "https://dl.flathub.org/repo/flathub.flatpakrepo".to_owned(),
+                systemwide: false,
+            },
+        )]);
+        let installed = HashMap::from([(
+            "flathub".to_owned(),
+            "https://dl.flathub.org/repo/".to_owned(),
+        )]);
+
+        assert!(check_remote_urls(&configured, &installed).is_ok());
+    }
+
+    #[test]
+    fn new_malformed_remote() {
+        let remote = Record::from_raw_cols_vals(
+            ["package"].into_iter().map(ToOwned::to_owned).collect(),
+            vec![Value::test_string("flathub")],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let value = Record::from_raw_cols_vals(
+            ["packages", "remotes"]
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+            vec![
+                Value::test_list(vec![]),
+                Value::test_list(vec![Value::test_record(remote)]),
+            ],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let res = Flatpak::new(&value, &Record::new());
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn removable_remotes_undeclared() {
+        let value = Record::from_raw_cols_vals(
+            ["packages"].into_iter().map(ToOwned::to_owned).collect(),
+            vec![Value::test_list(vec![])],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+        let flatpak = Flatpak::new(&value, &Record::new()).unwrap();
+        let installed = HashMap::from([(
+            "flathub".to_owned(),
+            "https://dl.flathub.org/repo/".to_owned(),
+        )]);
+
+        let res = flatpak.removable_remotes(&HashMap::new(), &installed, &HashSet::new());
+        assert!(res.is_empty());
+        assert!(flatpak.get_extra_remotes(false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn removable_remotes_used() {
+        let value = Record::from_raw_cols_vals(
+            ["packages", "remotes"]
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+            vec![Value::test_list(vec![]), Value::test_list(vec![])],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+        let flatpak = Flatpak::new(&value, &Record::new()).unwrap();
+        let installed = HashMap::from([
+            (
+                "flathub".to_owned(),
+                "https://dl.flathub.org/repo/".to_owned(),
+            ),
+            ("old".to_owned(), "https://old.org/repo/".to_owned()),
+        ]);
+
+        let res =
+            flatpak.removable_remotes(&HashMap::new(), &installed, &HashSet::from(["flathub"]));
+        assert_eq!(res, BTreeSet::from(["old"]));
+    }
+
+    #[test]
+    fn extra_pins_per_branch() {
+        let configured = HashMap::from([(
+            "org.gnome.Platform".to_owned(),
+            PinOpts {
+                branch: Some("46".to_owned()),
+                arch: None,
+                systemwide: false,
+                post_hook: None,
+            },
+        )]);
+        let installed = concat!(
+            "runtime/org.gnome.Platform/x86_64/45\n",
+            "runtime/org.gnome.Platform/x86_64/46\n",
+            "runtime/org.kde.Platform/x86_64/6.6\n",
+        );
+
+        assert_eq!(
+            *extra_pins(&configured, installed, false),
+            [
+                "runtime/org.gnome.Platform/x86_64/45",
+                "runtime/org.kde.Platform/x86_64/6.6"
+            ]
+        );
+        assert!(is_pinned(
+            installed,
+            "org.gnome.Platform",
+            &configured["org.gnome.Platform"],
+            false
+        ));
+    }
+
+    #[test]
+    fn parse_refs_origins() {
+        let refs = parse_refs("org.gnome.Platform\tflathub\norg.local.App\n");
+
+        assert_eq!(
+            *refs,
+            [("org.gnome.Platform", "flathub"), ("org.local.App", "")]
+        );
     }
 }
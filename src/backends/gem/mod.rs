@@ -0,0 +1,469 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const VERSION_KEY: &str = "version";
+const HOOK_KEY: &str = "post_hook";
+
+#[derive(Clone, Debug)]
+pub struct GemOpts {
+    version: Option<String>,
+    post_hook: Option<Closure>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Gem {
+    packages: HashMap<String, GemOpts>,
+}
+
+impl Backend for Gem {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for Gem"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The package list in Gem is not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed gems");
+        Ok(Gem { packages })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed = get_installed_gems()?;
+        let dependencies = get_dependencies()?;
+
+        Ok(self
+            .get_extra_gems(&installed, &dependencies)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, selection: Selection) -> Result<Plan> {
+        let installed = get_installed_gems()?;
+
+        let mut plan = Plan::default();
+
+        let (versioned, unversioned): (BTreeMap<_, _>, BTreeMap<_, _>) = self
+            .packages
+            .iter()
+            .filter(|(gem, spec)| needs_install(installed.get(*gem), spec))
+            .partition(|(_, spec)| spec.version.is_some());
+
+        plan.push(
+            Action::Install,
+            unversioned.keys().map(|gem| gem.as_str()),
+            ["gem", "install", "--user-install"]
+                .into_iter()
+                .chain(unversioned.keys().map(|gem| gem.as_str())),
+            Perms::User,
+        );
+        unversioned
+            .values()
+            .for_each(|spec| plan.push_hook(spec.post_hook.as_ref()));
+
+        // `--version` applies to every gem on the command line
+        versioned.into_iter().for_each(|(gem, spec)| {
+            plan.push(
+                Action::Install,
+                [gem],
+                ["gem", "install", "--user-install", gem, "--version"]
+                    .into_iter()
+                    .chain(spec.version.as_deref()),
+                Perms::User,
+            );
+            plan.push_hook(spec.post_hook.as_ref());
+        });
+
+        // The dependencies of the declared gems only matter for removals
+        if !selection.removals() {
+            return Ok(plan);
+        }
+
+        let dependencies = get_dependencies()?;
+        let extra = self.get_extra_gems(&installed, &dependencies);
+
+        // Dependencies of declared gems are never extra, so the dependency
+        // check would only prompt for gems that are being removed together
+        plan.push(
+            Action::Remove,
+            extra.iter().copied(),
+            [
+                "gem",
+                "uninstall",
+                "-x",
+                "--all",
+                "--ignore-dependencies",
+                "--user-install",
+            ]
+            .into_iter()
+            .chain(extra.iter().copied()),
+            Perms::User,
+        );
+
+        Ok(plan)
+    }
+
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let installed = get_installed_gems()?;
+
+        // Pinned versions are left alone, updating them would only undo the pin
+        let installed_gems: BTreeSet<_> = self
+            .packages
+            .iter()
+            .filter(|(gem, spec)| installed.contains_key(*gem) && !is_exact(spec))
+            .map(|(gem, _)| gem.as_str())
+            .collect();
+
+        if installed_gems.is_empty() {
+            log::info!("No installed gems to update");
+            return Ok(());
+        }
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to update the following gems?: ",
+                &installed_gems,
+            )?
+        {
+            return Ok(());
+        }
+
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        command_action(
+            ["gem", "update", "--user-install"]
+                .into_iter()
+                .chain(installed_gems),
+            Perms::User,
+        )
+        .inspect(|_| log::info!("Successfully updated gems"))
+        .map_err(|e| nest_errors!("Failed to update gems", e))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Gem",
+            &[PACKAGE_KEY, VERSION_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "Gem")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("gem".to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to clean gem cache?",
+                ["Removing old versions of installed gems"],
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(["gem", "cleanup"], Perms::User)
+            .inspect(|_| log::info!("Removed old gem versions"))
+            .map_err(|e| nest_errors!("Failed to clean up gems", e))
+    }
+}
+
+impl Gem {
+    // gem doesn't tell apart gems installed on request from their
+    // dependencies, so everything the declared gems pull in is kept
+    fn get_extra_gems<'a>(
+        &self,
+        installed: &'a BTreeMap<String, Vec<String>>,
+        dependencies: &HashMap<String, Vec<String>>,
+    ) -> BTreeSet<&'a str> {
+        let mut required: HashSet<&str> = HashSet::new();
+        let mut queue: Vec<&str> = self.packages.keys().map(String::as_str).collect();
+
+        while let Some(gem) = queue.pop() {
+            if required.insert(gem) {
+                queue.extend(
+                    dependencies
+                        .get(gem)
+                        .into_iter()
+                        .flatten()
+                        .map(String::as_str),
+                );
+            }
+        }
+
+        installed
+            .keys()
+            .map(String::as_str)
+            .filter(|gem| !required.contains(gem))
+            .collect()
+    }
+}
+
+fn needs_install(installed: Option<&Vec<String>>, spec: &GemOpts) -> bool {
+    match (installed, &spec.version) {
+        (None, _) => true,
+        (Some(installed), Some(version)) if is_exact(spec) => !installed.contains(version),
+        (Some(_), _) => false,
+    }
+}
+
+// Constraints such as `~> 3.0` are satisfied by whatever is installed
+fn is_exact(spec: &GemOpts) -> bool {
+    spec.version
+        .as_deref()
+        .is_some_and(|version| version.chars().all(|c| c.is_ascii_digit() || c == '.'))
+}
+
+/// Maps every installed gem to its versions that don't ship with ruby
+fn get_installed_gems() -> Result<BTreeMap<String, Vec<String>>> {
+    let user_dir = run_command_for_stdout(["ruby", "-e", "print Gem.user_dir"], Perms::User, true)
+        .map_err(|e| nest_errors!("Failed to find the user gem directory", e))?;
+
+    let gems = run_command_for_stdout(
+        user_gem_command(user_dir.trim(), &["list", "--local"]),
+        Perms::User,
+        true,
+    )
+    .map_err(|e| nest_errors!("Failed to get installed gems", e))?;
+
+    Ok(parse_gem_list(&gems))
+}
+
+/// Runs gem with only the user gem directory on its path, since the gems
+/// bundled with ruby or packaged by the distribution can't be removed with
+/// --user-install
+fn user_gem_command(user_dir: &str, args: &[&str]) -> Box<[String]> {
+    [
+        "env".to_owned(),
+        format!("GEM_HOME={user_dir}"),
+        format!("GEM_PATH={user_dir}"),
+        "gem".to_owned(),
+    ]
+    .into_iter()
+    .chain(args.iter().map(|arg| (*arg).to_owned()))
+    .collect()
+}
+
+// Lines look like `rake (13.1.0, default: 13.0.6)`. Default gems come with
+// ruby itself and can't be uninstalled, so only the other versions are kept
+fn parse_gem_list(gems: &str) -> BTreeMap<String, Vec<String>> {
+    gems.lines()
+        .filter_map(|line| {
+            let (gem, versions) = line.trim().split_once(" (")?;
+
+            let versions: Vec<_> = versions
+                .trim_end_matches(')')
+                .split(", ")
+                .filter(|version| !version.starts_with("default: "))
+                .filter_map(|version| version.split_whitespace().next())
+                .map(ToOwned::to_owned)
+                .collect();
+
+            (!versions.is_empty()).then(|| (gem.to_owned(), versions))
+        })
+        .collect()
+}
+
+fn get_dependencies() -> Result<HashMap<String, Vec<String>>> {
+    let dependencies = run_command_for_stdout(["gem", "dependency", "--local"], Perms::User, true)
+        .map_err(|e| nest_errors!("Failed to get gem dependencies", e))?;
+
+    Ok(parse_dependencies(&dependencies))
+}
+
+// Every installed version starts a `Gem name-version` block, followed by
+// indented `dep (requirement)` lines. Development dependencies are never
+// installed along with the gem, so they are skipped.
+fn parse_dependencies(dependencies: &str) -> HashMap<String, Vec<String>> {
+    let mut gems: HashMap<String, Vec<String>> = HashMap::new();
+    let mut current = None;
+
+    for line in dependencies.lines() {
+        if let Some(gem) = line.strip_prefix("Gem ") {
+            current = Some(gem_name(gem).to_owned());
+            continue;
+        }
+
+        let Some(gem) = &current else { continue };
+        let Some((dependency, requirement)) = line.trim().split_once(" (") else {
+            continue;
+        };
+
+        if !requirement.contains("development") {
+            gems.entry(gem.clone())
+                .or_default()
+                .push(dependency.to_owned());
+        }
+    }
+
+    gems
+}
+
+// The version starts after the first dash followed by a digit, e.g.
+// `nokogiri-1.16.2-x86_64-linux`
+fn gem_name(gem: &str) -> &str {
+    gem.char_indices()
+        .find(|&(index, c)| {
+            c == '-'
+                && gem[index + 1..]
+                    .chars()
+                    .next()
+                    .is_some_and(|next| next.is_ascii_digit())
+        })
+        .map_or(gem, |(index, _)| &gem[..index])
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, GemOpts)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The package-spec is not a record", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No gem mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The gem was not a string", e))?
+        .to_owned();
+
+    let version = match record.get(VERSION_KEY) {
+        Some(version) => Some(
+            version
+                .as_str()
+                .map_err(|e| nest_errors!("Version for {package} is not a string", e))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {package} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((package, GemOpts { version, post_hook }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_gem_list_skips_defaults() {
+        let gems = concat!(
+            "bigdecimal (default: 3.1.4)\n",
+            "nokogiri (1.16.2 x86_64-linux, 1.15.0 x86_64-linux)\n",
+            "rake (13.1.0, default: 13.0.6)\n",
+        );
+
+        let installed = parse_gem_list(gems);
+        assert!(!installed.contains_key("bigdecimal"));
+        assert_eq!(installed["nokogiri"], ["1.16.2", "1.15.0"]);
+        assert_eq!(installed["rake"], ["13.1.0"]);
+    }
+
+    #[test]
+    fn user_gem_command_hides_system_gems() {
+        let user_dir = "/home/user/.local/share/gem/ruby/3.2.0";
+
+        assert_eq!(
+            *user_gem_command(user_dir, &["list", "--local"]),
+            [
+                "env",
+                "GEM_HOME=/home/user/.local/share/gem/ruby/3.2.0",
+                "GEM_PATH=/home/user/.local/share/gem/ruby/3.2.0",
+                "gem",
+                "list",
+                "--local",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_dependencies_runtime_only() {
+        let dependencies = concat!(
+            "Gem nokogiri-1.16.2-x86_64-linux\n",
+            "  racc (~> 1.4)\n",
+            "  rake (>= 0, development)\n",
+            "\n",
+            "Gem racc-1.7.3\n",
+            "\n",
+        );
+
+        let dependencies = parse_dependencies(dependencies);
+        assert_eq!(dependencies["nokogiri"], ["racc"]);
+        assert!(!dependencies.contains_key("racc"));
+    }
+
+    #[test]
+    fn extra_gems_keep_dependencies() {
+        let gem = Gem {
+            packages: HashMap::from([(
+                "nokogiri".to_owned(),
+                GemOpts {
+                    version: Some("~> 1.16".to_owned()),
+                    post_hook: None,
+                },
+            )]),
+        };
+        let installed = parse_gem_list("nokogiri (1.16.2)\nracc (1.7.3)\nrubocop (1.60.0)\n");
+        let dependencies = HashMap::from([("nokogiri".to_owned(), vec!["racc".to_owned()])]);
+
+        assert_eq!(
+            gem.get_extra_gems(&installed, &dependencies)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["rubocop"]
+        );
+        assert!(!needs_install(
+            installed.get("nokogiri"),
+            &gem.packages["nokogiri"]
+        ));
+    }
+}
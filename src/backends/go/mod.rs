@@ -0,0 +1,402 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const VERSION_KEY: &str = "version";
+const HOOK_KEY: &str = "post_hook";
+
+const DEFAULT_VERSION: &str = "latest";
+
+#[derive(Clone, Debug)]
+pub struct GoOpts {
+    version: String,
+    post_hook: Option<Closure>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Binary {
+    file: String,
+    version: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Go {
+    packages: HashMap<String, GoOpts>,
+}
+
+impl Backend for Go {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for Go"))?
+            .as_list()
+            .map_err(|e| nest_errors!("Packages not a list for Go", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Parsed go packages from spec");
+
+        Ok(Go { packages })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed = get_installed_packages()?;
+
+        Ok(self
+            .get_extra_packages(&installed)
+            .into_keys()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let installed = get_installed_packages()?;
+
+        let mut plan = Plan::default();
+
+        self.packages
+            .iter()
+            .filter(|(package, spec)| needs_install(installed.get(*package), spec))
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .for_each(|(package, spec)| {
+                plan.push(
+                    Action::Install,
+                    [package],
+                    ["go", "install", &install_target(package, spec)],
+                    Perms::User,
+                );
+                plan.push_hook(spec.post_hook.as_ref());
+            });
+
+        self.get_extra_packages(&installed)
+            .into_iter()
+            .for_each(|(package, binary)| {
+                plan.push(
+                    Action::Remove,
+                    [package],
+                    ["rm", binary.file.as_str()],
+                    Perms::User,
+                );
+            });
+
+        Ok(plan)
+    }
+
+    // Reinstalling a package at a moving version such as `latest` picks up
+    // the newest release, while exact versions are left as they are
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let installed = get_installed_packages()?;
+
+        let installed_packages: BTreeSet<_> = self
+            .packages
+            .iter()
+            .filter(|(package, spec)| installed.contains_key(*package) && !is_exact(spec))
+            .map(|(package, _)| package.as_str())
+            .collect();
+
+        if installed_packages.is_empty() {
+            log::info!("No installed packages to update");
+            return Ok(());
+        }
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to update the following packages for go?: ",
+                &installed_packages,
+            )?
+        {
+            return Ok(());
+        }
+
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        installed_packages
+            .into_iter()
+            .try_for_each(|package| {
+                let target = install_target(package, &self.packages[package]);
+
+                command_action(["go".to_owned(), "install".to_owned(), target], Perms::User)
+                    .map_err(|e| nest_errors!("Failed to update {package}", e))
+            })
+            .inspect(|_| log::info!("Successfully updated go packages"))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Go",
+            &[PACKAGE_KEY, VERSION_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "Go")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("go".to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to clean go cache?",
+                ["Removing the build cache and the module cache"],
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(["go", "clean", "-cache", "-modcache"], Perms::User)
+            .inspect(|_| log::info!("Removed go's cache"))
+            .map_err(|e| nest_errors!("Failed to remove cache", e))
+    }
+}
+
+impl Go {
+    fn get_extra_packages<'a>(
+        &self,
+        installed: &'a BTreeMap<String, Binary>,
+    ) -> BTreeMap<&'a str, &'a Binary> {
+        installed
+            .iter()
+            .filter(|(package, _)| !self.packages.contains_key(*package))
+            .map(|(package, binary)| (package.as_str(), binary))
+            .collect()
+    }
+}
+
+// Every binary built by `go install` embeds the package it was built from,
+// so the bin directory itself is the record of what is installed
+fn get_installed_packages() -> Result<BTreeMap<String, Binary>> {
+    let bin = get_bin_path()?;
+
+    if !Path::new(&bin).is_dir() {
+        log::warn!("{bin} does not exist. Assuming packages are not installed.");
+        return Ok(BTreeMap::new());
+    }
+
+    let build_info =
+        run_command_for_stdout(["go", "version", "-m", bin.as_str()], Perms::User, true)
+            .map_err(|e| nest_errors!("Failed to read build info of go binaries", e))?;
+
+    Ok(parse_build_info(&build_info))
+}
+
+// `go version -m` prints `file: goversion` for every binary, followed by
+// tab indented `path <package>` and `mod <module> <version> <sum>` lines
+fn parse_build_info(build_info: &str) -> BTreeMap<String, Binary> {
+    let mut installed = BTreeMap::new();
+    let mut file = None;
+    let mut package = None;
+
+    for line in build_info.lines() {
+        let mut fields = line.split('\t').skip(1);
+
+        if !line.starts_with('\t') {
+            file = line.rsplit_once(": ").map(|(path, _)| path.to_owned());
+            package = None;
+            continue;
+        }
+
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("path"), Some(path), _) => package = Some(path.to_owned()),
+            (Some("mod"), Some(_), Some(version)) => {
+                if let (Some(file), Some(package)) = (file.take(), package.take()) {
+                    installed.insert(
+                        package,
+                        Binary {
+                            file,
+                            version: version.to_owned(),
+                        },
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    installed
+}
+
+fn needs_install(installed: Option<&Binary>, spec: &GoOpts) -> bool {
+    match installed {
+        None => true,
+        Some(binary) => is_exact(spec) && binary.version != spec.version,
+    }
+}
+
+// Queries like `latest`, branches and commit hashes can't be compared with
+// the version recorded in the binary
+fn is_exact(spec: &GoOpts) -> bool {
+    spec.version.starts_with('v') && spec.version.contains('.')
+}
+
+fn install_target(package: &str, spec: &GoOpts) -> String {
+    [package, "@", spec.version.as_str()].concat()
+}
+
+fn get_bin_path() -> Result<String> {
+    let env = run_command_for_stdout(["go", "env", "GOBIN", "GOPATH"], Perms::User, true)
+        .map_err(|e| nest_errors!("Failed to get go environment", e))?;
+
+    let mut env = env.lines();
+    let gobin = env.next().unwrap_or_default();
+    let gopath = env.next().unwrap_or_default();
+
+    if !gobin.is_empty() {
+        return Ok(gobin.to_owned());
+    }
+
+    // go installs into the first entry of GOPATH
+    let gopath = gopath.split(':').next().unwrap_or_default();
+
+    if gopath.is_empty() {
+        Err(mod_err!("Neither GOBIN nor GOPATH is set"))
+    } else {
+        Ok(gopath.to_owned() + "/bin")
+    }
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, GoOpts)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("Failed to parse value", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No package mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("Package name in record is not a string", e))?
+        .to_owned();
+
+    let version = match record.get(VERSION_KEY) {
+        Some(version) => version
+            .as_str()
+            .map_err(|e| nest_errors!("version for {package} is not a string", e))?
+            .to_owned(),
+        None => {
+            log::debug!("version not specified in {package}, defaulting to {DEFAULT_VERSION}");
+            DEFAULT_VERSION.to_owned()
+        }
+    };
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(closure) => {
+            let closure = closure
+                .as_closure()
+                .map_err(|e| nest_errors!("closure for {package} not a closure", e))?;
+
+            Some(closure.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((package, GoOpts { version, post_hook }))
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    #[test]
+    fn value_to_pkgspec_default_version() {
+        let record = Record::from_raw_cols_vals(
+            vec!["package".to_owned()],
+            vec![Value::string("golang.org/x/tools/gopls", Span::test_data())],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let (package, spec) = value_to_pkgspec(&Value::record(record, Span::test_data())).unwrap();
+        assert_eq!(
+            install_target(&package, &spec),
+            "golang.org/x/tools/gopls@latest"
+        );
+        assert!(!is_exact(&spec));
+    }
+
+    #[test]
+    fn parse_build_info_binaries() {
+        let build_info = concat!(
+            "/home/user/go/bin/gopls: go1.22.0\n",
+            "\tpath\tgolang.org/x/tools/gopls\n",
+            "\tmod\tgolang.org/x/tools/gopls\tv0.15.2\th1:abc=\n",
+            "\tdep\tgolang.org/x/mod\tv0.15.0\th1:def=\n",
+            "\tbuild\t-compiler=gc\n",
+            "/home/user/go/bin/dlv: go1.22.0\n",
+            "\tpath\tgithub.com/go-delve/delve/cmd/dlv\n",
+            "\tmod\tgithub.com/go-delve/delve\tv1.22.1\th1:ghi=\n",
+        );
+
+        let installed = parse_build_info(build_info);
+        assert_eq!(installed.len(), 2);
+        assert_eq!(
+            installed["golang.org/x/tools/gopls"],
+            Binary {
+                file: "/home/user/go/bin/gopls".to_owned(),
+                version: "v0.15.2".to_owned(),
+            }
+        );
+        assert_eq!(
+            installed["github.com/go-delve/delve/cmd/dlv"].version,
+            "v1.22.1"
+        );
+    }
+
+    #[test]
+    fn needs_install_versions() {
+        let spec = |version: &str| GoOpts {
+            version: version.to_owned(),
+            post_hook: None,
+        };
+        let binary = Binary {
+            file: "/home/user/go/bin/gopls".to_owned(),
+            version: "v0.15.2".to_owned(),
+        };
+
+        assert!(needs_install(None, &spec("latest")));
+        assert!(!needs_install(Some(&binary), &spec("latest")));
+        assert!(!needs_install(Some(&binary), &spec("v0.15.2")));
+        assert!(needs_install(Some(&binary), &spec("v0.14.0")));
+    }
+}
@@ -1,50 +1,208 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
 use anyhow::Result;
+pub use appimage::AppImage;
+pub use apt::Apt;
 pub use arch::Arch;
+pub use brew::Brew;
 pub use cargo::Cargo;
+pub use custom::Custom;
+pub use dnf::Dnf;
+pub use files::Files;
 pub use flatpak::Flatpak;
+pub use gem::Gem;
+pub use go::Go;
+pub use nix::Nix;
+pub use npm::Npm;
 use nu_protocol::Record;
+pub use opam::Opam;
+pub use plugin::{
+    PLUGIN_PREFIX, Plugin, describe_plugin, find_plugin, find_plugins, parse_plugins,
+    validate_plugin,
+};
 pub use rustup::Rustup;
+pub use snap::Snap;
+pub use uv::Uv;
+pub use vscode::VsCode;
 
-use crate::{CleanCacheCommand, CleanCommand, SyncCommand, parser::Engine};
+use crate::{
+    CleanCacheCommand, UpdateCommand,
+    parser::Engine,
+    plan::{Plan, Selection},
+    validate::Diagnostic,
+};
 
+mod appimage;
+mod apt;
 mod arch;
+mod brew;
 mod cargo;
+mod custom;
+mod dnf;
+mod files;
 mod flatpak;
+mod gem;
+mod go;
+mod nix;
+mod npm;
+mod opam;
+mod plugin;
 mod rustup;
+mod snap;
+mod uv;
+mod vscode;
 
+// Only a handful of these are ever alive at once, so boxing is not worth it
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum Backends {
     Arch(Arch),
     Flatpak(Flatpak),
     Cargo(Cargo),
     Rustup(Rustup),
+    Uv(Uv),
+    Npm(Npm),
+    Opam(Opam),
+    Dnf(Dnf),
+    Apt(Apt),
+    Go(Go),
+    Snap(Snap),
+    Brew(Brew),
+    Nix(Nix),
+    Gem(Gem),
+    VsCode(VsCode),
+    AppImage(AppImage),
+    Custom(Custom),
+    Files(Files),
+    /// Declared under a name that is not built in, see [`find_plugin`]
+    Plugin(Plugin),
 }
 
 pub trait Backend {
     fn clean_cache(&self, config: &Record, opts: &CleanCacheCommand) -> Result<()>;
-    fn install(&self, engine: &mut Engine, opts: &SyncCommand) -> Result<()>;
+    fn executable(config: &Record) -> Result<String>
+    where
+        Self: Sized;
     fn new(value: &Record, config: &Record) -> Result<Self>
     where
         Self: Sized;
-    fn remove(&self, opts: &CleanCommand) -> Result<()>;
+    fn package_count(&self) -> usize;
+    fn plan(&self, no_confirm: bool, selection: Selection) -> Result<Plan>;
+    fn settings(config: &Record) -> Result<Box<[(&'static str, String)]>>
+    where
+        Self: Sized;
+    fn unmanaged(&self) -> Result<Box<[String]>>;
+    fn update(&self, opts: &UpdateCommand) -> Result<()>;
+    fn validate(value: &Record, config: &Record) -> Vec<Diagnostic>
+    where
+        Self: Sized;
 }
 
 impl Backends {
-    pub fn install(&mut self, engine: &mut Engine, opts: &SyncCommand) -> Result<()> {
+    pub fn name(&self) -> &str {
         match self {
-            Backends::Arch(arch) => arch.install(engine, opts),
-            Backends::Flatpak(flatpak) => flatpak.install(engine, opts),
-            Backends::Cargo(cargo) => cargo.install(engine, opts),
-            Backends::Rustup(rustup) => rustup.install(engine, opts),
+            Backends::Arch(_) => "Arch",
+            Backends::Flatpak(_) => "Flatpak",
+            Backends::Cargo(_) => "Cargo",
+            Backends::Rustup(_) => "Rustup",
+            Backends::Uv(_) => "Uv",
+            Backends::Npm(_) => "Npm",
+            Backends::Opam(_) => "Opam",
+            Backends::Dnf(_) => "Dnf",
+            Backends::Apt(_) => "Apt",
+            Backends::Go(_) => "Go",
+            Backends::Snap(_) => "Snap",
+            Backends::Brew(_) => "Brew",
+            Backends::Nix(_) => "Nix",
+            Backends::Gem(_) => "Gem",
+            Backends::VsCode(_) => "VsCode",
+            Backends::AppImage(_) => "AppImage",
+            Backends::Custom(_) => "Custom",
+            Backends::Files(_) => "Files",
+            Backends::Plugin(plugin) => plugin.name(),
         }
     }
 
-    pub fn remove(&mut self, opts: &CleanCommand) -> Result<()> {
+    /// Hands the engine that parsed the package spec to the backends that
+    /// run closures from it or read files next to it
+    pub fn set_engine(&mut self, engine: &Engine) {
         match self {
-            Backends::Arch(arch) => arch.remove(opts),
-            Backends::Flatpak(flatpak) => flatpak.remove(opts),
-            Backends::Cargo(cargo) => cargo.remove(opts),
-            Backends::Rustup(rustup) => rustup.remove(opts),
+            Backends::Custom(custom) => custom.set_engine(engine),
+            Backends::Files(files) => files.set_config_dir(engine.config_dir()),
+            Backends::Uv(uv) => uv.set_config_dir(engine.config_dir()),
+            _ => {}
+        }
+    }
+
+    pub fn plan(&self, no_confirm: bool, selection: Selection) -> Result<Plan> {
+        match self {
+            Backends::Arch(arch) => arch.plan(no_confirm, selection),
+            Backends::Flatpak(flatpak) => flatpak.plan(no_confirm, selection),
+            Backends::Cargo(cargo) => cargo.plan(no_confirm, selection),
+            Backends::Rustup(rustup) => rustup.plan(no_confirm, selection),
+            Backends::Uv(uv) => uv.plan(no_confirm, selection),
+            Backends::Npm(npm) => npm.plan(no_confirm, selection),
+            Backends::Opam(opam) => opam.plan(no_confirm, selection),
+            Backends::Dnf(dnf) => dnf.plan(no_confirm, selection),
+            Backends::Apt(apt) => apt.plan(no_confirm, selection),
+            Backends::Go(go) => go.plan(no_confirm, selection),
+            Backends::Snap(snap) => snap.plan(no_confirm, selection),
+            Backends::Brew(brew) => brew.plan(no_confirm, selection),
+            Backends::Nix(nix) => nix.plan(no_confirm, selection),
+            Backends::Gem(gem) => gem.plan(no_confirm, selection),
+            Backends::VsCode(vscode) => vscode.plan(no_confirm, selection),
+            Backends::AppImage(appimage) => appimage.plan(no_confirm, selection),
+            Backends::Custom(custom) => custom.plan(no_confirm, selection),
+            Backends::Plugin(plugin) => plugin.plan(no_confirm, selection),
+            Backends::Files(files) => files.plan(no_confirm, selection),
+        }
+    }
+
+    pub fn unmanaged(&self) -> Result<Box<[String]>> {
+        match self {
+            Backends::Arch(arch) => arch.unmanaged(),
+            Backends::Flatpak(flatpak) => flatpak.unmanaged(),
+            Backends::Cargo(cargo) => cargo.unmanaged(),
+            Backends::Rustup(rustup) => rustup.unmanaged(),
+            Backends::Uv(uv) => uv.unmanaged(),
+            Backends::Npm(npm) => npm.unmanaged(),
+            Backends::Opam(opam) => opam.unmanaged(),
+            Backends::Dnf(dnf) => dnf.unmanaged(),
+            Backends::Apt(apt) => apt.unmanaged(),
+            Backends::Go(go) => go.unmanaged(),
+            Backends::Snap(snap) => snap.unmanaged(),
+            Backends::Brew(brew) => brew.unmanaged(),
+            Backends::Nix(nix) => nix.unmanaged(),
+            Backends::Gem(gem) => gem.unmanaged(),
+            Backends::VsCode(vscode) => vscode.unmanaged(),
+            Backends::AppImage(appimage) => appimage.unmanaged(),
+            Backends::Custom(custom) => custom.unmanaged(),
+            Backends::Plugin(plugin) => plugin.unmanaged(),
+            Backends::Files(files) => files.unmanaged(),
+        }
+    }
+
+    pub fn update(&mut self, opts: &UpdateCommand) -> Result<()> {
+        match self {
+            Backends::Arch(arch) => arch.update(opts),
+            Backends::Flatpak(flatpak) => flatpak.update(opts),
+            Backends::Cargo(cargo) => cargo.update(opts),
+            Backends::Rustup(rustup) => rustup.update(opts),
+            Backends::Uv(uv) => uv.update(opts),
+            Backends::Npm(npm) => npm.update(opts),
+            Backends::Opam(opam) => opam.update(opts),
+            Backends::Dnf(dnf) => dnf.update(opts),
+            Backends::Apt(apt) => apt.update(opts),
+            Backends::Go(go) => go.update(opts),
+            Backends::Snap(snap) => snap.update(opts),
+            Backends::Brew(brew) => brew.update(opts),
+            Backends::Nix(nix) => nix.update(opts),
+            Backends::Gem(gem) => gem.update(opts),
+            Backends::VsCode(vscode) => vscode.update(opts),
+            Backends::AppImage(appimage) => appimage.update(opts),
+            Backends::Custom(custom) => custom.update(opts),
+            Backends::Plugin(plugin) => plugin.update(opts),
+            Backends::Files(files) => files.update(opts),
         }
     }
 
@@ -54,10 +212,65 @@ impl Backends {
             Backends::Flatpak(flatpak) => flatpak.clean_cache(config, opts),
             Backends::Cargo(cargo) => cargo.clean_cache(config, opts),
             Backends::Rustup(rustup) => rustup.clean_cache(config, opts),
+            Backends::Uv(uv) => uv.clean_cache(config, opts),
+            Backends::Npm(npm) => npm.clean_cache(config, opts),
+            Backends::Opam(opam) => opam.clean_cache(config, opts),
+            Backends::Dnf(dnf) => dnf.clean_cache(config, opts),
+            Backends::Apt(apt) => apt.clean_cache(config, opts),
+            Backends::Go(go) => go.clean_cache(config, opts),
+            Backends::Snap(snap) => snap.clean_cache(config, opts),
+            Backends::Brew(brew) => brew.clean_cache(config, opts),
+            Backends::Nix(nix) => nix.clean_cache(config, opts),
+            Backends::Gem(gem) => gem.clean_cache(config, opts),
+            Backends::VsCode(vscode) => vscode.clean_cache(config, opts),
+            Backends::AppImage(appimage) => appimage.clean_cache(config, opts),
+            Backends::Custom(custom) => custom.clean_cache(config, opts),
+            Backends::Plugin(plugin) => plugin.clean_cache(config, opts),
+            Backends::Files(files) => files.clean_cache(config, opts),
         }
     }
 }
 
+/// Backends named on the command line are used even if they are disabled in
+/// the config, while skipped ones are dropped regardless
+pub fn is_enabled(backend: &str, only: &[String], skipped: &[String], disabled: &[String]) -> bool {
+    let contains = |list: &[String]| list.iter().any(|name| name == backend);
+
+    if contains(skipped) {
+        false
+    } else if only.is_empty() {
+        !contains(disabled)
+    } else {
+        contains(only)
+    }
+}
+
+/// The packages out of the ones to remove that a package staying installed
+/// still requires, directly or through other packages that are kept for it
+fn needed_packages<'a>(dependents: &BTreeMap<&'a str, HashSet<String>>) -> BTreeSet<&'a str> {
+    let mut needed = BTreeSet::new();
+
+    loop {
+        let newly_needed: Box<[_]> = dependents
+            .iter()
+            .filter(|(package, _)| !needed.contains(*package))
+            .filter(|(_, package_dependents)| {
+                package_dependents.iter().any(|dependent| {
+                    !dependents.contains_key(dependent.as_str())
+                        || needed.contains(dependent.as_str())
+                })
+            })
+            .map(|(package, _)| *package)
+            .collect();
+
+        if newly_needed.is_empty() {
+            return needed;
+        }
+
+        needed.extend(newly_needed);
+    }
+}
+
 #[macro_export]
 macro_rules! backend_parse {
     ($packages:ident, $config:ident, $($backend:ident),*) => {
@@ -82,9 +295,78 @@ macro_rules! backend_parse {
     };
 }
 
+#[macro_export]
+macro_rules! backend_names {
+    ($($backend:ident),*) => {
+        [$(stringify!($backend)),*]
+    };
+}
+
+#[macro_export]
+macro_rules! for_all_backends {
+    ($macro:ident, $($args:tt)*) => {
+        $macro!($($args)* Arch, Flatpak, Cargo, Rustup, Uv, Npm, Opam, Dnf, Apt, Go, Snap, Brew, Nix, Gem, VsCode, AppImage, Custom, Files)
+    };
+}
+
 #[macro_export]
 macro_rules! parse_all_backends {
     ($packages:ident, $config:ident) => {
-        backend_parse!($packages, $config, Arch, Flatpak, Cargo, Rustup)
+        for_all_backends!(backend_parse, $packages, $config,)
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| (*name).to_owned()).collect()
+    }
+
+    #[test]
+    fn is_enabled_by_default() {
+        assert!(is_enabled("Arch", &[], &[], &[]));
+    }
+
+    #[test]
+    fn is_enabled_disabled_in_config() {
+        let disabled = names(&["Flatpak"]);
+        assert!(!is_enabled("Flatpak", &[], &[], &disabled));
+        assert!(is_enabled("Arch", &[], &[], &disabled));
+    }
+
+    #[test]
+    fn is_enabled_only() {
+        let only = names(&["Arch", "Flatpak"]);
+        let disabled = names(&["Flatpak"]);
+        assert!(is_enabled("Arch", &only, &[], &[]));
+        assert!(!is_enabled("Cargo", &only, &[], &[]));
+        assert!(is_enabled("Flatpak", &only, &[], &disabled));
+    }
+
+    #[test]
+    fn needed_packages_follow_kept_dependents() {
+        let dependents = BTreeMap::from([
+            // Only needed by another package that goes away
+            ("libfoo", HashSet::from(["foo".to_owned()])),
+            ("foo", HashSet::new()),
+            // Needed by a package that stays, along with its own dependency
+            ("libbar", HashSet::from(["bar".to_owned()])),
+            ("bar", HashSet::from(["firefox".to_owned()])),
+        ]);
+
+        assert_eq!(
+            needed_packages(&dependents),
+            BTreeSet::from(["bar", "libbar"])
+        );
+    }
+
+    #[test]
+    fn is_enabled_skipped() {
+        let only = names(&["Arch"]);
+        let skipped = names(&["Arch"]);
+        assert!(!is_enabled("Arch", &only, &skipped, &[]));
+        assert!(!is_enabled("Arch", &[], &skipped, &[]));
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const HOOK_KEY: &str = "post_hook";
+
+// Attribute a flake ref without a fragment points to
+const DEFAULT_ATTR: &str = "default";
+
+#[derive(Clone, Debug)]
+pub struct Nix {
+    packages: HashMap<String, Option<Closure>>,
+}
+
+impl Backend for Nix {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for Nix"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The package list in Nix is not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed nix packages");
+        Ok(Nix { packages })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed = get_installed_elements()?;
+
+        Ok(self
+            .get_extra_elements(&installed)
+            .into_keys()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let installed = get_installed_elements()?;
+
+        let mut plan = Plan::default();
+
+        let missing: BTreeMap<_, _> = self
+            .packages
+            .iter()
+            .filter(|(flake_ref, _)| !installed.contains_key(&normalize(flake_ref)))
+            .collect();
+
+        plan.push(
+            Action::Install,
+            missing.keys().map(|flake_ref| flake_ref.as_str()),
+            ["nix", "profile", "install"]
+                .into_iter()
+                .chain(missing.keys().map(|flake_ref| flake_ref.as_str())),
+            Perms::User,
+        );
+        missing
+            .values()
+            .for_each(|hook| plan.push_hook(hook.as_ref()));
+
+        let extra = self.get_extra_elements(&installed);
+
+        plan.push(
+            Action::Remove,
+            extra.keys().copied(),
+            ["nix", "profile", "remove"]
+                .into_iter()
+                .chain(extra.values().copied()),
+            Perms::User,
+        );
+
+        Ok(plan)
+    }
+
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        let installed = get_installed_elements()?;
+
+        // Only the declared elements are upgraded, by the name (or index)
+        // the profile knows them by
+        let declared: BTreeMap<_, _> = self
+            .packages
+            .keys()
+            .filter_map(|flake_ref| {
+                installed
+                    .get(&normalize(flake_ref))
+                    .map(|element| (flake_ref.as_str(), element.as_str()))
+            })
+            .collect();
+
+        if declared.is_empty() {
+            log::info!("No declared nix packages are installed, nothing to upgrade");
+            return Ok(());
+        }
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to upgrade the following flake refs for nix?: ",
+                declared.keys(),
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(
+            ["nix", "profile", "upgrade"]
+                .into_iter()
+                .chain(declared.values().copied()),
+            Perms::User,
+        )
+        .inspect(|_| log::info!("Successfully upgraded the nix packages"))
+        .map_err(|e| nest_errors!("Failed to upgrade the nix packages", e))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Nix",
+            &[PACKAGE_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "Nix")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("nix".to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to clean nix cache?",
+                ["Deleting unreachable paths from the nix store"],
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(["nix-collect-garbage"], Perms::User)
+            .inspect(|_| log::info!("Collected nix's garbage"))
+            .map_err(|e| nest_errors!("Failed to collect garbage", e))
+    }
+}
+
+impl Nix {
+    /// Maps the flake ref of every undeclared element to the name (or index)
+    /// `nix profile remove` takes
+    fn get_extra_elements<'a>(
+        &self,
+        installed: &'a HashMap<String, String>,
+    ) -> BTreeMap<&'a str, &'a str> {
+        let configured: BTreeSet<_> = self
+            .packages
+            .keys()
+            .map(|flake_ref| normalize(flake_ref))
+            .collect();
+
+        installed
+            .iter()
+            .filter(|(flake_ref, _)| !configured.contains(*flake_ref))
+            .map(|(flake_ref, element)| (flake_ref.as_str(), element.as_str()))
+            .collect()
+    }
+}
+
+fn get_installed_elements() -> Result<HashMap<String, String>> {
+    let json = run_command_for_stdout(["nix", "profile", "list", "--json"], Perms::User, true)
+        .map_err(|e| nest_errors!("Failed to list the nix profile", e))?;
+
+    parse_profile(&json)
+}
+
+// Newer nix versions key the elements by name, older ones keep them in a
+// list and remove them by index
+fn parse_profile(json: &str) -> Result<HashMap<String, String>> {
+    let json: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| nest_errors!("error occured in parsing json data", e))?;
+
+    let elements: Vec<_> = match json.get("elements") {
+        Some(serde_json::Value::Object(elements)) => elements
+            .iter()
+            .map(|(name, element)| (name.to_owned(), element))
+            .collect(),
+        Some(serde_json::Value::Array(elements)) => elements
+            .iter()
+            .enumerate()
+            .map(|(index, element)| (index.to_string(), element))
+            .collect(),
+        _ => return Err(mod_err!("No elements found in the nix profile")),
+    };
+
+    Ok(elements
+        .into_iter()
+        .filter_map(|(name, element)| {
+            let url = element.get("originalUrl")?.as_str()?;
+            let attr = element.get("attrPath")?.as_str()?;
+
+            Some((normalize(&[url, "#", attr].concat()), name))
+        })
+        .collect())
+}
+
+// `nixpkgs#ripgrep` is recorded as `flake:nixpkgs` and
+// `legacyPackages.<system>.ripgrep`, so both sides are reduced to the form
+// written on the command line before comparing
+fn normalize(flake_ref: &str) -> String {
+    let (url, attr) = flake_ref.split_once('#').unwrap_or((flake_ref, ""));
+    let url = url.strip_prefix("flake:").unwrap_or(url);
+
+    let mut path = attr.splitn(3, '.');
+    let attr = match (path.next(), path.next(), path.next()) {
+        (Some("packages" | "legacyPackages"), Some(_), Some(name)) => name,
+        (Some("packages" | "legacyPackages"), Some(_), None) | (Some(""), _, _) => DEFAULT_ATTR,
+        _ => attr,
+    };
+
+    [url, "#", attr].concat()
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, Option<Closure>)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The package-spec is not a record", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No flake ref mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The flake ref was not a string", e))?
+        .to_owned();
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {package} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((package, post_hook))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_flake_refs() {
+        assert_eq!(normalize("nixpkgs#ripgrep"), "nixpkgs#ripgrep");
+        assert_eq!(
+            normalize("flake:nixpkgs#legacyPackages.x86_64-linux.ripgrep"),
+            "nixpkgs#ripgrep"
+        );
+        assert_eq!(normalize("github:user/repo"), "github:user/repo#default");
+        assert_eq!(
+            normalize("github:user/repo#packages.aarch64-linux.default"),
+            "github:user/repo#default"
+        );
+        assert_eq!(
+            normalize("nixpkgs#python3Packages.black"),
+            "nixpkgs#python3Packages.black"
+        );
+    }
+
+    #[test]
+    fn parse_profile_named_elements() {
+        let json = r#"{
+            "elements": {
+                "ripgrep": {
+                    "active": true,
+                    "attrPath": "legacyPackages.x86_64-linux.ripgrep",
+                    "originalUrl": "flake:nixpkgs",
+                    "url": "github:NixOS/nixpkgs/abcdef"
+                }
+            },
+            "version": 3
+        }"#;
+
+        let installed = parse_profile(json).unwrap();
+        assert_eq!(installed["nixpkgs#ripgrep"], "ripgrep");
+    }
+
+    #[test]
+    fn parse_profile_indexed_elements() {
+        let json = r#"{
+            "elements": [
+                { "attrPath": "legacyPackages.x86_64-linux.jq", "originalUrl": "flake:nixpkgs" },
+                { "attrPath": "packages.x86_64-linux.default", "originalUrl": "github:user/repo" }
+            ],
+            "version": 2
+        }"#;
+
+        let installed = parse_profile(json).unwrap();
+        assert_eq!(installed["nixpkgs#jq"], "0");
+        assert_eq!(installed["github:user/repo#default"], "1");
+        assert!(parse_profile("{}").is_err());
+    }
+}
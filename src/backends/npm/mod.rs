@@ -0,0 +1,470 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::{env, fs};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+};
+use crate::config::{DEFAULT_NPM_CLIENT, NPM_CLIENT_KEY};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const VERSION_KEY: &str = "version";
+const HOOK_KEY: &str = "post_hook";
+
+// Shipped with node itself, so they are never removed unless declared
+const BUNDLED_PACKAGES: [&str; 2] = ["npm", "corepack"];
+
+#[derive(Clone, Copy, Debug)]
+struct Client {
+    name: &'static str,
+    install: &'static str,
+    remove: &'static str,
+    clean_cache: &'static [&'static str],
+}
+
+const CLIENTS: [Client; 3] = [
+    Client {
+        name: "npm",
+        install: "install",
+        remove: "uninstall",
+        clean_cache: &["cache", "clean", "--force"],
+    },
+    Client {
+        name: "pnpm",
+        install: "add",
+        remove: "remove",
+        clean_cache: &["store", "prune"],
+    },
+    Client {
+        name: "bun",
+        install: "add",
+        remove: "remove",
+        clean_cache: &["pm", "cache", "rm"],
+    },
+];
+
+#[derive(Clone, Debug)]
+pub struct NpmOpts {
+    version: Option<String>,
+    post_hook: Option<Closure>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Npm {
+    packages: HashMap<String, NpmOpts>,
+    client: Client,
+}
+
+impl Backend for Npm {
+    fn new(value: &Record, config: &Record) -> Result<Self> {
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for Npm"))?
+            .as_list()
+            .map_err(|e| nest_errors!("Packages not a list for Npm", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Parsed npm packages from spec");
+
+        let client = get_client(config)?;
+
+        Ok(Npm { packages, client })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed = self.get_installed_packages()?;
+
+        Ok(self
+            .get_extra_packages(&installed)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let installed = self.get_installed_packages()?;
+        let client = self.client;
+
+        let mut plan = Plan::default();
+
+        self.packages
+            .iter()
+            .filter(|(name, spec)| needs_install(installed.get(*name), spec))
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .for_each(|(name, spec)| {
+                plan.push(
+                    Action::Install,
+                    [name],
+                    [
+                        client.name,
+                        client.install,
+                        "--global",
+                        &package_spec(name, spec),
+                    ],
+                    Perms::User,
+                );
+                plan.push_hook(spec.post_hook.as_ref());
+            });
+
+        self.get_extra_packages(&installed)
+            .into_iter()
+            .for_each(|package| {
+                plan.push(
+                    Action::Remove,
+                    [package],
+                    [client.name, client.remove, "--global", package],
+                    Perms::User,
+                );
+            });
+
+        Ok(plan)
+    }
+
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let installed = self.get_installed_packages()?;
+
+        // Pinned versions are left alone, updating them would only undo the pin
+        let installed_packages: BTreeSet<_> = self
+            .packages
+            .iter()
+            .filter(|(name, spec)| installed.contains_key(*name) && !is_exact(spec))
+            .map(|(name, _)| name.as_str())
+            .collect();
+
+        if installed_packages.is_empty() {
+            log::info!("No installed packages to update");
+            return Ok(());
+        }
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to update the following packages for npm?: ",
+                &installed_packages,
+            )?
+        {
+            return Ok(());
+        }
+
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        command_action(
+            [self.client.name, "update", "--global"]
+                .into_iter()
+                .chain(installed_packages),
+            Perms::User,
+        )
+        .inspect(|_| log::info!("Successfully updated npm packages"))
+        .map_err(|e| nest_errors!("Failed to update npm packages", e))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Npm",
+            &[PACKAGE_KEY, VERSION_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "Npm")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(config: &Record) -> Result<String> {
+        get_client(config).map(|client| client.name.to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn settings(config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        let client = get_client(config)?;
+
+        Ok(Box::new([(NPM_CLIENT_KEY, client.name.to_owned())]))
+    }
+
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let client = self.client;
+
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                format!("Do you want to clean {} cache?", client.name),
+                [client.clean_cache.join(" ")],
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(
+            [client.name].iter().chain(client.clean_cache).copied(),
+            Perms::User,
+        )
+        .inspect(|_| log::info!("Cleaned {}'s cache", client.name))
+        .map_err(|e| nest_errors!("Failed to clean cache", e))
+    }
+}
+
+impl Npm {
+    // npm and pnpm can report their globals as json, while bun keeps them in
+    // a regular package.json under its install directory
+    fn get_installed_packages(&self) -> Result<BTreeMap<String, String>> {
+        let json = if self.client.name == "bun" {
+            let global = get_bun_path()? + "/install/global/package.json";
+
+            match fs::read_to_string(&global) {
+                Ok(json) => json,
+                Err(e) => {
+                    log::warn!(
+                        "Error {e} occured in reading {global}. Assuming packages are not installed."
+                    );
+                    return Ok(BTreeMap::new());
+                }
+            }
+        } else {
+            run_command_for_stdout(
+                [self.client.name, "ls", "--global", "--json", "--depth=0"],
+                Perms::User,
+                true,
+            )
+            .map_err(|e| nest_errors!("Failed to list global packages", e))?
+        };
+
+        parse_installed_packages(&json)
+    }
+
+    fn get_extra_packages<'a>(&self, installed: &'a BTreeMap<String, String>) -> BTreeSet<&'a str> {
+        installed
+            .keys()
+            .map(String::as_str)
+            .filter(|package| !self.packages.contains_key(*package))
+            .filter(|package| !BUNDLED_PACKAGES.contains(package))
+            .collect()
+    }
+}
+
+// `npm ls` prints a single object while `pnpm ls` prints a list of them, one
+// per global directory. Dependencies map to either an object with the
+// installed version or, in a package.json, to the requested range.
+fn parse_installed_packages(json: &str) -> Result<BTreeMap<String, String>> {
+    let json: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| nest_errors!("error occured in parsing json data", e))?;
+
+    let roots = match &json {
+        serde_json::Value::Array(roots) => roots.iter().collect(),
+        root => vec![root],
+    };
+
+    Ok(roots
+        .into_iter()
+        .filter_map(|root| root.get("dependencies")?.as_object())
+        .flatten()
+        .map(|(name, spec)| {
+            let version = spec
+                .get("version")
+                .unwrap_or(spec)
+                .as_str()
+                .unwrap_or_default()
+                .trim_start_matches(['^', '~']);
+
+            (name.to_owned(), version.to_owned())
+        })
+        .collect())
+}
+
+fn needs_install(installed: Option<&String>, spec: &NpmOpts) -> bool {
+    match (installed, &spec.version) {
+        (None, _) => true,
+        (Some(installed), Some(version)) if is_exact(spec) => installed != version,
+        (Some(_), _) => false,
+    }
+}
+
+// Only exact versions can be compared against the installed one, tags and
+// ranges are resolved by the client at install time
+fn is_exact(spec: &NpmOpts) -> bool {
+    spec.version
+        .as_deref()
+        .is_some_and(|version| version.chars().all(|c| c.is_ascii_digit() || c == '.'))
+}
+
+fn package_spec(name: &str, spec: &NpmOpts) -> String {
+    match &spec.version {
+        Some(version) => [name, "@", version].concat(),
+        None => name.to_owned(),
+    }
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, NpmOpts)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("Failed to parse value", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No package mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("Package name in record is not a string", e))?
+        .to_owned();
+
+    let version = match record.get(VERSION_KEY) {
+        Some(version) => Some(
+            version
+                .as_str()
+                .map_err(|e| nest_errors!("version for {package} is not a string", e))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(closure) => {
+            let closure = closure
+                .as_closure()
+                .map_err(|e| nest_errors!("closure for {package} not a closure", e))?;
+
+            Some(closure.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((package, NpmOpts { version, post_hook }))
+}
+
+fn get_client(config: &Record) -> Result<Client> {
+    let name = match config.get(NPM_CLIENT_KEY) {
+        Some(name) => name
+            .as_str()
+            .map_err(|e| nest_errors!("Failed to parse config, npm client is not a string", e))?,
+        None => {
+            log::info!("Value not specified in config, defaulting to {DEFAULT_NPM_CLIENT}");
+            DEFAULT_NPM_CLIENT
+        }
+    };
+
+    CLIENTS
+        .into_iter()
+        .find(|client| client.name == name)
+        .ok_or_else(|| mod_err!("Unsupported npm client {name}, expected npm, pnpm or bun"))
+}
+
+fn get_bun_path() -> Result<String> {
+    env::var("BUN_INSTALL").or_else(|e| -> Result<String> {
+        log::debug!("Encountered error: {e}");
+        log::debug!("Using the default: ~/.bun");
+        let home = env::var("HOME")?;
+        Ok(home + "/.bun")
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    #[test]
+    fn get_client_default() {
+        let pnpm = Record::from_raw_cols_vals(
+            vec![NPM_CLIENT_KEY.to_owned()],
+            vec![Value::string("pnpm", Span::test_data())],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+        let yarn = Record::from_raw_cols_vals(
+            vec![NPM_CLIENT_KEY.to_owned()],
+            vec![Value::string("yarn", Span::test_data())],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        assert_eq!(get_client(&Record::new()).unwrap().name, "npm");
+        assert_eq!(get_client(&pnpm).unwrap().remove, "remove");
+        assert!(get_client(&yarn).is_err());
+    }
+
+    #[test]
+    fn value_to_pkgspec_version() {
+        let record = Record::from_raw_cols_vals(
+            vec!["package".to_owned(), "version".to_owned()],
+            vec![
+                Value::string("typescript", Span::test_data()),
+                Value::string("5.4.2", Span::test_data()),
+            ],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let (name, spec) = value_to_pkgspec(&Value::record(record, Span::test_data())).unwrap();
+        assert_eq!(package_spec(&name, &spec), "typescript@5.4.2");
+        assert!(spec.post_hook.is_none());
+    }
+
+    #[test]
+    fn parse_installed_packages_npm() {
+        let json = r#"{
+            "name": "lib",
+            "dependencies": {
+                "npm": { "version": "10.2.4" },
+                "typescript": { "version": "5.4.2" }
+            }
+        }"#;
+
+        let installed = parse_installed_packages(json).unwrap();
+        assert_eq!(installed.get("typescript").unwrap(), "5.4.2");
+        assert_eq!(installed.len(), 2);
+    }
+
+    #[test]
+    fn parse_installed_packages_pnpm_and_bun() {
+        let pnpm = r#"[{ "dependencies": { "prettier": { "version": "3.2.5" } } }]"#;
+        let bun = r#"{ "dependencies": { "prettier": "^3.2.5" } }"#;
+
+        assert_eq!(
+            parse_installed_packages(pnpm).unwrap(),
+            parse_installed_packages(bun).unwrap()
+        );
+        assert!(parse_installed_packages("[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn needs_install_versions() {
+        let spec = |version: Option<&str>| NpmOpts {
+            version: version.map(ToOwned::to_owned),
+            post_hook: None,
+        };
+        let installed = "5.4.2".to_owned();
+
+        assert!(needs_install(None, &spec(None)));
+        assert!(!needs_install(Some(&installed), &spec(None)));
+        assert!(!needs_install(Some(&installed), &spec(Some("latest"))));
+        assert!(!needs_install(Some(&installed), &spec(Some("5.4.2"))));
+        assert!(needs_install(Some(&installed), &spec(Some("5.3.0"))));
+    }
+}
@@ -0,0 +1,805 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::{
+    CleanCacheCommand, UpdateCommand,
+    commands::{Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout},
+    function, mod_err, nest_errors,
+    plan::{Action, Plan, Selection},
+    validate::{Diagnostic, check_records, unknown_keys},
+};
+
+use super::Backend;
+
+const SWITCH_LIST_KEY: &str = "switches";
+const COMPILER_KEY: &str = "compiler";
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const VERSION_KEY: &str = "version";
+const URL_KEY: &str = "url";
+const HOOK_KEY: &str = "post_hook";
+
+// Installed alongside the compiler of every switch, so never removed
+const BASE_PACKAGES: [&str; 5] = [
+    "ocaml",
+    "ocaml-base-compiler",
+    "ocaml-config",
+    "ocaml-system",
+    "ocaml-variants",
+];
+
+// Made by `opam init` without a declaration, and used by every shell that
+// has not picked a switch, so never removed
+const DEFAULT_SWITCH: &str = "default";
+
+#[derive(Debug, Clone)]
+pub struct Opam {
+    switches: HashMap<String, SwitchSpec>,
+}
+
+#[derive(Debug, Clone)]
+struct SwitchSpec {
+    compiler: Option<String>,
+    packages: HashMap<String, PackageSpec>,
+}
+
+#[derive(Debug, Clone)]
+struct PackageSpec {
+    pin: Option<Pin>,
+    post_hook: Option<Closure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Pin {
+    Version(String),
+    Url(String),
+}
+
+impl Backend for Opam {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let switches = value
+            .get(SWITCH_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get switches for Opam"))?
+            .as_record()
+            .map_err(|e| nest_errors!("The switch spec in Opam is not a record", e))?
+            .iter()
+            .map(|(switch, value)| -> Result<_> {
+                Ok((switch.to_owned(), value_to_switchspec(switch, value)?))
+            })
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed opam switches");
+        Ok(Opam { switches })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed_switches = get_installed_switches()?;
+
+        let mut unmanaged: Vec<_> = self
+            .get_extra_switches(&installed_switches)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect();
+
+        for (switch, spec) in self.get_present_switches(&installed_switches) {
+            get_installed_packages(switch)?
+                .keys()
+                .filter(|package| is_extra(package, spec))
+                .for_each(|package| {
+                    unmanaged.push([switch, PACKAGE_LIST_KEY, package].join("/"));
+                });
+        }
+
+        Ok(unmanaged.into())
+    }
+
+    fn plan(&self, no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let installed_switches = get_installed_switches()?;
+        let yes: Option<&'static str> = no_confirm.then_some("--yes");
+
+        let mut plan = Plan::default();
+
+        for (switch, spec) in self
+            .switches
+            .iter()
+            .filter(|(switch, _)| !installed_switches.contains(*switch))
+            .collect::<BTreeMap<_, _>>()
+        {
+            let compiler = spec.compiler.as_deref().ok_or_else(|| {
+                mod_err!("Switch {switch} does not exist and has no compiler to create it with")
+            })?;
+
+            plan.push(
+                Action::Install,
+                [switch],
+                ["opam", "switch", "create", switch, compiler]
+                    .into_iter()
+                    .chain(yes),
+                Perms::User,
+            );
+
+            plan_packages(
+                switch,
+                spec,
+                &BTreeMap::new(),
+                &BTreeSet::new(),
+                yes,
+                &mut plan,
+            );
+        }
+
+        for (switch, spec) in self.get_present_switches(&installed_switches) {
+            let installed = get_installed_packages(switch)?;
+            let pinned = get_pinned_packages(switch)?;
+
+            plan_packages(switch, spec, &installed, &pinned, yes, &mut plan);
+        }
+
+        self.get_extra_switches(&installed_switches)
+            .into_iter()
+            .for_each(|switch| {
+                plan.push(
+                    Action::Remove,
+                    [switch],
+                    ["opam", "switch", "remove", switch].into_iter().chain(yes),
+                    Perms::User,
+                );
+            });
+
+        Ok(plan)
+    }
+
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let installed_switches = get_installed_switches()?;
+
+        let switches: Box<[_]> = self
+            .get_present_switches(&installed_switches)
+            .map(|(switch, _)| switch)
+            .collect();
+
+        if switches.is_empty() {
+            log::info!("No installed switches to update");
+            return Ok(());
+        }
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to update the following switches for opam?: ",
+                &switches,
+            )?
+        {
+            return Ok(());
+        }
+
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        let yes = opts.no_confirm.then_some("--yes");
+
+        command_action(
+            ["opam", "update"].into_iter().chain(yes).collect(),
+            Perms::User,
+        )
+        .map_err(|e| nest_errors!("Failed to update opam repositories", e))?;
+
+        switches
+            .iter()
+            .try_for_each(|switch| {
+                command_action(
+                    ["opam", "upgrade", "--switch", switch]
+                        .into_iter()
+                        .chain(yes)
+                        .collect::<Vec<_>>(),
+                    Perms::User,
+                )
+                .map_err(|e| nest_errors!("Failed to upgrade switch {switch}", e))
+            })
+            .inspect(|_| log::info!("Successfully updated switches"))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let mut diagnostics = unknown_keys(value, &[SWITCH_LIST_KEY], "Opam");
+
+        let switches = match value.get(SWITCH_LIST_KEY).map(Value::as_record) {
+            Some(Ok(switches)) => switches,
+            Some(Err(e)) => {
+                diagnostics.push(Diagnostic::error("Opam.switches", e));
+                return diagnostics;
+            }
+            None => return diagnostics,
+        };
+
+        for (switch, spec) in switches.iter() {
+            let source = ["Opam", SWITCH_LIST_KEY, switch].join(".");
+
+            let record = match spec.as_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    diagnostics.push(Diagnostic::error(source, e));
+                    continue;
+                }
+            };
+
+            diagnostics.extend(unknown_keys(
+                record,
+                &[COMPILER_KEY, PACKAGE_LIST_KEY],
+                &source,
+            ));
+
+            if let Some(Err(e)) = record.get(COMPILER_KEY).map(Value::as_str) {
+                diagnostics.push(Diagnostic::error(
+                    [source.as_str(), COMPILER_KEY].join("."),
+                    e,
+                ));
+            }
+
+            let (_, packages) = check_records(
+                record,
+                PACKAGE_LIST_KEY,
+                &source,
+                &[PACKAGE_KEY, VERSION_KEY, URL_KEY, HOOK_KEY],
+                value_to_pkgspec,
+            );
+            diagnostics.extend(packages);
+        }
+
+        diagnostics
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("opam".to_owned())
+    }
+
+    /// Counts every switch and package
+    fn package_count(&self) -> usize {
+        self.switches
+            .values()
+            .map(|spec| 1 + spec.packages.len())
+            .sum()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to clean opam cache?",
+                ["Removing downloaded archives and build artifacts"],
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(["opam", "clean"], Perms::User)
+            .inspect(|_| log::info!("Cleaned opam's cache"))
+            .map_err(|e| nest_errors!("Failed to clean cache", e))
+    }
+}
+
+impl Opam {
+    fn get_extra_switches<'a>(&self, installed_switches: &'a [String]) -> Box<[&'a str]> {
+        installed_switches
+            .iter()
+            .filter(|switch| !self.switches.contains_key(*switch) && *switch != DEFAULT_SWITCH)
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn get_present_switches<'a>(
+        &'a self,
+        installed_switches: &'a [String],
+    ) -> impl Iterator<Item = (&'a str, &'a SwitchSpec)> {
+        let mut present: Vec<_> = self
+            .switches
+            .iter()
+            .filter(|(switch, _)| installed_switches.contains(*switch))
+            .map(|(switch, spec)| (switch.as_str(), spec))
+            .collect();
+        present.sort_unstable_by_key(|(switch, _)| *switch);
+
+        present.into_iter()
+    }
+}
+
+// Pinning a package installs it as well, so pinned packages never show up in
+// the plain install step
+fn plan_packages(
+    switch: &str,
+    switch_spec: &SwitchSpec,
+    installed: &BTreeMap<String, String>,
+    pinned: &BTreeSet<String>,
+    yes: Option<&'static str>,
+    plan: &mut Plan,
+) {
+    let qualify = |package: &str| [switch, PACKAGE_LIST_KEY, package].join("/");
+
+    let packages: BTreeMap<_, _> = switch_spec.packages.iter().collect();
+
+    packages
+        .iter()
+        .filter_map(|(package, spec)| Some((package, spec.pin.as_ref()?, spec)))
+        .filter(|(package, pin, _)| match pin {
+            Pin::Version(version) => installed.get(package.as_str()) != Some(version),
+            Pin::Url(_) => !pinned.contains(package.as_str()),
+        })
+        .for_each(|(package, pin, spec)| {
+            let target = match pin {
+                Pin::Version(version) | Pin::Url(version) => version.as_str(),
+            };
+
+            plan.push(
+                Action::Pin,
+                [qualify(package)],
+                ["opam", "pin", "add", "--switch", switch, package, target]
+                    .into_iter()
+                    .chain(yes),
+                Perms::User,
+            );
+            plan.push_hook(spec.post_hook.as_ref());
+        });
+
+    let missing: Box<[_]> = packages
+        .iter()
+        .filter(|(package, spec)| spec.pin.is_none() && !installed.contains_key(package.as_str()))
+        .map(|(package, _)| package.as_str())
+        .collect();
+
+    plan.push(
+        Action::Install,
+        missing.iter().map(|package| qualify(package)),
+        ["opam", "install", "--switch", switch]
+            .into_iter()
+            .chain(missing.iter().copied())
+            .chain(yes),
+        Perms::User,
+    );
+    missing
+        .iter()
+        .for_each(|package| plan.push_hook(switch_spec.packages[*package].post_hook.as_ref()));
+
+    let extra: Box<[_]> = installed
+        .keys()
+        .filter(|package| is_extra(package, switch_spec))
+        .map(String::as_str)
+        .collect();
+
+    extra
+        .iter()
+        .filter(|package| pinned.contains(**package))
+        .for_each(|package| {
+            plan.push(
+                Action::Unpin,
+                [qualify(package)],
+                [
+                    "opam",
+                    "pin",
+                    "remove",
+                    "--switch",
+                    switch,
+                    "--no-action",
+                    package,
+                ],
+                Perms::User,
+            );
+        });
+
+    plan.push(
+        Action::Remove,
+        extra.iter().map(|package| qualify(package)),
+        ["opam", "remove", "--switch", switch]
+            .into_iter()
+            .chain(extra.iter().copied())
+            .chain(yes),
+        Perms::User,
+    );
+}
+
+fn is_extra(package: &str, spec: &SwitchSpec) -> bool {
+    let compiler = spec
+        .compiler
+        .as_deref()
+        .map(|compiler| compiler.split_once('.').map_or(compiler, |split| split.0));
+
+    !spec.packages.contains_key(package)
+        && !BASE_PACKAGES.contains(&package)
+        && compiler != Some(package)
+}
+
+fn get_installed_switches() -> Result<Box<[String]>> {
+    let switches = run_command_for_stdout(["opam", "switch", "list", "--short"], Perms::User, true)
+        .map_err(|e| nest_errors!("Failed to get switches", e))?;
+
+    Ok(switches.lines().map(ToOwned::to_owned).collect())
+}
+
+/// Maps the explicitly installed packages of a switch to their version
+fn get_installed_packages(switch: &str) -> Result<BTreeMap<String, String>> {
+    let packages = run_command_for_stdout(
+        [
+            "opam",
+            "list",
+            "--switch",
+            switch,
+            "--installed-roots",
+            "--columns=package",
+            "--short",
+        ],
+        Perms::User,
+        true,
+    )
+    .map_err(|e| nest_errors!("Failed to get packages of {switch}", e))?;
+
+    Ok(parse_packages(&packages))
+}
+
+fn get_pinned_packages(switch: &str) -> Result<BTreeSet<String>> {
+    let packages = run_command_for_stdout(
+        ["opam", "pin", "list", "--switch", switch, "--short"],
+        Perms::User,
+        true,
+    )
+    .map_err(|e| nest_errors!("Failed to get pinned packages of {switch}", e))?;
+
+    Ok(parse_packages(&packages).into_keys().collect())
+}
+
+// opam prints packages as `name.version`, and package names never contain a dot
+fn parse_packages(stdout: &str) -> BTreeMap<String, String> {
+    stdout
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|package| {
+            let (name, version) = package.split_once('.').unwrap_or((package, ""));
+            (name.to_owned(), version.to_owned())
+        })
+        .collect()
+}
+
+fn value_to_switchspec(switch: &str, value: &Value) -> Result<SwitchSpec> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("Switch spec for {switch} is not a record", e))?;
+
+    let compiler = match record.get(COMPILER_KEY) {
+        Some(compiler) => Some(
+            compiler
+                .as_str()
+                .map_err(|e| nest_errors!("Compiler for {switch} is not a string", e))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let packages = match record.get(PACKAGE_LIST_KEY) {
+        Some(packages) => packages
+            .as_list()
+            .map_err(|e| nest_errors!("Packages of {switch} not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?,
+        None => HashMap::new(),
+    };
+
+    Ok(SwitchSpec { compiler, packages })
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, PackageSpec)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("Failed to parse value", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No package mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("Package name in record is not a string", e))?
+        .to_owned();
+
+    let version = record
+        .get(VERSION_KEY)
+        .map(Value::as_str)
+        .transpose()
+        .map_err(|e| nest_errors!("version for {package} is not a string", e))?;
+
+    let url = record
+        .get(URL_KEY)
+        .map(Value::as_str)
+        .transpose()
+        .map_err(|e| nest_errors!("url for {package} is not a string", e))?;
+
+    let pin = match (version, url) {
+        (Some(_), Some(_)) => {
+            return Err(mod_err!("{package} is pinned to both a version and a url"));
+        }
+        (Some(version), None) => Some(Pin::Version(version.to_owned())),
+        (None, Some(url)) => Some(Pin::Url(url.to_owned())),
+        (None, None) => None,
+    };
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(closure) => {
+            let closure = closure
+                .as_closure()
+                .map_err(|e| nest_errors!("closure for {package} not a closure", e))?;
+
+            Some(closure.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((package, PackageSpec { pin, post_hook }))
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    #[test]
+    fn value_to_switchspec_ok() {
+        let packages = Value::list(
+            vec![
+                Value::record(
+                    Record::from_raw_cols_vals(
+                        ["package"].into_iter().map(ToOwned::to_owned).collect(),
+                        vec![Value::string("dune", Span::test_data())],
+                        Span::test_data(),
+                        Span::test_data(),
+                    )
+                    .unwrap(),
+                    Span::test_data(),
+                ),
+                Value::record(
+                    Record::from_raw_cols_vals(
+                        ["package", "version"]
+                            .into_iter()
+                            .map(ToOwned::to_owned)
+                            .collect(),
+                        vec![
+                            Value::string("odoc", Span::test_data()),
+                            Value::string("2.4.0", Span::test_data()),
+                        ],
+                        Span::test_data(),
+                        Span::test_data(),
+                    )
+                    .unwrap(),
+                    Span::test_data(),
+                ),
+                Value::record(
+                    Record::from_raw_cols_vals(
+                        ["package", "url"]
+                            .into_iter()
+                            .map(ToOwned::to_owned)
+                            .collect(),
+                        vec![
+                            Value::string("foo", Span::test_data()),
+                            Value::string("git+https://example.com/foo", Span::test_data()),
+                        ],
+                        Span::test_data(),
+                        Span::test_data(),
+                    )
+                    .unwrap(),
+                    Span::test_data(),
+                ),
+            ],
+            Span::test_data(),
+        );
+
+        let spec = value_to_switchspec(
+            "default",
+            &Value::record(
+                Record::from_raw_cols_vals(
+                    ["compiler", "packages"]
+                        .into_iter()
+                        .map(ToOwned::to_owned)
+                        .collect(),
+                    vec![
+                        Value::string("ocaml-base-compiler.5.1.1", Span::test_data()),
+                        packages,
+                    ],
+                    Span::test_data(),
+                    Span::test_data(),
+                )
+                .unwrap(),
+                Span::test_data(),
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(spec.compiler.as_deref(), Some("ocaml-base-compiler.5.1.1"));
+        assert_eq!(spec.packages.len(), 3);
+        assert_eq!(spec.packages["dune"].pin, None);
+        assert_eq!(
+            spec.packages["odoc"].pin,
+            Some(Pin::Version("2.4.0".to_owned()))
+        );
+    }
+
+    #[test]
+    fn value_to_pkgspec_two_pins() {
+        let value = Value::record(
+            Record::from_raw_cols_vals(
+                ["package", "version", "url"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("foo", Span::test_data()),
+                    Value::string("1.0", Span::test_data()),
+                    Value::string("./foo", Span::test_data()),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        assert!(value_to_pkgspec(&value).is_err());
+    }
+
+    #[test]
+    fn parse_packages_splits_versions() {
+        let packages = parse_packages("# comment\ndune.3.14.0\nocaml-base-compiler.5.1.1\n");
+
+        assert_eq!(packages["dune"], "3.14.0");
+        assert_eq!(packages.len(), 2);
+    }
+
+    #[test]
+    fn is_extra_skips_compiler() {
+        let spec = SwitchSpec {
+            compiler: Some("ocaml-base-compiler.5.1.1".to_owned()),
+            packages: HashMap::from([(
+                "dune".to_owned(),
+                PackageSpec {
+                    pin: None,
+                    post_hook: None,
+                },
+            )]),
+        };
+
+        assert!(!is_extra("dune", &spec));
+        assert!(!is_extra("ocaml-base-compiler", &spec));
+        assert!(!is_extra("ocaml", &spec));
+        assert!(is_extra("utop", &spec));
+    }
+
+    #[test]
+    fn extra_switches_keep_default() {
+        let opam = Opam {
+            switches: HashMap::new(),
+        };
+        let installed = ["default".to_owned(), "5.1".to_owned()];
+
+        assert_eq!(*opam.get_extra_switches(&installed), ["5.1"]);
+    }
+
+    #[test]
+    fn plan_packages_new_switch() {
+        let spec = SwitchSpec {
+            compiler: Some("ocaml-base-compiler.5.1.1".to_owned()),
+            packages: HashMap::from([
+                (
+                    "dune".to_owned(),
+                    PackageSpec {
+                        pin: None,
+                        post_hook: None,
+                    },
+                ),
+                (
+                    "odoc".to_owned(),
+                    PackageSpec {
+                        pin: Some(Pin::Version("2.4.0".to_owned())),
+                        post_hook: None,
+                    },
+                ),
+                (
+                    "foo".to_owned(),
+                    PackageSpec {
+                        pin: Some(Pin::Url("git+https://example.com/foo".to_owned())),
+                        post_hook: None,
+                    },
+                ),
+            ]),
+        };
+
+        let mut plan = Plan::default();
+        plan_packages(
+            "default",
+            &spec,
+            &BTreeMap::new(),
+            &BTreeSet::new(),
+            None,
+            &mut plan,
+        );
+
+        let commands: Vec<_> = plan
+            .steps
+            .iter()
+            .map(|step| step.command.join(" "))
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                "opam pin add --switch default foo git+https://example.com/foo",
+                "opam pin add --switch default odoc 2.4.0",
+                "opam install --switch default dune",
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_packages_extra() {
+        let spec = SwitchSpec {
+            compiler: Some("ocaml-base-compiler.5.1.1".to_owned()),
+            packages: HashMap::from([
+                (
+                    "dune".to_owned(),
+                    PackageSpec {
+                        pin: None,
+                        post_hook: None,
+                    },
+                ),
+                (
+                    "odoc".to_owned(),
+                    PackageSpec {
+                        pin: Some(Pin::Version("2.4.0".to_owned())),
+                        post_hook: None,
+                    },
+                ),
+                (
+                    "foo".to_owned(),
+                    PackageSpec {
+                        pin: Some(Pin::Url("git+https://example.com/foo".to_owned())),
+                        post_hook: None,
+                    },
+                ),
+            ]),
+        };
+        let installed = parse_packages("dune.3.14.0\nodoc.2.4.0\nfoo.dev\nutop.2.13.0\n");
+        let pinned = ["foo", "utop"].into_iter().map(ToOwned::to_owned).collect();
+
+        let mut plan = Plan::default();
+        plan_packages(
+            "default",
+            &spec,
+            &installed,
+            &pinned,
+            Some("--yes"),
+            &mut plan,
+        );
+
+        let commands: Vec<_> = plan
+            .steps
+            .iter()
+            .map(|step| step.command.join(" "))
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                "opam pin remove --switch default --no-action utop",
+                "opam remove --switch default utop --yes",
+            ]
+        );
+        assert_eq!(
+            *plan.steps[1].packages,
+            ["default/packages/utop".to_owned()]
+        );
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs::read_dir;
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as Json, json};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command_with_stdin, find_executable, run_command_with_stdin,
+};
+use crate::output::{nu_to_json, record_to_json};
+use crate::plan::{Action, Call, Plan, Selection};
+use crate::validate::{Diagnostic, duplicates};
+use crate::{
+    CleanCacheCommand, UpdateCommand, backend_names, for_all_backends, function, mod_err,
+    nest_errors,
+};
+
+use super::Backends;
+
+pub const PLUGIN_PREFIX: &str = "supac-backend-";
+/// Bumped whenever a request or a response changes in a way that plugins
+/// written against the old one would misread
+const PROTOCOL_VERSION: u64 = 1;
+
+const DESCRIBE: &str = "describe";
+const LIST_INSTALLED: &str = "list_installed";
+const PLAN: &str = "plan";
+const APPLY: &str = "apply";
+const CLEAN_CACHE: &str = "clean_cache";
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const HOOK_KEY: &str = "post_hook";
+
+/// The answer to `describe`
+#[derive(Debug, Deserialize)]
+pub struct Description {
+    pub protocol: u64,
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+}
+
+/// The answer to `list_installed`
+#[derive(Debug, Deserialize)]
+struct Installed {
+    packages: Vec<String>,
+}
+
+/// The answer to `plan`
+#[derive(Debug, Deserialize)]
+struct Steps {
+    steps: Vec<PluginStep>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PluginStep {
+    action: Action,
+    packages: Vec<String>,
+}
+
+/// Sent to every command but `describe`, which only gets the config
+#[derive(Debug, Serialize)]
+struct Request<'a> {
+    config: &'a Json,
+    options: &'a Map<String, Json>,
+    packages: Vec<&'a Map<String, Json>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    no_confirm: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    step: Option<&'a PluginStep>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PluginOpts {
+    /// The package record as it is sent to the plugin, without the post hook
+    spec: Map<String, Json>,
+    post_hook: Option<Closure>,
+}
+
+/// A backend living in a `supac-backend-<name>` executable, spoken to with
+/// JSON over its stdin and stdout
+#[derive(Clone, Debug)]
+pub struct Plugin {
+    name: String,
+    executable: String,
+    config: Json,
+    options: Map<String, Json>,
+    packages: BTreeMap<String, PluginOpts>,
+}
+
+impl Plugin {
+    pub fn new(name: &str, executable: String, value: &Record, config: &Record) -> Result<Self> {
+        describe_plugin(&executable, config)?;
+
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for {name}"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The package list in {name} is not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        let options = value
+            .iter()
+            .filter(|(key, _)| *key != PACKAGE_LIST_KEY)
+            .map(|(key, option)| {
+                nu_to_json(option)
+                    .map(|option| (key.to_owned(), option))
+                    .map_err(|e| nest_errors!("Option {key} cannot be sent to {name}", e))
+            })
+            .collect::<Result<_>>()?;
+
+        let config = Json::Object(
+            record_to_json(config)
+                .map_err(|e| nest_errors!("The config cannot be sent to {name}", e))?,
+        );
+
+        log::info!("Successfully parsed {name} packages");
+        Ok(Plugin {
+            name: name.to_owned(),
+            executable,
+            config,
+            options,
+            packages,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed: Installed = self.send(LIST_INSTALLED, &self.request())?;
+
+        Ok(installed
+            .packages
+            .into_iter()
+            .filter(|package| !self.packages.contains_key(package))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect())
+    }
+
+    pub fn plan(&self, no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let steps: Steps = self.send(
+            PLAN,
+            &Request {
+                no_confirm: Some(no_confirm),
+                ..self.request()
+            },
+        )?;
+
+        let mut plan = Plan::default();
+
+        for step in &steps.steps {
+            let request = self.serialize(&Request {
+                step: Some(step),
+                ..self.request()
+            })?;
+
+            plan.push_call(
+                step.action,
+                step.packages.iter().map(String::as_str),
+                APPLY,
+                Call::Plugin {
+                    command: Box::new([self.executable.clone(), APPLY.to_owned()]),
+                    request,
+                },
+            );
+
+            if step.action == Action::Install {
+                step.packages
+                    .iter()
+                    .filter_map(|package| self.packages.get(package))
+                    .for_each(|opts| plan.push_hook(opts.post_hook.as_ref()));
+            }
+        }
+
+        Ok(plan)
+    }
+
+    pub fn update(&self, _opts: &UpdateCommand) -> Result<()> {
+        log::info!(
+            "Plugins have no way to update packages, nothing to do for {}",
+            self.name
+        );
+        Ok(())
+    }
+
+    pub fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                format!("Do you want to clean the cache for {}?", self.name),
+                [self.executable.as_str(), CLEAN_CACHE],
+            )?
+        {
+            return Ok(());
+        }
+
+        let request = self.serialize(&self.request())?;
+        let command = [self.executable.as_str(), CLEAN_CACHE];
+
+        if opts.dry_run {
+            dry_run_command_with_stdin(command, Perms::User, &request)
+        } else {
+            run_command_with_stdin(command, Perms::User, &request)
+                .map(|_| log::info!("Cleaned the cache for {}", self.name))
+                .map_err(|e| nest_errors!("Failed to clean cache", e))
+        }
+    }
+
+    pub fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn request(&self) -> Request<'_> {
+        Request {
+            config: &self.config,
+            options: &self.options,
+            packages: self.packages.values().map(|opts| &opts.spec).collect(),
+            no_confirm: None,
+            step: None,
+        }
+    }
+
+    fn serialize(&self, request: &Request) -> Result<String> {
+        let name = &self.name;
+
+        serde_json::to_string(request)
+            .map_err(|e| nest_errors!("Failed to serialize the request to {name}", e))
+    }
+
+    fn send<T: DeserializeOwned>(&self, command: &str, request: &Request) -> Result<T> {
+        exchange(&self.executable, command, &self.serialize(request)?)
+    }
+}
+
+/// Asks the plugin to describe itself, making sure it speaks the same
+/// protocol as supac
+pub fn describe_plugin(executable: &str, config: &Record) -> Result<Description> {
+    let config =
+        record_to_json(config).map_err(|e| nest_errors!("The config cannot be sent", e))?;
+
+    let description: Description = exchange(
+        executable,
+        DESCRIBE,
+        &json!({ "config": config }).to_string(),
+    )?;
+
+    if description.protocol == PROTOCOL_VERSION {
+        Ok(description)
+    } else {
+        Err(mod_err!(
+            "{executable} speaks version {} of the plugin protocol, supac speaks {PROTOCOL_VERSION}",
+            description.protocol
+        ))
+    }
+}
+
+/// Finds the plugin for a backend. Built in backends can not be replaced.
+pub fn find_plugin(name: &str) -> Option<String> {
+    if for_all_backends!(backend_names,).contains(&name) {
+        return None;
+    }
+
+    find_executable(&[PLUGIN_PREFIX, name].concat()).map(|path| path.to_string_lossy().into_owned())
+}
+
+/// Every plugin in PATH by backend name. The first one found wins, like it
+/// would when running it.
+pub fn find_plugins() -> BTreeMap<String, String> {
+    let mut plugins = BTreeMap::new();
+
+    let Some(paths) = env::var_os("PATH") else {
+        return plugins;
+    };
+
+    env::split_paths(&paths)
+        .filter_map(|dir| read_dir(dir).ok())
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix(PLUGIN_PREFIX)?
+                .to_owned();
+
+            Some((name, entry.path()))
+        })
+        .filter(|(name, path)| {
+            !name.is_empty()
+                && !for_all_backends!(backend_names,).contains(&name.as_str())
+                && path.is_file()
+        })
+        .for_each(|(name, path)| {
+            plugins
+                .entry(name)
+                .or_insert_with(|| path.to_string_lossy().into_owned());
+        });
+
+    plugins
+}
+
+/// Parses the declarations in the package spec that belong to plugins
+pub fn parse_plugins(packages: &Record, config: &Record) -> Result<Vec<Backends>> {
+    packages
+        .iter()
+        .filter_map(|(name, value)| Some((name, find_plugin(name)?, value.as_record().ok()?)))
+        .map(|(name, executable, value)| {
+            Plugin::new(name, executable, value, config)
+                .map(Backends::Plugin)
+                .map_err(|e| {
+                    log::error!("Error encountered in parsing {name} packages");
+                    mod_err!(e)
+                })
+        })
+        .collect()
+}
+
+pub fn validate_plugin(
+    name: &str,
+    executable: &str,
+    value: &Value,
+    config: &Record,
+) -> Vec<Diagnostic> {
+    let value = match value.as_record() {
+        Ok(value) => value,
+        Err(e) => return vec![Diagnostic::error(name, e)],
+    };
+
+    let source = [name, PACKAGE_LIST_KEY].join(".");
+
+    let mut diagnostics = match value.get(PACKAGE_LIST_KEY).map(Value::as_list) {
+        Some(Ok(packages)) => {
+            let mut diagnostics = Vec::new();
+            let mut parsed = Vec::new();
+
+            for (index, package) in packages.iter().enumerate() {
+                match value_to_pkgspec(package) {
+                    Ok((package, _)) => parsed.push(package),
+                    Err(e) => diagnostics.push(Diagnostic::error(format!("{source}[{index}]"), e)),
+                }
+            }
+
+            diagnostics.extend(duplicates(parsed.iter().map(String::as_str), &source));
+            diagnostics
+        }
+        Some(Err(e)) => vec![Diagnostic::error(source, e)],
+        None => vec![Diagnostic::error(
+            name,
+            format!("missing the `{PACKAGE_LIST_KEY}` list"),
+        )],
+    };
+
+    // Also asks the plugin to describe itself, so it is only worth running
+    // when the package list is fine
+    if diagnostics.is_empty()
+        && let Err(e) = Plugin::new(name, executable.to_owned(), value, config)
+    {
+        diagnostics.push(Diagnostic::error(name, e));
+    }
+
+    diagnostics
+}
+
+fn exchange<T: DeserializeOwned>(executable: &str, command: &str, request: &str) -> Result<T> {
+    let response = run_command_with_stdin([executable, command], Perms::User, request)
+        .map_err(|e| nest_errors!("{executable} failed to {command}", e))?;
+
+    serde_json::from_str(&response)
+        .map_err(|e| nest_errors!("Invalid response from {executable} to {command}", e))
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, PluginOpts)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The package-spec is not a record", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No package mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The package was not a string", e))?
+        .to_owned();
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {package} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    let spec = record
+        .iter()
+        .filter(|(key, _)| *key != HOOK_KEY)
+        .map(|(key, option)| {
+            nu_to_json(option)
+                .map(|option| (key.to_owned(), option))
+                .map_err(|e| nest_errors!("{key} of {package} cannot be sent to a plugin", e))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((package, PluginOpts { spec, post_hook }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{Permissions, remove_file, set_permissions, write};
+    use std::os::unix::fs::PermissionsExt;
+
+    use nu_protocol::{Id, Span};
+
+    use super::*;
+
+    #[test]
+    fn value_to_pkgspec_drops_hook() {
+        let closure = Closure {
+            block_id: Id::new(0),
+            captures: vec![],
+        };
+        let value = Value::record(
+            Record::from_raw_cols_vals(
+                [PACKAGE_KEY, "version", HOOK_KEY]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("black", Span::test_data()),
+                    Value::string("24.1", Span::test_data()),
+                    Value::closure(closure, Span::test_data()),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        let (package, opts) = value_to_pkgspec(&value).unwrap();
+        assert_eq!(package, "black");
+        assert!(opts.post_hook.is_some());
+        assert_eq!(
+            Json::Object(opts.spec),
+            json!({ "package": "black", "version": "24.1" })
+        );
+    }
+
+    #[test]
+    fn steps_use_plan_actions() {
+        let steps: Steps = serde_json::from_str(
+            r#"{"steps": [{"action": "mark-explicit", "packages": ["foo"]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(steps.steps[0].action, Action::MarkExplicit);
+
+        assert!(
+            serde_json::from_str::<Steps>(r#"{"steps": [{"action": "upgrade", "packages": []}]}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn describe_checks_protocol() {
+        let script = |name: &str, response: &str| {
+            let path =
+                env::temp_dir().join(format!("{PLUGIN_PREFIX}{name}-{}", std::process::id()));
+
+            write(
+                &path,
+                format!("#!/bin/sh\ncat > /dev/null\necho '{response}'\n"),
+            )
+            .unwrap();
+            set_permissions(&path, Permissions::from_mode(0o755)).unwrap();
+
+            path.to_string_lossy().into_owned()
+        };
+
+        let current = script(
+            "current",
+            r#"{"protocol": 1, "settings": {"index": "pypi"}}"#,
+        );
+        let description = describe_plugin(&current, &Record::new()).unwrap();
+        assert_eq!(description.settings["index"], "pypi");
+
+        let newer = script("newer", r#"{"protocol": 2}"#);
+        assert!(describe_plugin(&newer, &Record::new()).is_err());
+
+        [current, newer]
+            .iter()
+            .for_each(|path| remove_file(path).unwrap());
+    }
+}
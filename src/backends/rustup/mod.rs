@@ -4,10 +4,11 @@ use anyhow::{Result, anyhow};
 use nu_protocol::{Record, Value};
 
 use crate::{
-    CleanCacheCommand, CleanCommand, SyncCommand,
+    CleanCacheCommand, UpdateCommand,
     commands::{Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout},
     function, mod_err, nest_errors,
-    parser::Engine,
+    plan::{Action, Plan, Selection},
+    validate::{Diagnostic, duplicates, unknown_keys},
 };
 
 use super::Backend;
@@ -54,113 +55,163 @@ impl Backend for Rustup {
         Ok(Rustup { toolchains })
     }
 
-    fn install(&self, _engine: &mut Engine, opts: &SyncCommand) -> Result<()> {
+    fn unmanaged(&self) -> Result<Box<[String]>> {
         let installed_toolchains = get_installed_toolchains()?;
 
-        self.install_toolchains(installed_toolchains.as_ref(), opts)?;
-        self.install_missing(installed_toolchains.as_ref(), opts)?;
+        let mut unmanaged: Vec<_> = self
+            .get_extra_toolchains(installed_toolchains.as_ref())
+            .iter()
+            .map(|toolchain| toolchain.to_string())
+            .collect();
 
-        Ok(())
-    }
+        for toolchain in self.get_present_toolchains(installed_toolchains.as_ref()) {
+            let toolchain_spec = self.toolchains.get(toolchain).unwrap();
 
-    fn remove(&self, opts: &CleanCommand) -> Result<()> {
-        let installed_toolchains = get_installed_toolchains()?;
+            get_extra_targets(toolchain, &toolchain_spec.targets)?
+                .iter()
+                .for_each(|target| {
+                    unmanaged.push([toolchain, TARGET_LIST_KEY, target].join("/"));
+                });
 
-        self.remove_toolchains(installed_toolchains.as_ref(), opts)?;
-        self.remove_extra(installed_toolchains.as_ref(), opts)?;
+            get_extra_components(toolchain, &toolchain_spec.components)?
+                .iter()
+                .for_each(|component| {
+                    unmanaged.push([toolchain, COMPONENT_LIST_KEY, component].join("/"));
+                });
+        }
 
-        Ok(())
+        Ok(unmanaged.into())
     }
 
-    fn clean_cache(&self, _config: &Record, _opts: &CleanCacheCommand) -> Result<()> {
-        // Nothing to do here
-        Ok(())
-    }
-}
+    fn plan(&self, _no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let installed_toolchains = get_installed_toolchains()?;
 
-impl Rustup {
-    fn install_toolchains(
-        &self,
-        installed_toolchains: &[String],
-        opts: &SyncCommand,
-    ) -> Result<()> {
-        let configured_toolchains = self.toolchains.keys();
-
-        let missing_toolchains: Box<[_]> = configured_toolchains
-            .filter(|toolchain| {
+        let mut plan = Plan::default();
+
+        self.toolchains
+            .iter()
+            .filter(|(toolchain, _)| {
                 !installed_toolchains
                     .iter()
                     .any(|installed| installed.starts_with(*toolchain))
             })
-            .collect();
-
-        if missing_toolchains.is_empty() {
-            log::info!("No missing toolchains to install");
-            return Ok(());
-        }
-
-        if !opts.no_confirm
-            && !confirmation_prompt(
-                "Do you want to install the following toolchains for rustup?: ",
-                &missing_toolchains,
-            )?
-        {
-            return Ok(());
-        }
+            .for_each(|(toolchain, spec)| {
+                plan.push(
+                    Action::Install,
+                    [toolchain],
+                    install_toolchain_command(toolchain, spec),
+                    Perms::User,
+                );
+            });
+
+        for toolchain in self.get_present_toolchains(installed_toolchains.as_ref()) {
+            let spec = self.toolchains.get(toolchain).unwrap();
+
+            let installed_targets = get_installed_targets(toolchain)?;
+            let installed_components = get_installed_components(toolchain)?;
+
+            let missing_targets: Box<[_]> = spec
+                .targets
+                .iter()
+                .filter(|target| !installed_targets.contains(target))
+                .map(String::as_str)
+                .collect();
 
-        missing_toolchains
-            .into_iter()
-            .map(|toolchain| (toolchain, self.toolchains.get(toolchain).unwrap()))
-            .try_for_each(|(toolchain, spec)| install_missing_toolchain(toolchain, spec, opts))
-            .inspect(|_| log::info!("Successfully installed all the missing toolchains"))
-    }
+            let missing_components: Box<[_]> = spec
+                .components
+                .iter()
+                .map(String::as_str)
+                .chain(DEFAULT_COMPONENTS)
+                .filter(|component| {
+                    !installed_components
+                        .iter()
+                        .any(|comp| comp.starts_with(*component))
+                })
+                .collect();
+
+            let extra_targets: Box<[_]> = installed_targets
+                .iter()
+                .filter(|target| !spec.targets.contains(target))
+                .map(String::as_str)
+                .collect();
 
-    fn install_missing(&self, installed_toolchains: &[String], opts: &SyncCommand) -> Result<()> {
-        let mut configured_toolchains = installed_toolchains
-            .iter()
-            .filter_map(|toolchain| {
-                self.toolchains
-                    .keys()
-                    .find(|configured| toolchain.starts_with(*configured))
-            })
-            .peekable();
+            let extra_components: Box<[_]> = installed_components
+                .iter()
+                .filter(|component| {
+                    !spec
+                        .components
+                        .iter()
+                        .map(String::as_str)
+                        .chain(DEFAULT_COMPONENTS)
+                        .any(|comp| component.starts_with(comp))
+                })
+                .map(String::as_str)
+                .collect();
 
-        if configured_toolchains.peek().is_none() {
-            log::info!("No missing components or targets to install");
-            return Ok(());
+            let qualify = |kind: &str, names: &[&str]| -> Box<[String]> {
+                names
+                    .iter()
+                    .map(|name| [toolchain, kind, name].join("/"))
+                    .collect()
+            };
+
+            plan.push(
+                Action::Install,
+                qualify(TARGET_LIST_KEY, &missing_targets),
+                ["rustup", "target", "add", "--toolchain", toolchain]
+                    .into_iter()
+                    .chain(missing_targets.iter().copied()),
+                Perms::User,
+            );
+            plan.push(
+                Action::Install,
+                qualify(COMPONENT_LIST_KEY, &missing_components),
+                ["rustup", "component", "add", "--toolchain", toolchain]
+                    .into_iter()
+                    .chain(missing_components.iter().copied()),
+                Perms::User,
+            );
+            plan.push(
+                Action::Remove,
+                qualify(TARGET_LIST_KEY, &extra_targets),
+                ["rustup", "target", "remove", "--toolchain", toolchain]
+                    .into_iter()
+                    .chain(extra_targets.iter().copied()),
+                Perms::User,
+            );
+            plan.push(
+                Action::Remove,
+                qualify(COMPONENT_LIST_KEY, &extra_components),
+                ["rustup", "component", "remove", "--toolchain", toolchain]
+                    .into_iter()
+                    .chain(extra_components.iter().copied()),
+                Perms::User,
+            );
         }
 
-        for toolchain in configured_toolchains {
-            let toolchain_spec = self.toolchains.get(toolchain).unwrap();
-
-            install_missing_targets(toolchain, toolchain_spec.targets.as_ref(), opts)?;
-            install_missing_components(toolchain, toolchain_spec.components.as_ref(), opts)?;
-        }
+        let extra_toolchains = self.get_extra_toolchains(installed_toolchains.as_ref());
+        plan.push(
+            Action::Remove,
+            extra_toolchains.iter().copied(),
+            ["rustup", "toolchain", "remove"]
+                .into_iter()
+                .chain(extra_toolchains.iter().copied()),
+            Perms::User,
+        );
 
-        log::info!("Installed missing components and targets");
-        Ok(())
+        Ok(plan)
     }
 
-    fn remove_toolchains(
-        &self,
-        installed_toolchains: &[String],
-        opts: &CleanCommand,
-    ) -> Result<()> {
-        let configured_toolchains = &self.toolchains;
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let installed_toolchains = get_installed_toolchains()?;
 
-        let extra_toolchains: Box<[_]> = installed_toolchains
-            .iter()
-            .filter(|toolchain| {
-                !configured_toolchains
-                    .keys()
-                    .into_iter()
-                    .any(|configured| toolchain.starts_with(configured))
-            })
+        let toolchains: Box<[_]> = self
+            .get_present_toolchains(installed_toolchains.as_ref())
             .map(String::as_str)
             .collect();
 
-        if extra_toolchains.is_empty() {
-            log::info!("No extra toolchains to remove!");
+        if toolchains.is_empty() {
+            log::info!("No installed toolchains to update");
             return Ok(());
         }
 
@@ -172,52 +223,127 @@ impl Rustup {
 
         if opts.no_confirm
             || confirmation_prompt(
-                "Do you want to remove the following toolchains for rustup?: ",
-                &extra_toolchains,
+                "Do you want to update the following toolchains for rustup?: ",
+                &toolchains,
             )?
         {
             command_action(
-                ["rustup", "toolchain", "remove"]
-                    .into_iter()
-                    .chain(extra_toolchains),
+                ["rustup", "update"].into_iter().chain(toolchains),
                 Perms::User,
             )
-            .inspect(|_| log::info!("Successfully removed unused toolchains"))
-            .map_err(|e| nest_errors!("Failed to remove toolchains", e))
+            .inspect(|_| log::info!("Successfully updated toolchains"))
+            .map_err(|e| nest_errors!("Failed to update toolchains", e))
         } else {
             Ok(())
         }
     }
 
-    fn remove_extra(&self, installed_toolchains: &[String], opts: &CleanCommand) -> Result<()> {
-        let configured_toolchains = &self.toolchains;
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let mut diagnostics = unknown_keys(value, &[TOOLCHAIN_LIST_KEY], "Rustup");
 
-        let mut present_toolchains = installed_toolchains
-            .iter()
-            .flat_map(|toolchain| {
-                configured_toolchains
-                    .keys()
-                    .find(|configured| toolchain.starts_with(*configured))
-            })
-            .peekable();
+        let toolchains = match value.get(TOOLCHAIN_LIST_KEY).map(Value::as_record) {
+            Some(Ok(toolchains)) => toolchains,
+            Some(Err(e)) => {
+                diagnostics.push(Diagnostic::error("Rustup.toolchains", e));
+                return diagnostics;
+            }
+            None => return diagnostics,
+        };
 
-        if present_toolchains.peek().is_none() {
-            log::info!("No extra components or targets to remove");
-            return Ok(());
+        for (toolchain, spec) in toolchains.iter() {
+            let source = ["Rustup", TOOLCHAIN_LIST_KEY, toolchain].join(".");
+
+            if let Ok(record) = spec.as_record() {
+                diagnostics.extend(unknown_keys(
+                    record,
+                    &[COMPONENT_LIST_KEY, TARGET_LIST_KEY],
+                    &source,
+                ));
+
+                if let Some(Ok(targets)) = record.get(TARGET_LIST_KEY).map(Value::as_list) {
+                    targets
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(index, target)| Some((index, target.as_record().ok()?)))
+                        .for_each(|(index, target)| {
+                            diagnostics.extend(unknown_keys(
+                                target,
+                                &[ARCH_KEY, VENDOR_KEY, OS_KEY],
+                                &format!("{source}.{TARGET_LIST_KEY}[{index}]"),
+                            ));
+                        });
+                }
+            }
+
+            match value_to_toolchainspec(toolchain, spec) {
+                Ok(spec) => {
+                    diagnostics.extend(duplicates(
+                        spec.components.iter().map(String::as_str),
+                        &[source.as_str(), COMPONENT_LIST_KEY].join("."),
+                    ));
+                    diagnostics.extend(duplicates(
+                        spec.targets.iter().map(String::as_str),
+                        &[source.as_str(), TARGET_LIST_KEY].join("."),
+                    ));
+                }
+                Err(e) => diagnostics.push(Diagnostic::error(source, e)),
+            }
         }
 
-        for toolchain in present_toolchains {
-            let toolchain_spec = self.toolchains.get(toolchain).unwrap();
+        diagnostics
+    }
 
-            remove_extra_targets(toolchain, &toolchain_spec.targets, opts)?;
-            remove_extra_components(toolchain, &toolchain_spec.components, opts)?;
-        }
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("rustup".to_owned())
+    }
+
+    /// Counts every toolchain, target and component
+    fn package_count(&self) -> usize {
+        self.toolchains
+            .values()
+            .map(|spec| 1 + spec.targets.len() + spec.components.len())
+            .sum()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
 
-        log::info!("Removed extra components and targets");
+    fn clean_cache(&self, _config: &Record, _opts: &CleanCacheCommand) -> Result<()> {
+        // Nothing to do here
         Ok(())
     }
 }
 
+impl Rustup {
+    fn get_extra_toolchains<'a>(&self, installed_toolchains: &'a [String]) -> Box<[&'a str]> {
+        let configured_toolchains = &self.toolchains;
+
+        installed_toolchains
+            .iter()
+            .filter(|toolchain| {
+                !configured_toolchains
+                    .keys()
+                    .any(|configured| toolchain.starts_with(configured))
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn get_present_toolchains<'a>(
+        &'a self,
+        installed_toolchains: &'a [String],
+    ) -> impl Iterator<Item = &'a String> {
+        let configured_toolchains = &self.toolchains;
+
+        installed_toolchains.iter().flat_map(|toolchain| {
+            configured_toolchains
+                .keys()
+                .find(|configured| toolchain.starts_with(*configured))
+        })
+    }
+}
+
 fn values_to_pkgspec(record: &Record) -> Result<HashMap<String, ToolchainSpec>> {
     record
         .iter()
@@ -243,11 +369,10 @@ fn get_installed_toolchains() -> Result<Box<[String]>> {
     Ok(toolchains)
 }
 
-fn install_missing_toolchain(
-    toolchain: &str,
-    toolchain_spec: &ToolchainSpec,
-    opts: &SyncCommand,
-) -> Result<()> {
+fn install_toolchain_command<'a>(
+    toolchain: &'a str,
+    toolchain_spec: &'a ToolchainSpec,
+) -> Vec<&'a str> {
     let components = Some(
         ["--component"]
             .into_iter()
@@ -266,170 +391,32 @@ fn install_missing_toolchain(
     .filter(|_| !toolchain_spec.targets.is_empty())
     .flatten();
 
-    let command_action = if opts.dry_run {
-        dry_run_command
-    } else {
-        run_command
-    };
-
-    command_action(
-        ["rustup", "toolchain", "install", toolchain]
-            .into_iter()
-            .chain(components)
-            .chain(targets),
-        Perms::User,
-    )
-    .inspect(|_| log::debug!("Successfully installed missing toolchain {toolchain}"))
-    .map_err(|e| nest_errors!("Failed to install toolchain {toolchain}", e))
-}
-
-fn install_missing_targets(
-    toolchain: &String,
-    configured_targets: &[String],
-    opts: &SyncCommand,
-) -> Result<()> {
-    let installed_targets = get_installed_targets(toolchain)?;
-
-    let missing_targets: Box<[_]> = configured_targets
-        .iter()
-        .filter(|target| !installed_targets.contains(target))
-        .map(String::as_str)
-        .collect();
-
-    if missing_targets.is_empty() {
-        log::debug!("No missing targets to install for {toolchain}");
-        return Ok(());
-    }
-
-    let command_action = if opts.dry_run {
-        dry_run_command
-    } else {
-        run_command
-    };
-
-    if opts.no_confirm
-        || confirmation_prompt(
-            "Do you want to install the following targets for ".to_string()
-                + toolchain
-                + "? (Rustup): ",
-            &missing_targets,
-        )?
-    {
-        command_action(
-            ["rustup", "target", "add", "--toolchain", toolchain]
-                .into_iter()
-                .chain(missing_targets),
-            Perms::User,
-        )
-        .map_err(|e| nest_errors!("Failed to add targets for {toolchain}", e))
-    } else {
-        Ok(())
-    }
-}
-
-fn install_missing_components(
-    toolchain: &String,
-    configured_components: &[String],
-    opts: &SyncCommand,
-) -> Result<()> {
-    let installed_components = get_installed_components(toolchain)?;
-
-    let missing_components: Box<[_]> = configured_components
-        .iter()
-        .map(String::as_str)
-        .chain(DEFAULT_COMPONENTS)
-        .filter(|component| {
-            !installed_components
-                .iter()
-                .any(|comp| comp.starts_with(*component))
-        })
-        .collect();
-
-    if missing_components.is_empty() {
-        log::debug!("No components left to install for {toolchain}");
-        return Ok(());
-    }
-
-    let command_action = if opts.dry_run {
-        dry_run_command
-    } else {
-        run_command
-    };
-
-    if opts.no_confirm
-        || confirmation_prompt(
-            "Do you want to install the following components for ".to_string()
-                + toolchain
-                + "? (Rustup): ",
-            &missing_components,
-        )?
-    {
-        command_action(
-            ["rustup", "component", "add", "--toolchain", toolchain]
-                .into_iter()
-                .chain(missing_components),
-            Perms::User,
-        )
-        .map_err(|e| nest_errors!("Failed to add components for {toolchain}", e))
-    } else {
-        Ok(())
-    }
+    ["rustup", "toolchain", "install", toolchain]
+        .into_iter()
+        .chain(components)
+        .chain(targets)
+        .collect()
 }
 
-fn remove_extra_targets(
-    toolchain: &str,
-    configured_targets: &[String],
-    opts: &CleanCommand,
-) -> Result<()> {
+fn get_extra_targets(toolchain: &str, configured_targets: &[String]) -> Result<Box<[String]>> {
     let installed_targets = get_installed_targets(toolchain)?;
 
-    let extra_targets: Box<[_]> = installed_targets
-        .iter()
+    let extra_targets = installed_targets
+        .into_iter()
         .filter(|target| !configured_targets.contains(target))
-        .map(String::as_str)
         .collect();
 
-    if extra_targets.is_empty() {
-        log::debug!("No extra targets to remove for {toolchain}!");
-        return Ok(());
-    }
-
-    let command_action = if opts.dry_run {
-        dry_run_command
-    } else {
-        run_command
-    };
-
-    if opts.no_confirm
-        || confirmation_prompt(
-            "Do you want to remove the following targets for ".to_string()
-                + toolchain
-                + "? (Rustup): ",
-            &extra_targets,
-        )?
-    {
-        command_action(
-            ["rustup", "target", "remove", "--toolchain", toolchain]
-                .into_iter()
-                .chain(extra_targets),
-            Perms::User,
-        )
-        .inspect(|_| log::debug!("Remove extra targets for {toolchain}"))
-        .map_err(|e| nest_errors!("Failed to remove unused targets for {toolchain}", e))
-    } else {
-        Ok(())
-    }
+    Ok(extra_targets)
 }
 
-fn remove_extra_components(
+fn get_extra_components(
     toolchain: &str,
     configured_components: &[String],
-    opts: &CleanCommand,
-) -> Result<()> {
+) -> Result<Box<[String]>> {
     let installed_components = get_installed_components(toolchain)?;
 
-    let extra_components: Box<[_]> = installed_components
-        .iter()
+    let extra_components = installed_components
+        .into_iter()
         .filter(|component| {
             !configured_components
                 .iter()
@@ -437,44 +424,9 @@ fn remove_extra_components(
                 .chain(DEFAULT_COMPONENTS)
                 .any(|comp| component.starts_with(comp))
         })
-        .map(String::as_str)
         .collect();
 
-    if extra_components.is_empty() {
-        log::debug!("No extra components to remove for {toolchain}!");
-        return Ok(());
-    }
-
-    let command_action = if opts.dry_run {
-        dry_run_command
-    } else {
-        run_command
-    };
-
-    if opts.no_confirm
-        || confirmation_prompt(
-            "Do you want to install the following components for ".to_string()
-                + toolchain
-                + "? (Rustup): ",
-            &extra_components,
-        )?
-    {
-        command_action(
-            ["rustup", "component", "remove", "--toolchain", toolchain]
-                .into_iter()
-                .chain(extra_components),
-            Perms::User,
-        )
-        .inspect(|_| log::debug!("Removed extra components for {toolchain}"))
-        .map_err(|e| {
-            nest_errors!(
-                "rustup command to remove components for {toolchain} failed!",
-                e
-            )
-        })
-    } else {
-        Ok(())
-    }
+    Ok(extra_components)
 }
 
 fn value_to_toolchainspec(toolchain: &str, value: &Value) -> Result<ToolchainSpec> {
@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const CHANNEL_KEY: &str = "channel";
+const CLASSIC_KEY: &str = "classic";
+const HOOK_KEY: &str = "post_hook";
+
+/// Content snaps only ever installed as the runtime of other snaps. Bases
+/// and snapd are recognised from their notes instead.
+const CONTENT_SNAPS: [&str; 1] = ["gtk-common-themes"];
+const CONTENT_SNAP_PREFIXES: [&str; 3] = ["gnome-3-", "gnome-4", "kde-frameworks-"];
+
+#[derive(Clone, Debug)]
+pub struct SnapOpts {
+    channel: Option<String>,
+    classic: bool,
+    post_hook: Option<Closure>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Installed {
+    revision: String,
+    tracking: String,
+    notes: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Snap {
+    packages: HashMap<String, SnapOpts>,
+}
+
+impl Backend for Snap {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for Snap"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The package list in Snap is not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed snap packages");
+        Ok(Snap { packages })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed = get_installed_snaps(false)?;
+
+        Ok(self
+            .get_extra_snaps(&installed)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let installed = get_installed_snaps(false)?;
+        let lookup: HashMap<_, _> = installed
+            .iter()
+            .map(|(name, snap)| (name.as_str(), snap))
+            .collect();
+
+        let mut plan = Plan::default();
+
+        for (name, spec) in self.packages.iter().collect::<BTreeMap<_, _>>() {
+            let channel = spec
+                .channel
+                .as_ref()
+                .map(|channel| ["--channel=", channel].concat());
+
+            match lookup.get(name.as_str()) {
+                None => {
+                    plan.push(
+                        Action::Install,
+                        [name],
+                        ["snap", "install"]
+                            .into_iter()
+                            .chain(channel.as_deref())
+                            .chain(["--classic"].into_iter().filter(|_| spec.classic))
+                            .chain([name.as_str()]),
+                        Perms::Root,
+                    );
+                    plan.push_hook(spec.post_hook.as_ref());
+                }
+                Some(snap) if !tracks_channel(snap, spec) => {
+                    plan.push(
+                        Action::Install,
+                        [name],
+                        ["snap", "refresh"]
+                            .into_iter()
+                            .chain(channel.as_deref())
+                            .chain([name.as_str()]),
+                        Perms::Root,
+                    );
+                }
+                Some(_) => {}
+            }
+        }
+
+        let extra = self.get_extra_snaps(&installed);
+
+        plan.push(
+            Action::Remove,
+            extra.iter().copied(),
+            ["snap", "remove"].into_iter().chain(extra.iter().copied()),
+            Perms::Root,
+        );
+
+        Ok(plan)
+    }
+
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        command_action(["snap", "refresh"], Perms::Root)
+            .inspect(|_| log::info!("Successfully refreshed snaps"))
+            .map_err(|e| nest_errors!("Failed to refresh snaps", e))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Snap",
+            &[PACKAGE_KEY, CHANNEL_KEY, CLASSIC_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "Snap")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("snap".to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    // snapd keeps the previous revisions of every snap around for rollbacks,
+    // and those are what take up the space
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let disabled: Box<[_]> = get_installed_snaps(true)?
+            .into_iter()
+            .filter(|(_, snap)| snap.notes.split(',').any(|note| note == "disabled"))
+            .collect();
+
+        if disabled.is_empty() {
+            log::info!("No disabled revisions to remove");
+            return Ok(());
+        }
+
+        let revisions: Box<[_]> = disabled
+            .iter()
+            .map(|(name, snap)| format!("{name} (revision {})", snap.revision))
+            .collect();
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to remove these disabled revisions?",
+                &revisions,
+            )?
+        {
+            return Ok(());
+        }
+
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        disabled
+            .iter()
+            .try_for_each(|(name, snap)| {
+                command_action(
+                    [
+                        "snap".to_owned(),
+                        "remove".to_owned(),
+                        name.to_owned(),
+                        ["--revision=", &snap.revision].concat(),
+                    ],
+                    Perms::Root,
+                )
+                .map_err(|e| nest_errors!("Failed to remove a revision of {name}", e))
+            })
+            .inspect(|_| log::info!("Removed disabled snap revisions"))
+    }
+}
+
+impl Snap {
+    fn get_extra_snaps<'a>(&self, installed: &'a [(String, Installed)]) -> Box<[&'a str]> {
+        installed
+            .iter()
+            .filter(|(name, snap)| !self.packages.contains_key(name) && !is_base(name, snap))
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+}
+
+fn is_base(name: &str, snap: &Installed) -> bool {
+    CONTENT_SNAPS.contains(&name)
+        || CONTENT_SNAP_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        || snap
+            .notes
+            .split(',')
+            .any(|note| ["base", "core", "snapd"].contains(&note))
+}
+
+// Channels without a track implicitly follow `latest`
+fn tracks_channel(snap: &Installed, spec: &SnapOpts) -> bool {
+    match &spec.channel {
+        Some(channel) if channel.contains('/') => snap.tracking == *channel,
+        Some(channel) => snap.tracking == ["latest/", channel].concat(),
+        None => true,
+    }
+}
+
+fn get_installed_snaps(all: bool) -> Result<Box<[(String, Installed)]>> {
+    let snaps = run_command_for_stdout(
+        ["snap", "list"]
+            .into_iter()
+            .chain(["--all"].into_iter().filter(|_| all)),
+        Perms::User,
+        true,
+    )
+    .map_err(|e| nest_errors!("Failed to get installed snaps", e))?;
+
+    Ok(parse_snap_list(&snaps))
+}
+
+// `snap list` prints a header followed by the columns
+// `Name Version Rev Tracking Publisher Notes`, none of which contain spaces
+fn parse_snap_list(stdout: &str) -> Box<[(String, Installed)]> {
+    stdout
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Box<[_]> = line.split_whitespace().collect();
+            let [name, _, revision, tracking, _, notes] = *fields else {
+                return None;
+            };
+
+            Some((
+                name.to_owned(),
+                Installed {
+                    revision: revision.to_owned(),
+                    tracking: tracking.to_owned(),
+                    notes: notes.to_owned(),
+                },
+            ))
+        })
+        .collect()
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, SnapOpts)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The package-spec is not a record", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No package mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The package was not a string", e))?
+        .to_owned();
+
+    let channel = match record.get(CHANNEL_KEY) {
+        Some(channel) => Some(
+            channel
+                .as_str()
+                .map_err(|e| nest_errors!("Channel for {package} is not a string", e))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let classic = match record.get(CLASSIC_KEY) {
+        Some(classic) => classic
+            .as_bool()
+            .map_err(|e| nest_errors!("classic in {package} is not a boolean", e))?,
+        None => {
+            log::debug!("classic not specified in {package}, defaulting to false");
+            false
+        }
+    };
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {package} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((
+        package,
+        SnapOpts {
+            channel,
+            classic,
+            post_hook,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    const SNAP_LIST: &str = concat!(
+        "Name                Version          Rev    Tracking         Publisher   Notes\n",
+        "bare                1.0              5      latest/stable    canonical✓  base\n",
+        "core22              20240111         1122   latest/stable    canonical✓  base\n",
+        "firefox             122.0-2          3728   latest/stable    mozilla✓    -\n",
+        "firefox             121.0-1          3600   latest/stable    mozilla✓    disabled\n",
+        "gnome-42-2204       0+git.510a601    176    latest/stable/…  canonical✓  -\n",
+        "nvim                v0.9.5           2888   latest/edge      neovim-snap classic\n",
+        "snapd               2.61.1           20671  latest/stable    canonical✓  snapd\n",
+    );
+
+    #[test]
+    fn value_to_pkgspec_classic() {
+        let record = Record::from_raw_cols_vals(
+            ["package", "channel", "classic"]
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+            vec![
+                Value::string("nvim", Span::test_data()),
+                Value::string("edge", Span::test_data()),
+                Value::bool(true, Span::test_data()),
+            ],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let (package, spec) = value_to_pkgspec(&Value::record(record, Span::test_data())).unwrap();
+        assert_eq!(package, "nvim");
+        assert_eq!(spec.channel.as_deref(), Some("edge"));
+        assert!(spec.classic);
+    }
+
+    #[test]
+    fn parse_snap_list_columns() {
+        let snaps = parse_snap_list(SNAP_LIST);
+
+        assert_eq!(snaps.len(), 7);
+        assert_eq!(snaps[5].0, "nvim");
+        assert_eq!(snaps[5].1.tracking, "latest/edge");
+        assert_eq!(snaps[3].1.notes, "disabled");
+    }
+
+    #[test]
+    fn extra_snaps_skip_bases() {
+        let snap = Snap {
+            packages: HashMap::from([(
+                "nvim".to_owned(),
+                SnapOpts {
+                    channel: None,
+                    classic: false,
+                    post_hook: None,
+                },
+            )]),
+        };
+
+        let snaps: Box<[_]> = parse_snap_list(SNAP_LIST)
+            .into_iter()
+            .filter(|(_, installed)| installed.notes != "disabled")
+            .collect();
+        assert_eq!(*snap.get_extra_snaps(&snaps), ["firefox"]);
+    }
+
+    #[test]
+    fn is_base_only_content_snaps() {
+        let snap = |notes: &str| Installed {
+            revision: "1".to_owned(),
+            tracking: "latest/stable".to_owned(),
+            notes: notes.to_owned(),
+        };
+
+        assert!(is_base("core", &snap("core")));
+        assert!(is_base("core24", &snap("base")));
+        assert!(is_base("gnome-3-38-2004", &snap("-")));
+        assert!(is_base("gnome-46-2404", &snap("-")));
+        assert!(is_base("gtk-common-themes", &snap("-")));
+        assert!(!is_base("gnome-calculator", &snap("-")));
+        assert!(!is_base("gnome-system-monitor", &snap("-")));
+        assert!(!is_base("corectrl", &snap("-")));
+    }
+
+    #[test]
+    fn tracks_channel_implicit_track() {
+        let spec = |channel: Option<&str>| SnapOpts {
+            channel: channel.map(ToOwned::to_owned),
+            classic: false,
+            post_hook: None,
+        };
+        let (_, nvim) = &parse_snap_list(SNAP_LIST)[5];
+
+        assert!(tracks_channel(nvim, &spec(None)));
+        assert!(tracks_channel(nvim, &spec(Some("edge"))));
+        assert!(tracks_channel(nvim, &spec(Some("latest/edge"))));
+        assert!(!tracks_channel(nvim, &spec(Some("stable"))));
+    }
+}
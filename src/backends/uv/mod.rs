@@ -0,0 +1,554 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const PYTHON_KEY: &str = "python";
+const EXTRAS_KEY: &str = "extras";
+const WITH_KEY: &str = "with";
+const GIT_KEY: &str = "git";
+const PATH_KEY: &str = "path";
+const HOOK_KEY: &str = "post_hook";
+
+#[derive(Clone, Debug)]
+pub struct UvOpts {
+    python: Option<String>,
+    extras: Box<[String]>,
+    with: Box<[String]>,
+    source: Option<Source>,
+    post_hook: Option<Closure>,
+}
+
+#[derive(Clone, Debug)]
+enum Source {
+    Git(String),
+    /// Relative paths are resolved against the config dir
+    Path(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Uv {
+    packages: HashMap<String, UvOpts>,
+    /// Set after parsing with [`Uv::set_config_dir`]
+    config_dir: Option<PathBuf>,
+}
+
+impl Backend for Uv {
+    fn new(value: &Record, _config: &Record) -> Result<Self> {
+        let packages = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get packages for Uv"))?
+            .as_list()
+            .map_err(|e| nest_errors!("Packages not a list for Uv", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Parsed uv packages from spec");
+
+        Ok(Uv {
+            packages,
+            config_dir: None,
+        })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed = get_installed_tools()?;
+
+        Ok(self
+            .get_extra_tools(&installed)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let installed = get_installed_tools()?;
+
+        let mut plan = Plan::default();
+
+        let missing: BTreeMap<_, _> = self
+            .packages
+            .iter()
+            .filter(|(name, _)| !installed.contains(&normalize(name)))
+            .collect();
+
+        for (name, spec) in missing {
+            plan.push(
+                Action::Install,
+                [name],
+                install_command(name, spec, self.config_dir.as_deref())?,
+                Perms::User,
+            );
+            plan.push_hook(spec.post_hook.as_ref());
+        }
+
+        self.get_extra_tools(&installed)
+            .into_iter()
+            .for_each(|tool| {
+                plan.push(
+                    Action::Remove,
+                    [tool],
+                    ["uv", "tool", "uninstall", tool],
+                    Perms::User,
+                );
+            });
+
+        Ok(plan)
+    }
+
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let installed = get_installed_tools()?;
+
+        let installed_tools: BTreeSet<_> = self
+            .packages
+            .keys()
+            .filter(|name| installed.contains(&normalize(name)))
+            .collect();
+
+        if installed_tools.is_empty() {
+            log::info!("No installed tools to update");
+            return Ok(());
+        }
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to update the following tools for uv?: ",
+                &installed_tools,
+            )?
+        {
+            return Ok(());
+        }
+
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        command_action(
+            ["uv", "tool", "upgrade"]
+                .into_iter()
+                .chain(installed_tools.into_iter().map(String::as_str)),
+            Perms::User,
+        )
+        .inspect(|_| log::info!("Successfully updated uv tools"))
+        .map_err(|e| nest_errors!("Failed to update uv tools", e))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "Uv",
+            &[
+                PACKAGE_KEY,
+                PYTHON_KEY,
+                EXTRAS_KEY,
+                WITH_KEY,
+                GIT_KEY,
+                PATH_KEY,
+                HOOK_KEY,
+            ],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "Uv")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(_config: &Record) -> Result<String> {
+        Ok("uv".to_owned())
+    }
+
+    fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    fn settings(_config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        Ok(Box::new([]))
+    }
+
+    fn clean_cache(&self, _config: &Record, opts: &CleanCacheCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to clean uv cache?",
+                ["Removing unused cache entries"],
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(["uv", "cache", "prune"], Perms::User)
+            .inspect(|_| log::info!("Pruned uv's cache"))
+            .map_err(|e| nest_errors!("Failed to prune cache", e))
+    }
+}
+
+impl Uv {
+    pub fn set_config_dir(&mut self, config_dir: &Path) {
+        self.config_dir = Some(config_dir.to_owned());
+    }
+
+    fn get_extra_tools<'a>(&self, installed: &'a BTreeSet<String>) -> BTreeSet<&'a str> {
+        let configured: BTreeSet<_> = self.packages.keys().map(|name| normalize(name)).collect();
+
+        installed
+            .iter()
+            .filter(|tool| !configured.contains(*tool))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+fn get_installed_tools() -> Result<BTreeSet<String>> {
+    let stdout = run_command_for_stdout(["uv", "tool", "list"], Perms::User, true)
+        .map_err(|e| nest_errors!("Failed to list uv tools", e))?;
+
+    Ok(parse_tool_list(&stdout))
+}
+
+// Each tool is printed as `name vX.Y.Z`, followed by its executables as
+// `- exe` lines
+fn parse_tool_list(stdout: &str) -> BTreeSet<String> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?;
+            let version = fields.next()?;
+
+            version.starts_with('v').then(|| normalize(name))
+        })
+        .collect()
+}
+
+// uv reports tools under their normalized distribution name
+fn normalize(name: &str) -> String {
+    name.to_lowercase().replace(['_', '.'], "-")
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, UvOpts)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("Failed to parse value", e))?;
+
+    let package = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No package mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("Package name in record is not a string", e))?
+        .to_owned();
+
+    let python = match record.get(PYTHON_KEY) {
+        Some(python) => Some(
+            python
+                .as_str()
+                .map_err(|e| nest_errors!("python version for {package} is not a string", e))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let extras = get_string_list(record, EXTRAS_KEY, &package)?;
+    let with = get_string_list(record, WITH_KEY, &package)?;
+
+    let git = match record.get(GIT_KEY) {
+        Some(git) => Some(
+            git.as_str()
+                .map_err(|e| nest_errors!("git source for {package} is not a string", e))?,
+        ),
+        None => None,
+    };
+
+    let path = match record.get(PATH_KEY) {
+        Some(path) => Some(
+            path.as_str()
+                .map_err(|e| nest_errors!("path source for {package} is not a string", e))?,
+        ),
+        None => None,
+    };
+
+    let source = match (git, path) {
+        (Some(_), Some(_)) => {
+            return Err(mod_err!("{package} has both a git and a path source"));
+        }
+        (Some(git), None) if git.starts_with("git+") => Some(Source::Git(git.to_owned())),
+        (Some(git), None) => Some(Source::Git("git+".to_owned() + git)),
+        (None, Some(path)) => Some(Source::Path(path.to_owned())),
+        (None, None) => None,
+    };
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(closure) => {
+            let closure = closure
+                .as_closure()
+                .map_err(|e| nest_errors!("closure for {package} not a closure", e))?;
+
+            Some(closure.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((
+        package,
+        UvOpts {
+            python,
+            extras,
+            with,
+            source,
+            post_hook,
+        },
+    ))
+}
+
+fn get_string_list(record: &Record, key: &str, package: &str) -> Result<Box<[String]>> {
+    match record.get(key) {
+        Some(list) => list
+            .as_list()
+            .map_err(|e| nest_errors!("{key} in {package} is not a list", e))?
+            .iter()
+            .map(|elem| {
+                elem.as_str()
+                    .map(ToOwned::to_owned)
+                    .map_err(|e| nest_errors!("Element in {package} {key} not a string", e))
+            })
+            .collect(),
+        None => Ok(Box::new([])),
+    }
+}
+
+// A direct reference only takes urls, so local paths are turned into
+// absolute `file://` ones
+fn requirement(name: &str, spec: &UvOpts, config_dir: Option<&Path>) -> Result<String> {
+    let extras = if spec.extras.is_empty() {
+        String::new()
+    } else {
+        ["[", spec.extras.join(",").as_str(), "]"].concat()
+    };
+
+    let url = match &spec.source {
+        Some(Source::Git(git)) => git.to_owned(),
+        Some(Source::Path(path)) if Path::new(path).is_absolute() => ["file://", path].concat(),
+        Some(Source::Path(path)) => {
+            let config_dir =
+                config_dir.ok_or_else(|| mod_err!("No config dir to find {path} in"))?;
+            let path: PathBuf = config_dir.join(path).components().collect();
+
+            ["file://", path.to_string_lossy().as_ref()].concat()
+        }
+        None => return Ok([name, extras.as_str()].concat()),
+    };
+
+    Ok([name, extras.as_str(), " @ ", url.as_str()].concat())
+}
+
+fn install_command(name: &str, spec: &UvOpts, config_dir: Option<&Path>) -> Result<Vec<String>> {
+    let python = ["--python"]
+        .into_iter()
+        .chain(spec.python.as_deref())
+        .filter(|_| spec.python.is_some());
+
+    let with = spec
+        .with
+        .iter()
+        .flat_map(|package| ["--with", package.as_str()]);
+
+    Ok(["uv", "tool", "install"]
+        .into_iter()
+        .chain(python)
+        .chain(with)
+        .map(ToOwned::to_owned)
+        .chain([requirement(name, spec, config_dir)?])
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    #[test]
+    fn uv_backend_ok() {
+        let package = Value::record(
+            Record::from_raw_cols_vals(
+                ["package"].into_iter().map(ToOwned::to_owned).collect(),
+                vec![Value::string("ruff", Span::test_data())],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+        let value = Value::record(
+            Record::from_raw_cols_vals(
+                ["packages"].into_iter().map(ToOwned::to_owned).collect(),
+                vec![Value::list(vec![package], Span::test_data())],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        let uv = Uv::new(value.as_record().unwrap(), &Record::new());
+        assert!(uv.is_ok());
+        assert!(uv.unwrap().packages.contains_key("ruff"));
+    }
+
+    #[test]
+    fn value_to_pkgspec_full() {
+        let value = Value::record(
+            Record::from_raw_cols_vals(
+                ["package", "python", "extras", "with", "git"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("black", Span::test_data()),
+                    Value::string("3.12", Span::test_data()),
+                    Value::list(
+                        vec![
+                            Value::string("d", Span::test_data()),
+                            Value::string("jupyter", Span::test_data()),
+                        ],
+                        Span::test_data(),
+                    ),
+                    Value::list(
+                        vec![Value::string("tomli", Span::test_data())],
+                        Span::test_data(),
+                    ),
+                    Value::string("https://github.com/psf/black", Span::test_data()),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        let (name, spec) = value_to_pkgspec(&value).unwrap();
+        assert_eq!(name, "black");
+        assert_eq!(
+            install_command(&name, &spec, None).unwrap(),
+            [
+                "uv",
+                "tool",
+                "install",
+                "--python",
+                "3.12",
+                "--with",
+                "tomli",
+                "black[d,jupyter] @ git+https://github.com/psf/black",
+            ]
+        );
+    }
+
+    #[test]
+    fn value_to_pkgspec_no_opts() {
+        let value = Value::record(
+            Record::from_raw_cols_vals(
+                ["package"].into_iter().map(ToOwned::to_owned).collect(),
+                vec![Value::string("ruff", Span::test_data())],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        let (name, spec) = value_to_pkgspec(&value).unwrap();
+        assert_eq!(
+            install_command(&name, &spec, None).unwrap(),
+            ["uv", "tool", "install", "ruff"]
+        );
+        assert!(spec.post_hook.is_none());
+    }
+
+    #[test]
+    fn value_to_pkgspec_path_source() {
+        let value = Value::record(
+            Record::from_raw_cols_vals(
+                ["package", "path"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("foo", Span::test_data()),
+                    Value::string("./tools/foo", Span::test_data()),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        let (name, spec) = value_to_pkgspec(&value).unwrap();
+        assert_eq!(
+            install_command(&name, &spec, Some(Path::new("/home/user/.config/supac"))).unwrap(),
+            [
+                "uv",
+                "tool",
+                "install",
+                "foo @ file:///home/user/.config/supac/tools/foo",
+            ]
+        );
+        assert!(install_command(&name, &spec, None).is_err());
+    }
+
+    #[test]
+    fn value_to_pkgspec_two_sources() {
+        let value = Value::record(
+            Record::from_raw_cols_vals(
+                ["package", "git", "path"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("foo", Span::test_data()),
+                    Value::string("https://example.com/foo", Span::test_data()),
+                    Value::string("./foo", Span::test_data()),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        assert!(value_to_pkgspec(&value).is_err());
+    }
+
+    #[test]
+    fn parse_tool_list_skips_executables() {
+        let stdout = "black v24.2.0\n- black\n- blackd\nMarkdown_It v3.0.0\n- markdown-it\n";
+
+        let tools = parse_tool_list(stdout);
+        assert_eq!(
+            tools.into_iter().collect::<Vec<_>>(),
+            ["black", "markdown-it"]
+        );
+    }
+}
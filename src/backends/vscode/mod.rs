@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value, engine::Closure};
+
+use crate::commands::{
+    Perms, confirmation_prompt, dry_run_command, run_command, run_command_for_stdout,
+};
+use crate::config::{DEFAULT_VSCODE_BINARY, VSCODE_BINARY_KEY};
+use crate::plan::{Action, Plan, Selection};
+use crate::validate::{Diagnostic, check_records, unknown_keys};
+use crate::{CleanCacheCommand, UpdateCommand, function, mod_err, nest_errors};
+
+use super::Backend;
+
+const PACKAGE_LIST_KEY: &str = "packages";
+const PACKAGE_KEY: &str = "package";
+const VERSION_KEY: &str = "version";
+const HOOK_KEY: &str = "post_hook";
+
+const BINARIES: [&str; 3] = ["code", "codium", "code-insiders"];
+
+#[derive(Clone, Debug)]
+pub struct VsCodeOpts {
+    version: Option<String>,
+    post_hook: Option<Closure>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VsCode {
+    binary: &'static str,
+    extensions: HashMap<String, VsCodeOpts>,
+}
+
+impl Backend for VsCode {
+    fn new(value: &Record, config: &Record) -> Result<Self> {
+        let binary = get_binary(config)?;
+
+        let extensions = value
+            .get(PACKAGE_LIST_KEY)
+            .ok_or_else(|| mod_err!("Failed to get extensions for VsCode"))?
+            .as_list()
+            .map_err(|e| nest_errors!("The extension list in VsCode is not a list", e))?
+            .iter()
+            .map(value_to_pkgspec)
+            .collect::<Result<_>>()?;
+
+        log::info!("Successfully parsed {binary} extensions");
+        Ok(VsCode { binary, extensions })
+    }
+
+    fn unmanaged(&self) -> Result<Box<[String]>> {
+        let installed = self.get_installed_extensions()?;
+
+        Ok(self
+            .get_extra_extensions(&installed)
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn plan(&self, _no_confirm: bool, _selection: Selection) -> Result<Plan> {
+        let installed = self.get_installed_extensions()?;
+
+        let mut plan = Plan::default();
+
+        let missing: BTreeMap<_, _> = self
+            .extensions
+            .iter()
+            .filter(|(extension, spec)| needs_install(installed.get(*extension), spec))
+            .map(|(extension, spec)| (extension.as_str(), spec))
+            .collect();
+
+        // A different version of an installed extension is only put in place
+        // with --force
+        let force = missing
+            .keys()
+            .any(|extension| installed.contains_key(*extension))
+            .then_some("--force");
+
+        let targets: Vec<_> = missing
+            .iter()
+            .map(|(extension, spec)| install_target(extension, spec))
+            .collect();
+
+        plan.push(
+            Action::Install,
+            missing.keys().copied(),
+            [self.binary]
+                .into_iter()
+                .chain(
+                    targets
+                        .iter()
+                        .flat_map(|target| ["--install-extension", target.as_str()]),
+                )
+                .chain(force),
+            Perms::User,
+        );
+        missing
+            .values()
+            .for_each(|spec| plan.push_hook(spec.post_hook.as_ref()));
+
+        let extra = self.get_extra_extensions(&installed);
+
+        plan.push(
+            Action::Remove,
+            extra.iter().copied(),
+            [self.binary].into_iter().chain(
+                extra
+                    .iter()
+                    .flat_map(|extension| ["--uninstall-extension", *extension]),
+            ),
+            Perms::User,
+        );
+
+        Ok(plan)
+    }
+
+    fn update(&self, opts: &UpdateCommand) -> Result<()> {
+        let command_action = if opts.dry_run {
+            dry_run_command
+        } else {
+            run_command
+        };
+
+        // Pinned extensions stay at their version, the others are installed
+        // again with --force to get the latest one
+        let unpinned: BTreeSet<_> = self
+            .extensions
+            .iter()
+            .filter(|(_, spec)| spec.version.is_none())
+            .map(|(extension, _)| extension.as_str())
+            .collect();
+
+        if unpinned.is_empty() {
+            log::info!(
+                "Every {} extension is pinned, nothing to update",
+                self.binary
+            );
+            return Ok(());
+        }
+
+        if !opts.no_confirm
+            && !confirmation_prompt(
+                "Do you want to update the following extensions?: ",
+                &unpinned,
+            )?
+        {
+            return Ok(());
+        }
+
+        command_action(
+            [self.binary]
+                .into_iter()
+                .chain(
+                    unpinned
+                        .iter()
+                        .flat_map(|extension| ["--install-extension", *extension]),
+                )
+                .chain(["--force"]),
+            Perms::User,
+        )
+        .inspect(|_| log::info!("Successfully updated {} extensions", self.binary))
+        .map_err(|e| nest_errors!("Failed to update extensions", e))
+    }
+
+    fn validate(value: &Record, _config: &Record) -> Vec<Diagnostic> {
+        let (_, diagnostics) = check_records(
+            value,
+            PACKAGE_LIST_KEY,
+            "VsCode",
+            &[PACKAGE_KEY, VERSION_KEY, HOOK_KEY],
+            value_to_pkgspec,
+        );
+
+        unknown_keys(value, &[PACKAGE_LIST_KEY], "VsCode")
+            .into_iter()
+            .chain(diagnostics)
+            .collect()
+    }
+
+    fn executable(config: &Record) -> Result<String> {
+        get_binary(config).map(ToOwned::to_owned)
+    }
+
+    fn package_count(&self) -> usize {
+        self.extensions.len()
+    }
+
+    fn settings(config: &Record) -> Result<Box<[(&'static str, String)]>> {
+        let binary = get_binary(config)?;
+
+        Ok(Box::new([(VSCODE_BINARY_KEY, binary.to_owned())]))
+    }
+
+    fn clean_cache(&self, _config: &Record, _opts: &CleanCacheCommand) -> Result<()> {
+        // Nothing to do here
+        Ok(())
+    }
+}
+
+impl VsCode {
+    fn get_installed_extensions(&self) -> Result<HashMap<String, String>> {
+        let extensions = run_command_for_stdout(
+            [self.binary, "--list-extensions", "--show-versions"],
+            Perms::User,
+            true,
+        )
+        .map_err(|e| nest_errors!("Failed to list installed extensions", e))?;
+
+        Ok(parse_extensions(&extensions))
+    }
+
+    fn get_extra_extensions<'a>(
+        &self,
+        installed: &'a HashMap<String, String>,
+    ) -> BTreeSet<&'a str> {
+        installed
+            .keys()
+            .filter(|extension| !self.extensions.contains_key(*extension))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+fn needs_install(installed: Option<&String>, spec: &VsCodeOpts) -> bool {
+    match (installed, &spec.version) {
+        (None, _) => true,
+        (Some(installed), Some(version)) => installed != version,
+        (Some(_), None) => false,
+    }
+}
+
+fn install_target(extension: &str, spec: &VsCodeOpts) -> String {
+    match &spec.version {
+        Some(version) => [extension, "@", version].concat(),
+        None => extension.to_owned(),
+    }
+}
+
+// Extension ids are case insensitive, but are listed the way their publisher
+// wrote them
+fn parse_extensions(extensions: &str) -> HashMap<String, String> {
+    extensions
+        .lines()
+        .filter_map(|line| line.trim().split_once('@'))
+        .map(|(extension, version)| (extension.to_lowercase(), version.to_owned()))
+        .collect()
+}
+
+fn get_binary(config: &Record) -> Result<&'static str> {
+    let name = match config.get(VSCODE_BINARY_KEY) {
+        Some(name) => name.as_str().map_err(|e| {
+            nest_errors!("Failed to parse config, vscode binary is not a string", e)
+        })?,
+        None => {
+            log::info!("Value not specified in config, defaulting to {DEFAULT_VSCODE_BINARY}");
+            DEFAULT_VSCODE_BINARY
+        }
+    };
+
+    BINARIES
+        .into_iter()
+        .find(|binary| *binary == name)
+        .ok_or_else(|| {
+            mod_err!("Unsupported vscode binary {name}, expected code, codium or code-insiders")
+        })
+}
+
+fn value_to_pkgspec(value: &Value) -> Result<(String, VsCodeOpts)> {
+    let record = value
+        .as_record()
+        .map_err(|e| nest_errors!("The extension-spec is not a record", e))?;
+
+    let extension = record
+        .get(PACKAGE_KEY)
+        .ok_or_else(|| mod_err!("No extension mentioned"))?
+        .as_str()
+        .map_err(|e| nest_errors!("The extension id was not a string", e))?
+        .to_lowercase();
+
+    let version = match record.get(VERSION_KEY) {
+        Some(version) => Some(
+            version
+                .as_str()
+                .map_err(|e| nest_errors!("Version for {extension} is not a string", e))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let post_hook = match record.get(HOOK_KEY) {
+        Some(post_hook) => {
+            let post_hook = post_hook
+                .as_closure()
+                .map_err(|e| nest_errors!("Post hook for {extension} is not a closure", e))?;
+
+            Some(post_hook.to_owned())
+        }
+        None => None,
+    };
+
+    Ok((extension, VsCodeOpts { version, post_hook }))
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    #[test]
+    fn get_binary_default() {
+        let codium = Record::from_raw_cols_vals(
+            vec![VSCODE_BINARY_KEY.to_owned()],
+            vec![Value::string("codium", Span::test_data())],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+        let vim = Record::from_raw_cols_vals(
+            vec![VSCODE_BINARY_KEY.to_owned()],
+            vec![Value::string("vim", Span::test_data())],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        assert_eq!(get_binary(&Record::new()).unwrap(), "code");
+        assert_eq!(get_binary(&codium).unwrap(), "codium");
+        assert!(get_binary(&vim).is_err());
+    }
+
+    #[test]
+    fn parse_extensions_lowercase() {
+        let extensions =
+            "ms-python.python@2024.2.1\nrust-lang.rust-analyzer@0.3.1850\nGitHub.copilot@1.167.0\n";
+
+        let installed = parse_extensions(extensions);
+        assert_eq!(installed.len(), 3);
+        assert_eq!(installed["github.copilot"], "1.167.0");
+    }
+
+    #[test]
+    fn needs_install_pinned() {
+        let spec = |version: Option<&str>| VsCodeOpts {
+            version: version.map(ToOwned::to_owned),
+            post_hook: None,
+        };
+        let installed = "2024.2.1".to_owned();
+
+        assert!(needs_install(None, &spec(None)));
+        assert!(!needs_install(Some(&installed), &spec(None)));
+        assert!(!needs_install(Some(&installed), &spec(Some("2024.2.1"))));
+        assert!(needs_install(Some(&installed), &spec(Some("2024.0.0"))));
+        assert_eq!(
+            install_target("ms-python.python", &spec(Some("2024.0.0"))),
+            "ms-python.python@2024.0.0"
+        );
+    }
+}
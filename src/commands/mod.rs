@@ -1,4 +1,9 @@
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
+use std::thread;
 
 use anyhow::{Result, anyhow};
 use inquire::Confirm;
@@ -39,7 +44,90 @@ where
     }
 }
 
+/// Runs the command with the input written to its stdin, returning its stdout
+pub fn run_command_with_stdin<I>(args: I, perms: Perms, input: &str) -> Result<String>
+where
+    I: IntoIterator,
+    I::Item: Into<String>,
+{
+    let args = get_command(args, perms)?;
+
+    let (first_arg, remaining_args) = args.split_first().unwrap();
+
+    let mut child = Command::new(first_arg)
+        .args(remaining_args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take();
+
+    // Written from another thread so that a command answering before it has
+    // read everything does not block on a full pipe
+    let output = thread::scope(|scope| {
+        scope.spawn(move || {
+            stdin
+                .as_mut()
+                .map(|stdin| stdin.write_all(input.as_bytes()))
+        });
+        child.wait_with_output()
+    })?;
+
+    if output.status.success() {
+        Ok(String::from_utf8(output.stdout)?)
+    } else {
+        Err(mod_err!("command failed: {:?}", args.join(" ")))
+    }
+}
+
 pub fn run_command<I>(args: I, perms: Perms) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: Into<String>,
+{
+    spawn_command(args, perms, Stdio::inherit())
+}
+
+/// Runs the command with its stdout sent to stderr, keeping stdout free for
+/// machine-readable output
+pub fn run_command_to_stderr<I>(args: I, perms: Perms) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: Into<String>,
+{
+    spawn_command(args, perms, Stdio::from(io::stderr()))
+}
+
+/// Runs `f` with the stdout of the whole process pointed at stderr. Closures
+/// print straight to the file descriptor, both from nushell and from the
+/// commands they spawn, so it has to be swapped rather than piped.
+pub fn with_stdout_to_stderr<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    io::stdout().flush()?;
+
+    let stdout = io::stdout().as_fd().try_clone_to_owned()?;
+
+    // SAFETY: both descriptors stay open until they are swapped back
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let result = f();
+
+    io::stdout().flush()?;
+
+    // SAFETY: `stdout` is owned here and still open
+    if unsafe { libc::dup2(stdout.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    result
+}
+
+fn spawn_command<I>(args: I, perms: Perms, stdout: Stdio) -> Result<()>
 where
     I: IntoIterator,
     I::Item: Into<String>,
@@ -52,7 +140,7 @@ where
     let status = command
         .args(remaining_args)
         .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
+        .stdout(stdout)
         .stderr(Stdio::inherit())
         .status()?;
 
@@ -63,6 +151,68 @@ where
     }
 }
 
+/// Runs the command with every line of its output prefixed, so that commands
+/// running side by side can be told apart. Stdin is only handed over to
+/// interactive commands, the others must not wait for input.
+pub fn run_command_prefixed<I>(
+    args: I,
+    perms: Perms,
+    prefix: &str,
+    interactive: bool,
+    quiet_stdout: bool,
+) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: Into<String>,
+{
+    let args = get_command(args, perms)?;
+
+    let (first_arg, remaining_args) = args.split_first().unwrap();
+
+    let mut child = Command::new(first_arg)
+        .args(remaining_args)
+        .stdin(if interactive {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    thread::scope(|scope| {
+        scope.spawn(|| forward_lines(stdout, prefix, quiet_stdout));
+        forward_lines(stderr, prefix, true);
+    });
+
+    if child.wait()?.success() {
+        Ok(())
+    } else {
+        Err(mod_err!("command failed: {:?}", args.join(" ")))
+    }
+}
+
+fn forward_lines<R: Read>(reader: Option<R>, prefix: &str, to_stderr: bool) {
+    let Some(reader) = reader else {
+        return;
+    };
+
+    BufReader::new(reader)
+        .lines()
+        .map_while(Result::ok)
+        .for_each(|line| {
+            #[allow(clippy::print_stdout, clippy::print_stderr)]
+            if to_stderr {
+                eprintln!("[{prefix}] {line}");
+            } else {
+                println!("[{prefix}] {line}");
+            }
+        });
+}
+
 pub fn dry_run_command<I>(args: I, perms: Perms) -> Result<()>
 where
     I: IntoIterator,
@@ -78,6 +228,21 @@ where
     Ok(())
 }
 
+pub fn dry_run_command_with_stdin<I>(args: I, perms: Perms, input: &str) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: Into<String>,
+{
+    let command = get_command(args, perms)?;
+    let command_str = "DRY RUN COMMAND> ".to_owned() + command.join(" ").as_str() + " <<< " + input;
+
+    #[allow(clippy::print_stderr)]
+    {
+        eprintln!("{command_str}");
+    }
+    Ok(())
+}
+
 pub fn confirmation_prompt<P, I>(prompt: P, items: I) -> Result<bool>
 where
     P: AsRef<str>,
@@ -97,6 +262,14 @@ where
     answer.map_err(|_| mod_err!("Failed to retrieve answer"))
 }
 
+pub fn find_executable(name: &str) -> Option<PathBuf> {
+    let paths = env::var_os("PATH")?;
+
+    env::split_paths(&paths)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
 fn get_command<I>(args: I, perms: Perms) -> Result<Vec<String>>
 where
     I: IntoIterator,
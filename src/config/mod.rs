@@ -1,8 +1,11 @@
 use std::{env, fs::File, io::Write, path::PathBuf};
 
 use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value};
 
-use crate::{function, mod_err, nest_errors};
+use crate::backends::find_plugin;
+use crate::validate::{Diagnostic, unknown_keys};
+use crate::{backend_names, for_all_backends, function, mod_err, nest_errors};
 
 pub const ARCH_PACKAGE_MANAGER_KEY: &str = "arch_package_manager";
 pub const DEFAULT_PACKAGE_MANAGER: &str = "paru";
@@ -13,10 +16,21 @@ pub const DEFAULT_FLATPAK_SYSTEMWIDE: bool = false;
 pub const CARGO_USE_BINSTALL_KEY: &str = "cargo_use_binstall";
 pub const DEFAULT_CARGO_USE_BINSTALL: bool = false;
 
-const CONFIG: [(&str, &str); 3] = [
+pub const NPM_CLIENT_KEY: &str = "npm_client";
+pub const DEFAULT_NPM_CLIENT: &str = "npm";
+
+pub const VSCODE_BINARY_KEY: &str = "vscode_binary";
+pub const DEFAULT_VSCODE_BINARY: &str = "code";
+
+pub const DISABLED_BACKENDS_KEY: &str = "disabled_backends";
+
+const CONFIG: [(&str, &str); 6] = [
     (ARCH_PACKAGE_MANAGER_KEY, DEFAULT_PACKAGE_MANAGER),
     (FLATPAK_DEFAULT_SYSTEMWIDE_KEY, "false"),
     (CARGO_USE_BINSTALL_KEY, "false"),
+    (NPM_CLIENT_KEY, DEFAULT_NPM_CLIENT),
+    (VSCODE_BINARY_KEY, DEFAULT_VSCODE_BINARY),
+    (DISABLED_BACKENDS_KEY, "[]"),
 ];
 
 pub fn get_config_path() -> Result<PathBuf> {
@@ -80,3 +94,62 @@ pub fn write_default_config(config_path: &PathBuf) -> Result<()> {
 
     Ok(())
 }
+
+pub fn get_disabled_backends(config: &Record) -> Result<Box<[String]>> {
+    let Some(disabled) = config.get(DISABLED_BACKENDS_KEY) else {
+        return Ok(Box::new([]));
+    };
+
+    disabled
+        .as_list()
+        .map_err(|e| nest_errors!("disabled backends is not a list", e))?
+        .iter()
+        .map(|backend| {
+            backend
+                .as_str()
+                .map(ToOwned::to_owned)
+                .map_err(|e| nest_errors!("disabled backend is not a string", e))
+        })
+        .collect()
+}
+
+pub fn validate(config: &Record) -> Vec<Diagnostic> {
+    let known_keys: Box<[_]> = CONFIG.iter().map(|(key, _)| *key).collect();
+
+    let mut diagnostics = unknown_keys(config, &known_keys, "config.nu");
+
+    [ARCH_PACKAGE_MANAGER_KEY, NPM_CLIENT_KEY, VSCODE_BINARY_KEY]
+        .into_iter()
+        .for_each(|key| {
+            if let Some(Err(e)) = config.get(key).map(Value::as_str) {
+                diagnostics.push(Diagnostic::error(["config.nu", key].join("."), e));
+            }
+        });
+
+    [FLATPAK_DEFAULT_SYSTEMWIDE_KEY, CARGO_USE_BINSTALL_KEY]
+        .into_iter()
+        .for_each(|key| {
+            if let Some(Err(e)) = config.get(key).map(Value::as_bool) {
+                diagnostics.push(Diagnostic::error(["config.nu", key].join("."), e));
+            }
+        });
+
+    let source = ["config.nu", DISABLED_BACKENDS_KEY].join(".");
+    let backends = for_all_backends!(backend_names,);
+
+    match get_disabled_backends(config) {
+        Ok(disabled) => diagnostics.extend(
+            disabled
+                .iter()
+                .filter(|backend| {
+                    !backends.contains(&backend.as_str()) && find_plugin(backend).is_none()
+                })
+                .map(|backend| {
+                    Diagnostic::warning(&source, format!("unknown backend `{backend}`"))
+                }),
+        ),
+        Err(e) => diagnostics.push(Diagnostic::error(source, e)),
+    }
+
+    diagnostics
+}
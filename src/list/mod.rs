@@ -0,0 +1,184 @@
+use std::ffi::OsStr;
+use std::fs::read;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use nu_protocol::Record;
+
+use crate::backends::{
+    AppImage, Apt, Arch, Backend, Brew, Cargo, Custom, Dnf, Files, Flatpak, Gem, Go, Nix, Npm,
+    Opam, Plugin, Rustup, Snap, Uv, VsCode, describe_plugin, find_plugins,
+};
+use crate::commands::{Perms, find_executable, run_command_for_stdout};
+use crate::parser::Engine;
+use crate::{BackendArgs, backend_describe, config, for_all_backends, function, nest_errors};
+
+pub fn run(config_dir: &Path, config_file: &Path, backend_args: &BackendArgs) -> Result<()> {
+    let config = fetch(config_dir, config_file)
+        .map_err(|e| nest_errors!("Error encountered while parsing config spec", e))?;
+
+    let package_nu = [config_dir.as_os_str(), OsStr::new("package.nu")].join(OsStr::new("/"));
+    let packages = fetch(config_dir, Path::new(&package_nu))
+        .map_err(|e| nest_errors!("Error encountered while parsing package spec", e))?;
+
+    let disabled = config::get_disabled_backends(&config)
+        .map_err(|e| nest_errors!("Error encountered while parsing config spec", e))?;
+
+    let builtin = for_all_backends!(backend_describe, packages, config, backend_args, disabled,);
+
+    let plugins = find_plugins().into_iter().map(|(name, executable)| {
+        let enabled = backend_args.is_enabled(&name, &disabled);
+        describe_plugin_backend(&name, &executable, &packages, &config, enabled)
+    });
+
+    let output: Box<[_]> = builtin.into_iter().chain(plugins).collect();
+
+    #[allow(clippy::print_stdout)]
+    {
+        println!("{}", output.join("\n"));
+    }
+
+    Ok(())
+}
+
+pub fn describe<B: Backend>(
+    name: &str,
+    packages: &Record,
+    config: &Record,
+    enabled: bool,
+) -> String {
+    let declaration = packages.get(name);
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+
+    let mut lines = vec![
+        format!("declared: {}", yes_no(declaration.is_some())),
+        format!("enabled: {}", yes_no(enabled)),
+        format!("executable: {}", describe_executable(B::executable(config))),
+    ];
+
+    match B::settings(config) {
+        Ok(settings) => lines.extend(
+            settings
+                .iter()
+                .map(|(key, setting)| format!("{key}: {setting}")),
+        ),
+        Err(_) => lines.push("settings: invalid, run validate for details".to_owned()),
+    }
+
+    if let Some(declaration) = declaration {
+        let count = declaration
+            .as_record()
+            .map_err(|e| anyhow!(e))
+            .and_then(|declaration| B::new(declaration, config))
+            .map(|backend| backend.package_count().to_string())
+            .unwrap_or_else(|_| "invalid declaration, run validate for details".to_owned());
+
+        lines.push(format!("packages: {count}"));
+    }
+
+    lines
+        .iter()
+        .fold(name.to_owned() + ":", |acc, line| acc + "\n    " + line)
+}
+
+fn describe_plugin_backend(
+    name: &str,
+    executable: &str,
+    packages: &Record,
+    config: &Record,
+    enabled: bool,
+) -> String {
+    let declaration = packages.get(name);
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+
+    let mut lines = vec![
+        format!("declared: {}", yes_no(declaration.is_some())),
+        format!("enabled: {}", yes_no(enabled)),
+        format!("executable: {executable} (plugin)"),
+    ];
+
+    match describe_plugin(executable, config) {
+        Ok(description) => lines.extend(
+            [format!("protocol: {}", description.protocol)]
+                .into_iter()
+                .chain(
+                    description
+                        .settings
+                        .iter()
+                        .map(|(key, setting)| format!("{key}: {setting}")),
+                ),
+        ),
+        Err(_) => lines.push("protocol: unknown, run validate for details".to_owned()),
+    }
+
+    if let Some(declaration) = declaration {
+        let count = declaration
+            .as_record()
+            .map_err(|e| anyhow!(e))
+            .and_then(|declaration| Plugin::new(name, executable.to_owned(), declaration, config))
+            .map(|backend| backend.package_count().to_string())
+            .unwrap_or_else(|_| "invalid declaration, run validate for details".to_owned());
+
+        lines.push(format!("packages: {count}"));
+    }
+
+    lines
+        .iter()
+        .fold(name.to_owned() + ":", |acc, line| acc + "\n    " + line)
+}
+
+fn describe_executable(executable: Result<String>) -> String {
+    let executable = match executable {
+        Ok(executable) => executable,
+        Err(_) => return "unknown, run validate for details".to_owned(),
+    };
+
+    let Some(path) = find_executable(&executable) else {
+        return format!("{executable} (not found in PATH)");
+    };
+    let path = path.to_string_lossy();
+
+    let version = run_command_for_stdout([path.as_ref(), "--version"], Perms::User, true)
+        .ok()
+        .and_then(|version| version.lines().next().map(ToOwned::to_owned))
+        .unwrap_or_else(|| "unknown version".to_owned());
+
+    format!("{executable} ({path}, {version})")
+}
+
+fn fetch(config_dir: &Path, file: &Path) -> Result<Record> {
+    let contents = read(file).map_err(|e| nest_errors!("Failed to read {file:?}", e))?;
+
+    Engine::new(config_dir).fetch(&contents)
+}
+
+#[macro_export]
+macro_rules! backend_describe {
+    ($packages:ident, $config:ident, $backend_args:ident, $disabled:ident, $($backend:ident),*) => {
+        [$(
+            $crate::list::describe::<$backend>(
+                stringify!($backend),
+                &$packages,
+                &$config,
+                $backend_args.is_enabled(stringify!($backend), &$disabled),
+            ),
+        )*]
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn describe_undeclared() {
+        let description = describe::<Rustup>("Rustup", &Record::new(), &Record::new(), false);
+        let lines: Box<[_]> = description.lines().collect();
+
+        assert_eq!(lines[0], "Rustup:");
+        assert_eq!(lines[1], "    declared: no");
+        assert_eq!(lines[2], "    enabled: no");
+        assert!(lines[3].starts_with("    executable: rustup"));
+        assert!(!description.contains("packages:"));
+    }
+}
@@ -1,25 +1,46 @@
 use std::ffi::OsStr;
 use std::fs::{File, create_dir_all, read};
-use std::path;
+use std::io::{self, IsTerminal};
+use std::{env, path};
 
 use anyhow::anyhow;
+use backends::AppImage;
+use backends::Apt;
 use backends::Arch;
 use backends::Backend;
 use backends::Backends;
+use backends::Brew;
 use backends::Cargo;
+use backends::Custom;
+use backends::Dnf;
+use backends::Files;
 use backends::Flatpak;
+use backends::Gem;
+use backends::Go;
+use backends::Nix;
+use backends::Npm;
+use backends::Opam;
 use backends::Rustup;
+use backends::Snap;
+use backends::Uv;
+use backends::VsCode;
 use clap::Args;
 use clap::Parser;
 use clap::Subcommand;
 use env_logger::Env;
+use output::{OutputFormat, PlanEntry, UnmanagedEntry};
 use parser::Engine;
+use plan::{Selection, Status};
 
 mod backends;
 mod commands;
 mod config;
 mod error;
+mod list;
+mod output;
 mod parser;
+mod plan;
+mod validate;
 
 /// A nushell based declarative package management utility
 #[derive(Parser)]
@@ -28,17 +49,54 @@ struct Arguments {
     /// Path to config-file
     #[arg(short, long)]
     config_dir: Option<path::PathBuf>,
+    /// Print machine-readable output for sync, clean, rebuild and unmanaged
+    #[arg(short, long, global = true, value_enum)]
+    output: Option<OutputFormat>,
+    #[command(flatten)]
+    backend_args: BackendArgs,
     #[command(subcommand)]
     subcommand: SubCommand,
 }
 
+#[derive(Args)]
+struct BackendArgs {
+    #[arg(long = "backend", global = true, value_name = "BACKEND", value_parser = backend_name)]
+    /// only use the given backend, can be repeated
+    backends: Vec<String>,
+    #[arg(long = "skip-backend", global = true, value_name = "BACKEND", value_parser = backend_name)]
+    /// do not use the given backend, can be repeated
+    skip_backends: Vec<String>,
+}
+
+/// Built in backends, or plugins found in PATH
+fn backend_name(name: &str) -> Result<String, String> {
+    if for_all_backends!(backend_names,).contains(&name) || backends::find_plugin(name).is_some() {
+        Ok(name.to_owned())
+    } else {
+        Err(format!(
+            "expected one of {} or a backend with {}{name} in PATH",
+            for_all_backends!(backend_names,).join(", "),
+            backends::PLUGIN_PREFIX
+        ))
+    }
+}
+
+impl BackendArgs {
+    fn is_enabled(&self, backend: &str, disabled: &[String]) -> bool {
+        backends::is_enabled(backend, &self.backends, &self.skip_backends, disabled)
+    }
+}
+
 #[derive(Subcommand)]
 enum SubCommand {
     Clean(CleanCommand),
     Sync(SyncCommand),
     Unmanaged(UnmanagedCommand),
     Validate(ValidateCommand),
+    Backends(BackendsCommand),
     CleanCache(CleanCacheCommand),
+    Update(UpdateCommand),
+    Rebuild(RebuildCommand),
 }
 
 #[derive(Args)]
@@ -51,6 +109,9 @@ struct CleanCommand {
     #[arg(short = 'y', long)]
     /// do not ask for any confirmation
     no_confirm: bool,
+    #[arg(short = 'j', long, default_value_t = 1)]
+    /// apply the changes of up to this many backends at once
+    jobs: usize,
 }
 
 #[derive(Args)]
@@ -63,17 +124,33 @@ struct SyncCommand {
     #[arg(short = 'y', long)]
     /// do not ask for any confirmation
     no_confirm: bool,
+    #[arg(short = 'j', long, default_value_t = 1)]
+    /// apply the changes of up to this many backends at once
+    jobs: usize,
 }
 
 #[derive(Args)]
 #[command(visible_alias("u"))]
 /// show explicitly installed packages not managed
-struct UnmanagedCommand;
+struct UnmanagedCommand {
+    #[arg(short = 'p', long)]
+    /// print one tab-separated backend and package pair per line
+    plain: bool,
+}
 
 #[derive(Args)]
-#[command(visible_alias("b"))]
-/// show the backends found and validate their configs
-struct ValidateCommand;
+#[command(visible_aliases(["b", "v"]))]
+/// check the config and the package declarations for mistakes
+struct ValidateCommand {
+    #[arg(short = 's', long)]
+    /// treat warnings as errors
+    strict: bool,
+}
+
+#[derive(Args)]
+#[command(visible_alias("list"))]
+/// show every backend, whether it is declared and enabled, and its settings
+struct BackendsCommand;
 
 #[derive(Args)]
 #[command(visible_alias("e"))]
@@ -87,10 +164,51 @@ struct CleanCacheCommand {
     no_confirm: bool,
 }
 
+#[derive(Args)]
+#[command(visible_alias("upgrade"))]
+/// upgrade the declared packages of all the backends
+struct UpdateCommand {
+    #[arg(short = 'n', long)]
+    /// do not execute commands
+    dry_run: bool,
+    #[arg(short = 'y', long)]
+    /// do not ask for any confirmation
+    no_confirm: bool,
+}
+
+#[derive(Args)]
+#[command(visible_alias("r"))]
+/// install, mark and remove packages until the system matches the declaration
+struct RebuildCommand {
+    #[arg(short = 'n', long)]
+    /// do not execute commands
+    dry_run: bool,
+    #[arg(short = 'y', long)]
+    /// do not ask for any confirmation
+    no_confirm: bool,
+    #[arg(short = 'j', long, default_value_t = 1)]
+    /// apply the changes of up to this many backends at once
+    jobs: usize,
+}
+
 fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("off")).init();
     let args = Arguments::parse();
 
+    if args.output.is_some()
+        && !matches!(
+            args.subcommand,
+            SubCommand::Sync(_)
+                | SubCommand::Clean(_)
+                | SubCommand::Rebuild(_)
+                | SubCommand::Unmanaged(_)
+        )
+    {
+        return Err(mod_err!(
+            "--output is only supported by sync, clean, rebuild and unmanaged"
+        ));
+    }
+
     let config_file = args
         .config_dir
         .map(Ok)
@@ -122,6 +240,19 @@ fn main() -> anyhow::Result<()> {
 
     let config_dir = config_file.parent().unwrap();
 
+    if let SubCommand::Validate(validate_command) = &args.subcommand {
+        return validate::run(
+            config_dir,
+            &config_file,
+            &args.backend_args,
+            validate_command,
+        );
+    }
+
+    if let SubCommand::Backends(_) = &args.subcommand {
+        return list::run(config_dir, &config_file, &args.backend_args);
+    }
+
     let config_contents = read(&config_file).map_err(|e| {
         log::error!("Error occured when reading the config spec");
         log::error!("{e:?}");
@@ -143,21 +274,76 @@ fn main() -> anyhow::Result<()> {
 
     let mut engine = Engine::new(config_dir);
 
-    let packages = engine
+    let mut packages = engine
         .fetch(&contents)
         .map_err(|e| nest_errors!("Error encountered while parsing package spec", e))?;
 
-    let mut backends = parse_all_backends!(packages, config);
+    let disabled = config::get_disabled_backends(&config)
+        .map_err(|e| nest_errors!("Error encountered while parsing config spec", e))?;
+    packages.retain(|backend, _| args.backend_args.is_enabled(backend, &disabled));
+
+    let plugins = backends::parse_plugins(&packages, &config)?;
+    let mut backends: Vec<_> = parse_all_backends!(packages, config)
+        .into_iter()
+        .chain(plugins.into_iter().map(Some))
+        .collect();
+    backends
+        .iter_mut()
+        .flatten()
+        .for_each(|backend| backend.set_engine(&engine));
+
+    match &args.subcommand {
+        SubCommand::Sync(opts) => {
+            return apply(
+                &backends,
+                &engine,
+                Selection::Additions,
+                opts.dry_run,
+                opts.no_confirm,
+                opts.jobs,
+                args.output,
+            );
+        }
+        SubCommand::Clean(opts) => {
+            return apply(
+                &backends,
+                &engine,
+                Selection::Removals,
+                opts.dry_run,
+                opts.no_confirm,
+                opts.jobs,
+                args.output,
+            );
+        }
+        SubCommand::Rebuild(opts) => {
+            return apply(
+                &backends,
+                &engine,
+                Selection::All,
+                opts.dry_run,
+                opts.no_confirm,
+                opts.jobs,
+                args.output,
+            );
+        }
+        SubCommand::Unmanaged(opts) => return unmanaged(&backends, opts, args.output),
+        _ => {}
+    }
 
     let results = backends.iter_mut().flat_map(|backend_opt| {
         backend_opt.as_mut().map(|backend| match &args.subcommand {
-            SubCommand::Clean(clean_command) => backend.remove(clean_command),
-            SubCommand::Sync(sync_command) => backend.install(&mut engine, sync_command),
-            SubCommand::Unmanaged(_unmanaged_command) => todo!("Not implemented yet"),
-            SubCommand::Validate(_validate_command) => todo!("Not implemented yet"),
+            SubCommand::Clean(_) | SubCommand::Sync(_) | SubCommand::Rebuild(_) => {
+                unreachable!("plans are applied before the loop")
+            }
+            SubCommand::Unmanaged(_) => {
+                unreachable!("unmanaged packages are listed before the loop")
+            }
+            SubCommand::Validate(_) => unreachable!("validation is handled before parsing"),
+            SubCommand::Backends(_) => unreachable!("backends are listed before parsing"),
             SubCommand::CleanCache(clean_cache_command) => {
                 backend.clean_cache(&config, clean_cache_command)
             }
+            SubCommand::Update(update_command) => backend.update(update_command),
         })
     });
 
@@ -168,3 +354,187 @@ fn main() -> anyhow::Result<()> {
         (Err(orig), Err(e)) => Err(concat_err!(orig, e)),
     })
 }
+
+/// Shows the plans of all the backends as one diff, asks for confirmation
+/// once and then carries them out
+fn apply(
+    backends: &[Option<Backends>],
+    engine: &Engine,
+    selection: Selection,
+    dry_run: bool,
+    no_confirm: bool,
+    jobs: usize,
+    output: Option<OutputFormat>,
+) -> anyhow::Result<()> {
+    let plans = backends
+        .iter()
+        .flatten()
+        .map(|backend| {
+            let name = backend.name();
+            // Backends running side by side cannot share the terminal, so
+            // their tools are told not to ask
+            backend
+                .plan(no_confirm || jobs > 1, selection)
+                .map(|plan| (name, selection.select(plan)))
+                .map_err(|e| nest_errors!("Failed to plan changes for {name}", e))
+        })
+        .collect::<anyhow::Result<Box<[_]>>>()?;
+
+    let plans: Box<[_]> = plans
+        .into_iter()
+        .filter(|(_, plan)| !plan.is_empty())
+        .collect();
+
+    let no_color = env::var_os("NO_COLOR").is_some();
+
+    if output.is_none() {
+        let color = io::stdout().is_terminal() && !no_color;
+
+        #[allow(clippy::print_stdout)]
+        {
+            if plans.is_empty() {
+                println!("Nothing to do");
+                return Ok(());
+            }
+
+            plans
+                .iter()
+                .for_each(|(name, plan)| println!("{}", plan.render(name, color)));
+        }
+    } else if !no_confirm {
+        // stdout is kept for the report, but the changes still have to be
+        // shown before asking for them
+        let color = io::stderr().is_terminal() && !no_color;
+
+        #[allow(clippy::print_stderr)]
+        plans
+            .iter()
+            .for_each(|(name, plan)| eprintln!("{}", plan.render(name, color)));
+    }
+
+    let no_items: [&str; 0] = [];
+    let confirmed = plans.is_empty()
+        || no_confirm
+        || commands::confirmation_prompt("Apply these changes?", no_items)?;
+
+    let runs = if confirmed {
+        plan::run_all(&plans, dry_run, output.is_some(), jobs)
+    } else {
+        plans
+            .iter()
+            .map(|(_, plan)| (vec![Status::Planned; plan.steps.len()].into(), Ok(())))
+            .collect()
+    };
+
+    let mut entries = Vec::new();
+
+    // A failing backend skips its own hooks but does not hold up the others.
+    // Hooks share the engine, so they always run one after the other.
+    let results = plans
+        .iter()
+        .zip(runs)
+        .map(|((name, plan), (statuses, result))| {
+            let result = result.and_then(|()| {
+                if confirmed {
+                    plan.run_hooks(engine, dry_run, output.is_some())
+                } else {
+                    Ok(())
+                }
+            });
+
+            entries.extend(plan.steps.iter().zip(statuses).flat_map(|(step, status)| {
+                step.packages.iter().map(move |package| PlanEntry {
+                    backend: name,
+                    package,
+                    action: step.action,
+                    command: step.argv(),
+                    status,
+                })
+            }));
+
+            result.map_err(|e| nest_errors!("Failed to apply the changes for {name}", e))
+        });
+
+    #[allow(clippy::manual_try_fold)]
+    let result = results.fold(Ok(()), |acc, curr| match (acc, curr) {
+        (acc, Ok(())) => acc,
+        (Ok(()), curr) => curr,
+        (Err(orig), Err(e)) => Err(concat_err!(orig, e)),
+    });
+
+    if let Some(format) = output {
+        output::emit(format, &entries)?;
+    }
+
+    result
+}
+
+fn unmanaged(
+    backends: &[Option<Backends>],
+    opts: &UnmanagedCommand,
+    output: Option<OutputFormat>,
+) -> anyhow::Result<()> {
+    let results = backends.iter().flatten().map(|backend| {
+        backend
+            .unmanaged()
+            .map(|unmanaged| (backend.name(), unmanaged))
+    });
+
+    let mut found = Vec::new();
+
+    #[allow(clippy::manual_try_fold)]
+    let result = results.fold(Ok(()), |acc, curr| match (acc, curr) {
+        (acc, Ok(unmanaged)) => {
+            found.push(unmanaged);
+            acc
+        }
+        (Ok(()), Err(e)) => Err(e),
+        (Err(orig), Err(e)) => Err(concat_err!(orig, e)),
+    });
+
+    match output {
+        Some(format) => {
+            let entries: Box<[_]> = found
+                .iter()
+                .flat_map(|(backend, unmanaged)| {
+                    unmanaged
+                        .iter()
+                        .map(|package| UnmanagedEntry { backend, package })
+                })
+                .collect();
+
+            output::emit(format, &entries)?;
+        }
+        None => found
+            .iter()
+            .for_each(|(backend, unmanaged)| print_unmanaged(backend, unmanaged, opts)),
+    }
+
+    result
+}
+
+fn print_unmanaged(backend: &str, unmanaged: &[String], opts: &UnmanagedCommand) {
+    if unmanaged.is_empty() {
+        log::info!("No unmanaged packages found for {backend}");
+        return;
+    }
+
+    let output = if opts.plain {
+        unmanaged
+            .iter()
+            .map(|package| [backend, package.as_str()].join("\t"))
+            .collect::<Box<[_]>>()
+            .join("\n")
+    } else {
+        unmanaged
+            .iter()
+            .fold(backend.to_owned() + ":", |acc, package| {
+                acc + "\n    " + package
+            })
+    };
+
+    #[allow(clippy::print_stdout)]
+    {
+        println!("{output}");
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use nu_protocol::engine::EngineState;
+use nu_protocol::{Record, Span, Value};
+use nuon::ToStyle;
+use serde::Serialize;
+use serde_json::{Map, Value as Json};
+
+use crate::plan::{Action, Status};
+use crate::{function, mod_err, nest_errors};
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, ValueEnum)]
+pub enum OutputFormat {
+    Json,
+    Nuon,
+}
+
+/// A single package of a plan step, along with what became of it
+#[derive(Debug, Serialize)]
+pub struct PlanEntry<'a> {
+    pub backend: &'a str,
+    pub package: &'a str,
+    pub action: Action,
+    pub command: Box<[&'a str]>,
+    pub status: Status,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnmanagedEntry<'a> {
+    pub backend: &'a str,
+    pub package: &'a str,
+}
+
+pub fn emit<T: Serialize>(format: OutputFormat, value: &T) -> Result<()> {
+    let output = render(format, value)?;
+
+    #[allow(clippy::print_stdout)]
+    {
+        println!("{output}");
+    }
+
+    Ok(())
+}
+
+fn render<T: Serialize>(format: OutputFormat, value: &T) -> Result<String> {
+    match format {
+        OutputFormat::Json => serde_json::to_string_pretty(value)
+            .map_err(|e| nest_errors!("Failed to serialize the output", e)),
+        OutputFormat::Nuon => {
+            let value = serde_json::to_value(value)
+                .map_err(|e| nest_errors!("Failed to serialize the output", e))?;
+
+            nuon::to_nuon(
+                &EngineState::new(),
+                &json_to_nu(value),
+                ToStyle::Spaces(2),
+                None,
+                false,
+            )
+            .map_err(|e| nest_errors!("Failed to serialize the output", e))
+        }
+    }
+}
+
+fn json_to_nu(value: serde_json::Value) -> Value {
+    let span = Span::unknown();
+
+    match value {
+        serde_json::Value::Null => Value::nothing(span),
+        serde_json::Value::Bool(bool) => Value::bool(bool, span),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(int) => Value::int(int, span),
+            None => Value::float(number.as_f64().unwrap_or_default(), span),
+        },
+        serde_json::Value::String(string) => Value::string(string, span),
+        serde_json::Value::Array(values) => {
+            Value::list(values.into_iter().map(json_to_nu).collect(), span)
+        }
+        serde_json::Value::Object(fields) => Value::record(
+            fields
+                .into_iter()
+                .map(|(key, field)| (key, json_to_nu(field)))
+                .collect::<Record>(),
+            span,
+        ),
+    }
+}
+
+/// The JSON form of a record, failing on values like closures that have none
+pub fn record_to_json(record: &Record) -> Result<Map<String, Json>> {
+    record
+        .iter()
+        .map(|(key, value)| {
+            nu_to_json(value)
+                .map(|value| (key.to_owned(), value))
+                .map_err(|e| nest_errors!("{key} cannot be sent", e))
+        })
+        .collect()
+}
+
+pub fn nu_to_json(value: &Value) -> Result<Json> {
+    let json = match value {
+        Value::Nothing { .. } => Json::Null,
+        Value::Bool { val, .. } => Json::Bool(*val),
+        Value::Int { val, .. } => Json::from(*val),
+        Value::Float { val, .. } => Json::from(*val),
+        Value::String { val, .. } | Value::Glob { val, .. } => Json::String(val.to_owned()),
+        Value::Filesize { val, .. } => Json::from(val.get()),
+        Value::Duration { val, .. } => Json::from(*val),
+        Value::Date { val, .. } => Json::String(val.to_rfc3339()),
+        Value::List { vals, .. } => {
+            Json::Array(vals.iter().map(nu_to_json).collect::<Result<_>>()?)
+        }
+        Value::Record { val, .. } => Json::Object(record_to_json(val)?),
+        value => return Err(mod_err!("A {} has no JSON form", value.get_type())),
+    };
+
+    Ok(json)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry() -> PlanEntry<'static> {
+        PlanEntry {
+            backend: "Arch",
+            package: "foo",
+            action: Action::MarkExplicit,
+            command: Box::new([]),
+            status: Status::DryRun,
+        }
+    }
+
+    #[test]
+    fn render_json() {
+        let output = render(OutputFormat::Json, &[entry()]).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(parsed[0]["backend"], "Arch");
+        assert_eq!(parsed[0]["package"], "foo");
+        assert_eq!(parsed[0]["action"], "mark-explicit");
+        assert_eq!(parsed[0]["status"], "dry-run");
+        assert!(parsed[0]["command"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn render_nuon() {
+        let output = render(OutputFormat::Nuon, &[entry()]).unwrap();
+        let parsed = nuon::from_nuon(&output, None).unwrap();
+        let parsed = parsed.as_list().unwrap()[0].as_record().unwrap();
+
+        assert_eq!(parsed.get("backend").unwrap().as_str().unwrap(), "Arch");
+        assert_eq!(
+            parsed.get("action").unwrap().as_str().unwrap(),
+            "mark-explicit"
+        );
+        assert!(parsed.get("command").unwrap().as_list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn nu_to_json_nested() {
+        let value = Value::record(
+            Record::from_raw_cols_vals(
+                ["name", "versions", "pinned"]
+                    .into_iter()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                vec![
+                    Value::string("foo", Span::test_data()),
+                    Value::list(
+                        vec![
+                            Value::int(1, Span::test_data()),
+                            Value::float(1.5, Span::test_data()),
+                        ],
+                        Span::test_data(),
+                    ),
+                    Value::nothing(Span::test_data()),
+                ],
+                Span::test_data(),
+                Span::test_data(),
+            )
+            .unwrap(),
+            Span::test_data(),
+        );
+
+        assert_eq!(
+            nu_to_json(&value).unwrap(),
+            serde_json::json!({ "name": "foo", "versions": [1, 1.5], "pinned": null })
+        );
+    }
+}
@@ -1,22 +1,31 @@
-use std::path::Path;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Result, anyhow};
 use nu_cli::gather_parent_env_vars;
 use nu_cmd_lang::create_default_context;
 use nu_command::add_shell_command_context;
-use nu_engine::eval_block_with_early_return;
+use nu_engine::{ClosureEvalOnce, eval_block_with_early_return};
 use nu_protocol::{
     PipelineData::Empty,
-    Record, Span,
+    Record, Span, Value,
     debugger::WithoutDebug,
     engine::{Closure, EngineState, Stack, StateWorkingSet},
 };
 
 use crate::{function, mod_err};
 
+#[derive(Clone)]
 pub struct Engine {
     engine: EngineState,
     stack: Stack,
+    config_dir: PathBuf,
+}
+
+impl fmt::Debug for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Engine").finish_non_exhaustive()
+    }
 }
 
 impl Engine {
@@ -30,9 +39,15 @@ impl Engine {
         Engine {
             engine: engine_state,
             stack,
+            config_dir: config_dir.to_owned(),
         }
     }
 
+    /// The directory the specs are evaluated in
+    pub fn config_dir(&self) -> &Path {
+        &self.config_dir
+    }
+
     pub fn fetch(&mut self, contents: &[u8]) -> Result<Record> {
         let mut working_set = StateWorkingSet::new(&self.engine);
         let block = nu_parser::parse(&mut working_set, None, contents, false);
@@ -49,17 +64,21 @@ impl Engine {
         })?
     }
 
-    pub fn execute_closure(&mut self, closure: &Closure) -> Result<()> {
-        eval_block_with_early_return::<WithoutDebug>(
-            &self.engine,
-            &mut self.stack,
-            self.engine.get_block(closure.block_id),
-            Empty,
-        )
-        .map(|_| Ok(()))?
+    /// Runs the closure with the given positional arguments and returns what
+    /// it evaluates to. Output of external commands still goes to the terminal.
+    pub fn execute_closure(&self, closure: &Closure, args: &[Value]) -> Result<Value> {
+        let eval =
+            ClosureEvalOnce::new_preserve_out_dest(&self.engine, &self.stack, closure.to_owned());
+
+        Ok(args
+            .iter()
+            .cloned()
+            .fold(eval, ClosureEvalOnce::add_arg)
+            .run_with_input(Empty)?
+            .into_value(Span::unknown())?)
     }
 
-    pub fn dry_run_closure(&mut self, closure: &Closure) -> Result<()> {
+    pub fn dry_run_closure(&self, closure: &Closure, args: &[Value]) -> Result<()> {
         let source = self
             .engine
             .get_block(closure.block_id)
@@ -68,9 +87,24 @@ impl Engine {
             .map(|source| String::from_utf8_lossy(source))
             .ok_or_else(|| mod_err!("Failed to get the source for closure"))?;
 
+        let args = args
+            .iter()
+            .map(|arg| {
+                nuon::to_nuon(&self.engine, arg, nuon::ToStyle::Raw, None, false)
+                    .map_err(|e| mod_err!(e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
         #[allow(clippy::print_stderr)]
         {
-            eprintln!("DRY RUN CLOSURE> {source}");
+            eprintln!(
+                "DRY RUN CLOSURE> {}",
+                [source.as_ref()]
+                    .into_iter()
+                    .chain(args.iter().map(String::as_str))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
         }
 
         Ok(())
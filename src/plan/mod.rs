@@ -0,0 +1,732 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{Result, anyhow};
+use nu_ansi_term::Color;
+use nu_protocol::{Value, engine::Closure};
+use serde::{Deserialize, Serialize};
+use strum_macros::Display;
+
+use crate::commands::{
+    Perms, dry_run_command, dry_run_command_with_stdin, run_command, run_command_prefixed,
+    run_command_to_stderr, run_command_with_stdin, with_stdout_to_stderr,
+};
+use crate::parser::Engine;
+use crate::{function, nest_errors};
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    AddRemote,
+    Pin,
+    Install,
+    MarkExplicit,
+    /// Kept installed, but only for as long as something depends on it
+    MarkDependency,
+    Unpin,
+    Remove,
+    RemoveRemote,
+}
+
+impl Action {
+    pub const fn is_removal(self) -> bool {
+        matches!(
+            self,
+            Action::MarkDependency | Action::Unpin | Action::Remove | Action::RemoveRemote
+        )
+    }
+
+    const fn sign(self) -> (char, Color) {
+        match self {
+            Action::AddRemote | Action::Pin | Action::Install => ('+', Color::Green),
+            Action::MarkExplicit | Action::MarkDependency => ('~', Color::Yellow),
+            Action::Unpin | Action::Remove | Action::RemoveRemote => ('-', Color::Red),
+        }
+    }
+
+    const fn label(self) -> Option<&'static str> {
+        match self {
+            Action::AddRemote | Action::RemoveRemote => Some("remote"),
+            Action::Pin | Action::Unpin => Some("pin"),
+            Action::MarkExplicit => Some("mark explicit"),
+            Action::MarkDependency => Some("mark dependency"),
+            Action::Install | Action::Remove => None,
+        }
+    }
+}
+
+type CommandAction = fn(&[String], Perms) -> Result<()>;
+
+/// The part of a plan that is going to be applied, so that backends can skip
+/// the queries only the other part needs
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Selection {
+    Additions,
+    Removals,
+    All,
+}
+
+impl Selection {
+    pub const fn additions(self) -> bool {
+        !matches!(self, Selection::Removals)
+    }
+
+    pub const fn removals(self) -> bool {
+        !matches!(self, Selection::Additions)
+    }
+
+    /// Keeps the selected steps of a plan made for more than the selection
+    pub fn select(self, plan: Plan) -> Plan {
+        match self {
+            Selection::Additions => plan.additions(),
+            Selection::Removals => plan.removals(),
+            Selection::All => plan,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    /// Not run, either because the plan was declined or not applied at all
+    Planned,
+    DryRun,
+    Succeeded,
+    Failed,
+    /// Not run because an earlier step of the same backend failed
+    Skipped,
+}
+
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub action: Action,
+    pub packages: Box<[String]>,
+    pub command: Box<[String]>,
+    pub perms: Perms,
+    /// Written to the stdin of the command
+    pub input: Option<String>,
+    /// Run instead of the command, which then only describes the step
+    pub call: Option<Call>,
+}
+
+/// Work done by supac itself rather than by a package manager command
+#[derive(Debug, Clone)]
+pub enum Call {
+    /// A closure from the package spec, along with the engine that parsed it
+    Closure {
+        engine: Arc<Engine>,
+        closure: Closure,
+        args: Box<[Value]>,
+    },
+    /// A request to a backend plugin, written to its stdin
+    Plugin {
+        command: Box<[String]>,
+        request: String,
+    },
+}
+
+impl Call {
+    fn run(&self, dry_run: bool, quiet_stdout: bool) -> Result<()> {
+        match self {
+            Call::Closure {
+                engine,
+                closure,
+                args,
+            } => {
+                let run = || {
+                    if dry_run {
+                        engine.dry_run_closure(closure, args)
+                    } else {
+                        engine.execute_closure(closure, args).map(|_| ())
+                    }
+                };
+
+                if quiet_stdout {
+                    with_stdout_to_stderr(run)
+                } else {
+                    run()
+                }
+            }
+            Call::Plugin { command, request } => {
+                if dry_run {
+                    dry_run_command_with_stdin(command, Perms::User, request)
+                } else {
+                    run_command_with_stdin(command, Perms::User, request).map(|_| ())
+                }
+            }
+        }
+    }
+}
+
+impl Step {
+    /// The command as it is actually run, with sudo if it needs root
+    pub fn argv(&self) -> Box<[&str]> {
+        Some("sudo")
+            .filter(|_| self.perms == Perms::Root)
+            .into_iter()
+            .chain(self.command.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+/// The changes a backend needs to make to match its declaration, computed
+/// up front so that they can be shown and confirmed before anything runs.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+    pub hooks: Vec<Closure>,
+    /// Set when the tool gets root on its own, like AUR helpers calling sudo,
+    /// so its steps are treated like the ones run with root
+    pub elevated: bool,
+    /// Line diffs of what the additions change, shown along with the plan
+    pub diffs: Vec<(String, Box<[String]>)>,
+}
+
+/// Held while a step that may ask for a password runs, so that only one
+/// prompt is on the terminal at a time
+static ROOT_LOCK: Mutex<()> = Mutex::new(());
+
+type RunResult = (Box<[Status]>, Result<()>);
+
+impl Plan {
+    /// Adds a step to the plan, skipping it when there are no packages to act on
+    pub fn push<P, C>(&mut self, action: Action, packages: P, command: C, perms: Perms)
+    where
+        P: IntoIterator,
+        P::Item: Into<String>,
+        C: IntoIterator,
+        C::Item: Into<String>,
+    {
+        self.push_step(action, packages, command, perms, None);
+    }
+
+    /// Like [`Plan::push`], with the input written to the stdin of the command
+    /// when it runs
+    pub fn push_with_input<P, C>(
+        &mut self,
+        action: Action,
+        packages: P,
+        command: C,
+        perms: Perms,
+        input: String,
+    ) where
+        P: IntoIterator,
+        P::Item: Into<String>,
+        C: IntoIterator,
+        C::Item: Into<String>,
+    {
+        self.push_step(action, packages, command, perms, Some(input));
+    }
+
+    fn push_step<P, C>(
+        &mut self,
+        action: Action,
+        packages: P,
+        command: C,
+        perms: Perms,
+        input: Option<String>,
+    ) where
+        P: IntoIterator,
+        P::Item: Into<String>,
+        C: IntoIterator,
+        C::Item: Into<String>,
+    {
+        let packages: Box<[String]> = packages.into_iter().map(Into::into).collect();
+
+        if packages.is_empty() {
+            return;
+        }
+
+        self.steps.push(Step {
+            action,
+            packages,
+            command: command.into_iter().map(Into::into).collect(),
+            perms,
+            input,
+            call: None,
+        });
+    }
+
+    /// Adds a step run by supac itself, skipping it when there are no packages
+    /// to act on
+    pub fn push_call<P>(&mut self, action: Action, packages: P, name: &str, call: Call)
+    where
+        P: IntoIterator,
+        P::Item: Into<String>,
+    {
+        let packages: Box<[String]> = packages.into_iter().map(Into::into).collect();
+
+        if packages.is_empty() {
+            return;
+        }
+
+        self.steps.push(Step {
+            action,
+            command: [name.to_owned()]
+                .into_iter()
+                .chain(packages.iter().cloned())
+                .collect(),
+            packages,
+            perms: Perms::User,
+            input: None,
+            call: Some(call),
+        });
+    }
+
+    pub fn push_diff(&mut self, name: &str, lines: Box<[String]>) {
+        if !lines.is_empty() {
+            self.diffs.push((name.to_owned(), lines));
+        }
+    }
+
+    pub fn push_hook(&mut self, hook: Option<&Closure>) {
+        if let Some(hook) = hook {
+            self.hooks.push(hook.to_owned());
+        }
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.steps.is_empty() && self.hooks.is_empty()
+    }
+
+    /// Keeps the steps that bring in declared packages, along with the hooks
+    pub fn additions(mut self) -> Self {
+        self.steps.retain(|step| !step.action.is_removal());
+        self
+    }
+
+    /// Keeps the steps that get rid of undeclared packages
+    pub fn removals(mut self) -> Self {
+        self.steps.retain(|step| step.action.is_removal());
+        self.hooks.clear();
+        self.diffs.clear();
+        self
+    }
+
+    /// Runs the steps in order, stopping at the first failure. The status of
+    /// every step is returned along with the error.
+    pub fn run(&self, dry_run: bool, quiet_stdout: bool) -> RunResult {
+        let (command_action, success): (CommandAction, _) = if dry_run {
+            (|args, perms| dry_run_command(args, perms), Status::DryRun)
+        } else if quiet_stdout {
+            (
+                |args, perms| run_command_to_stderr(args, perms),
+                Status::Succeeded,
+            )
+        } else {
+            (|args, perms| run_command(args, perms), Status::Succeeded)
+        };
+
+        self.run_steps(success, |step| match (&step.call, &step.input) {
+            (Some(call), _) => call.run(dry_run, quiet_stdout),
+            (None, Some(input)) if dry_run => {
+                dry_run_command_with_stdin(&step.command, step.perms, input)
+            }
+            (None, Some(input)) => {
+                run_command_with_stdin(&step.command, step.perms, input).map(|_| ())
+            }
+            (None, None) => command_action(&step.command, step.perms),
+        })
+    }
+
+    /// Like [`Plan::run`], but with the output of every command prefixed by
+    /// the backend name so that it can run alongside other backends
+    pub fn run_prefixed(&self, backend: &str, quiet_stdout: bool) -> RunResult {
+        self.run_steps(Status::Succeeded, |step| {
+            // Calls print straight to the terminal, there is no output to
+            // prefix
+            if let Some(call) = &step.call {
+                return call.run(false, quiet_stdout);
+            }
+
+            let interactive = self.elevated || step.perms == Perms::Root;
+
+            let _guard = interactive.then(|| {
+                ROOT_LOCK
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+            });
+
+            // The output of a command reading its stdin is captured, there is
+            // none to prefix
+            if let Some(input) = &step.input {
+                return run_command_with_stdin(&step.command, step.perms, input).map(|_| ());
+            }
+
+            run_command_prefixed(
+                &step.command,
+                step.perms,
+                backend,
+                interactive,
+                quiet_stdout,
+            )
+        })
+    }
+
+    fn run_steps<F>(&self, success: Status, mut run_step: F) -> RunResult
+    where
+        F: FnMut(&Step) -> Result<()>,
+    {
+        let mut result = Ok(());
+
+        let statuses = self
+            .steps
+            .iter()
+            .map(|step| {
+                if result.is_err() {
+                    return Status::Skipped;
+                }
+
+                let action = step.action;
+                let packages = step.packages.join(" ");
+
+                match run_step(step) {
+                    Ok(()) => success,
+                    Err(e) => {
+                        result = Err(nest_errors!("Failed to {action} {packages}", e));
+                        Status::Failed
+                    }
+                }
+            })
+            .collect();
+
+        (statuses, result)
+    }
+
+    /// Runs the post hooks, with their stdout sent to stderr when
+    /// `quiet_stdout` is set
+    pub fn run_hooks(&self, engine: &Engine, dry_run: bool, quiet_stdout: bool) -> Result<()> {
+        let run = || {
+            self.hooks.iter().try_for_each(|hook| {
+                if dry_run {
+                    engine.dry_run_closure(hook, &[])
+                } else {
+                    engine.execute_closure(hook, &[]).map(|_| ())
+                }
+            })
+        };
+
+        if quiet_stdout {
+            with_stdout_to_stderr(run)
+        } else {
+            run()
+        }
+        .map_err(|e| nest_errors!("Failed to execute post hooks", e))
+    }
+
+    /// Renders the plan as a diff, one line per package
+    pub fn render(&self, backend: &str, color: bool) -> String {
+        let steps = self.steps.iter().flat_map(|step| {
+            let (sign, sign_color) = step.action.sign();
+            let label = step
+                .action
+                .label()
+                .map(|label| format!(" ({label})"))
+                .unwrap_or_default();
+
+            step.packages.iter().map(move |package| {
+                let line = format!("{sign} {package}{label}");
+
+                if color {
+                    sign_color.paint(line).to_string()
+                } else {
+                    line
+                }
+            })
+        });
+
+        let diffs = self.diffs.iter().flat_map(|(name, lines)| {
+            let lines = lines.iter().map(move |line| {
+                let line_color = match line.chars().next() {
+                    Some('-') => Some(Color::Red),
+                    Some('+') => Some(Color::Green),
+                    _ => None,
+                };
+
+                match line_color {
+                    Some(line_color) if color => format!("    {}", line_color.paint(line)),
+                    _ => format!("    {line}"),
+                }
+            });
+
+            [format!("diff {name}:")].into_iter().chain(lines)
+        });
+
+        let hooks = Some(self.hooks.len())
+            .filter(|hooks| *hooks > 0)
+            .map(|hooks| format!("post hooks: {hooks}"));
+
+        steps
+            .chain(diffs)
+            .chain(hooks)
+            .fold(backend.to_owned() + ":", |acc, line| acc + "\n    " + &line)
+    }
+}
+
+/// Runs the plans, up to `jobs` of them at a time. Dry runs only print, so
+/// they always go one after the other.
+pub fn run_all(
+    plans: &[(&str, Plan)],
+    dry_run: bool,
+    quiet_stdout: bool,
+    jobs: usize,
+) -> Box<[RunResult]> {
+    if dry_run || jobs <= 1 {
+        return plans
+            .iter()
+            .map(|(_, plan)| plan.run(dry_run, quiet_stdout))
+            .collect();
+    }
+
+    let next = AtomicUsize::new(0);
+
+    let mut runs: Vec<_> = thread::scope(|scope| {
+        let workers: Box<[_]> = (0..jobs.min(plans.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut runs = Vec::new();
+
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+
+                        let Some((backend, plan)) = plans.get(index) else {
+                            break runs;
+                        };
+
+                        runs.push((index, plan.run_prefixed(backend, quiet_stdout)));
+                    }
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+
+    runs.sort_by_key(|(index, _)| *index);
+    runs.into_iter().map(|(_, run)| run).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use nu_protocol::Id;
+
+    use super::*;
+
+    #[test]
+    fn push_skips_empty_steps() {
+        let mut plan = Plan::default();
+        let packages: [&str; 0] = [];
+
+        plan.push(Action::Install, packages, ["paru", "--sync"], Perms::User);
+        assert!(plan.is_empty());
+
+        plan.push(
+            Action::Install,
+            ["foo"],
+            ["paru", "--sync", "foo"],
+            Perms::User,
+        );
+        assert_eq!(plan.steps.len(), 1);
+        assert_eq!(*plan.steps[0].packages, ["foo"]);
+        assert_eq!(*plan.steps[0].command, ["paru", "--sync", "foo"]);
+    }
+
+    #[test]
+    fn push_call_describes_closure() {
+        let mut plan = Plan::default();
+        let packages: [&str; 0] = [];
+        let call = Call::Closure {
+            engine: Arc::new(Engine::new(Path::new("."))),
+            closure: Closure {
+                block_id: Id::new(0),
+                captures: vec![],
+            },
+            args: Box::new([]),
+        };
+
+        plan.push_call(Action::Install, packages, "install", call.clone());
+        assert!(plan.is_empty());
+
+        plan.push_call(Action::Install, ["foo", "bar"], "install", call);
+        assert_eq!(*plan.steps[0].command, ["install", "foo", "bar"]);
+        assert!(plan.steps[0].call.is_some());
+    }
+
+    #[test]
+    fn push_with_input_keeps_input() {
+        let mut plan = Plan::default();
+        let packages: [&str; 0] = [];
+
+        plan.push_with_input(
+            Action::Install,
+            packages,
+            ["cat"],
+            Perms::User,
+            "foo".into(),
+        );
+        assert!(plan.is_empty());
+
+        plan.push_with_input(Action::Install, ["foo"], ["cat"], Perms::User, "foo".into());
+        plan.push(Action::Install, ["bar"], ["true"], Perms::User);
+        assert_eq!(plan.steps[0].input.as_deref(), Some("foo"));
+        assert!(plan.steps[1].input.is_none());
+        assert!(plan.run(false, true).1.is_ok());
+    }
+
+    #[test]
+    fn argv_adds_sudo_for_root() {
+        let mut plan = Plan::default();
+        plan.push(
+            Action::Install,
+            ["foo"],
+            ["pacman", "--sync", "foo"],
+            Perms::Root,
+        );
+        plan.push(
+            Action::Install,
+            ["bar"],
+            ["paru", "--sync", "bar"],
+            Perms::User,
+        );
+
+        assert_eq!(*plan.steps[0].argv(), ["sudo", "pacman", "--sync", "foo"]);
+        assert_eq!(*plan.steps[1].argv(), ["paru", "--sync", "bar"]);
+    }
+
+    #[test]
+    fn push_hook_skips_none() {
+        let mut plan = Plan::default();
+        let closure = Closure {
+            block_id: Id::new(0),
+            captures: vec![],
+        };
+
+        plan.push_hook(None);
+        assert!(plan.is_empty());
+
+        plan.push_hook(Some(&closure));
+        assert_eq!(plan.hooks.len(), 1);
+        assert!(!plan.is_empty());
+    }
+
+    #[test]
+    fn render_lists_steps_and_hooks() {
+        let mut plan = Plan::default();
+        plan.push(Action::Install, ["foo", "bar"], ["true"], Perms::User);
+        plan.push(Action::Remove, ["baz"], ["true"], Perms::User);
+        plan.push_hook(Some(&Closure {
+            block_id: Id::new(0),
+            captures: vec![],
+        }));
+
+        assert_eq!(
+            plan.render("Arch", false),
+            "Arch:\n    + foo\n    + bar\n    - baz\n    post hooks: 1"
+        );
+    }
+
+    #[test]
+    fn render_labels_actions() {
+        let mut plan = Plan::default();
+        plan.push(Action::AddRemote, ["flathub"], ["true"], Perms::User);
+        plan.push(Action::MarkExplicit, ["foo"], ["true"], Perms::User);
+        plan.push(Action::Unpin, ["bar"], ["true"], Perms::User);
+
+        assert_eq!(
+            plan.render("Flatpak", false),
+            "Flatpak:\n    + flathub (remote)\n    ~ foo (mark explicit)\n    - bar (pin)"
+        );
+    }
+
+    #[test]
+    fn render_shows_diffs() {
+        let mut plan = Plan::default();
+        plan.push(Action::Install, ["~/.gitconfig"], ["true"], Perms::User);
+        plan.push_diff("~/.gitconfig", Box::new([]));
+        assert!(plan.diffs.is_empty());
+
+        plan.push_diff(
+            "~/.gitconfig",
+            Box::new(["- name = foo".to_owned(), "+ name = bar".to_owned()]),
+        );
+
+        assert_eq!(
+            plan.render("Files", false),
+            "Files:\n    + ~/.gitconfig\n    diff ~/.gitconfig:\n        - name = foo\n        + name = bar"
+        );
+        assert!(plan.removals().diffs.is_empty());
+    }
+
+    #[test]
+    fn additions_and_removals_split() {
+        let mut plan = Plan::default();
+        plan.push(Action::Install, ["foo"], ["true"], Perms::User);
+        plan.push(Action::Remove, ["bar"], ["true"], Perms::User);
+        plan.push_hook(Some(&Closure {
+            block_id: Id::new(0),
+            captures: vec![],
+        }));
+
+        let additions = plan.clone().additions();
+        assert_eq!(additions.steps.len(), 1);
+        assert_eq!(additions.steps[0].action, Action::Install);
+        assert_eq!(additions.hooks.len(), 1);
+
+        let removals = plan.removals();
+        assert_eq!(removals.steps.len(), 1);
+        assert_eq!(removals.steps[0].action, Action::Remove);
+        assert!(removals.hooks.is_empty());
+    }
+
+    #[test]
+    fn selection_keeps_its_side() {
+        let mut plan = Plan::default();
+        plan.push(Action::Install, ["foo"], ["true"], Perms::User);
+        plan.push(Action::Remove, ["bar"], ["true"], Perms::User);
+
+        assert!(Selection::Additions.additions() && !Selection::Additions.removals());
+        assert!(!Selection::Removals.additions() && Selection::Removals.removals());
+        assert_eq!(Selection::All.select(plan.clone()).steps.len(), 2);
+        assert_eq!(
+            Selection::Removals.select(plan).steps[0].action,
+            Action::Remove
+        );
+    }
+
+    #[test]
+    fn run_all_keeps_plan_order() {
+        let mut slow = Plan::default();
+        slow.push(Action::Install, ["foo"], ["sleep", "0.2"], Perms::User);
+        slow.push(Action::Remove, ["bar"], ["true"], Perms::User);
+
+        let mut failing = Plan::default();
+        failing.push(Action::Install, ["baz"], ["false"], Perms::User);
+        failing.push(Action::Remove, ["qux"], ["true"], Perms::User);
+
+        let mut fast = Plan::default();
+        fast.push(Action::Install, ["quux"], ["true"], Perms::User);
+
+        let plans = [("Slow", slow), ("Failing", failing), ("Fast", fast)];
+        let runs = run_all(&plans, false, true, 3);
+
+        let statuses: Box<[_]> = runs.iter().map(|(statuses, _)| &**statuses).collect();
+        assert_eq!(
+            *statuses,
+            [
+                &[Status::Succeeded, Status::Succeeded][..],
+                &[Status::Failed, Status::Skipped],
+                &[Status::Succeeded],
+            ]
+        );
+        assert!(runs[0].1.is_ok());
+        assert!(runs[1].1.is_err());
+        assert!(runs[2].1.is_ok());
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::fs::read;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use nu_protocol::{Record, Value};
+
+use crate::backends::{
+    AppImage, Apt, Arch, Backend, Brew, Cargo, Custom, Dnf, Files, Flatpak, Gem, Go, Nix, Npm,
+    Opam, Rustup, Snap, Uv, VsCode, find_plugin, validate_plugin,
+};
+use crate::commands::find_executable;
+use crate::parser::Engine;
+use crate::{
+    BackendArgs, ValidateCommand, backend_names, backend_validate, config, for_all_backends,
+    function, mod_err,
+};
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub source: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error<S: Display, M: Display>(source: S, message: M) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            source: source.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn warning<S: Display, M: Display>(source: S, message: M) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            source: source.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+
+        let message = self.message.lines().collect::<Box<[_]>>().join("\n    ");
+
+        write!(f, "{severity}: {}: {message}", self.source)
+    }
+}
+
+pub fn run(
+    config_dir: &Path,
+    config_file: &Path,
+    backend_args: &BackendArgs,
+    opts: &ValidateCommand,
+) -> Result<()> {
+    let mut diagnostics = Vec::new();
+
+    let config = match read(config_file)
+        .map_err(|e| anyhow!(e))
+        .and_then(|contents| Engine::new(config_dir).fetch(&contents))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            diagnostics.push(Diagnostic::error("config.nu", e));
+            Record::new()
+        }
+    };
+
+    diagnostics.extend(config::validate(&config));
+
+    let package_nu = [config_dir.as_os_str(), OsStr::new("package.nu")].join(OsStr::new("/"));
+
+    match read(package_nu)
+        .map_err(|e| anyhow!(e))
+        .and_then(|contents| Engine::new(config_dir).fetch(&contents))
+    {
+        Ok(mut packages) => {
+            let plugins: Box<[_]> = packages
+                .columns()
+                .filter_map(|name| Some((name.to_owned(), find_plugin(name)?)))
+                .collect();
+
+            let known: Box<[_]> = for_all_backends!(backend_names,)
+                .into_iter()
+                .chain(plugins.iter().map(|(name, _)| name.as_str()))
+                .collect();
+
+            diagnostics.extend(unknown_keys(&packages, &known, "package.nu"));
+
+            // Malformed values are already reported by the config checks
+            let disabled = config::get_disabled_backends(&config).unwrap_or_default();
+            packages.retain(|backend, _| backend_args.is_enabled(backend, &disabled));
+
+            diagnostics.extend(for_all_backends!(backend_validate, packages, config,).concat());
+            diagnostics.extend(plugins.iter().flat_map(|(name, executable)| {
+                packages
+                    .get(name)
+                    .map(|value| validate_plugin(name, executable, value, &config))
+                    .unwrap_or_default()
+            }));
+        }
+        Err(e) => diagnostics.push(Diagnostic::error("package.nu", e)),
+    }
+
+    report(&diagnostics, opts)
+}
+
+pub fn validate_backend<B: Backend>(name: &str, value: &Value, config: &Record) -> Vec<Diagnostic> {
+    let value = match value.as_record() {
+        Ok(value) => value,
+        Err(e) => return vec![Diagnostic::error(name, e)],
+    };
+
+    let mut diagnostics = B::validate(value, config);
+
+    match B::executable(config) {
+        Ok(executable) if find_executable(&executable).is_none() => diagnostics.push(
+            Diagnostic::error(name, format!("executable `{executable}` not found in PATH")),
+        ),
+        Ok(executable) => log::info!("Found {executable} for {name}"),
+        Err(e) => diagnostics.push(Diagnostic::error(name, e)),
+    }
+
+    let has_errors = diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error);
+
+    // The constructor stops at the first error, so it is only worth running
+    // when the detailed checks did not already find what it would report
+    if !has_errors && let Err(e) = B::new(value, config) {
+        diagnostics.push(Diagnostic::error(name, e));
+    }
+
+    diagnostics
+}
+
+pub fn unknown_keys(record: &Record, known_keys: &[&str], source: &str) -> Vec<Diagnostic> {
+    record
+        .columns()
+        .filter(|key| !known_keys.contains(&key.as_str()))
+        .map(|key| Diagnostic::warning(source, format!("unknown key `{key}`")))
+        .collect()
+}
+
+pub fn duplicates<'a, I>(names: I, source: &str) -> Vec<Diagnostic>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut seen = HashSet::new();
+
+    names
+        .into_iter()
+        .filter(|name| !seen.insert(*name))
+        .map(|name| Diagnostic::error(source, format!("`{name}` is declared more than once")))
+        .collect()
+}
+
+/// Parses every record in the list at `key`, reporting type errors, unknown
+/// keys and duplicate names instead of stopping at the first problem.
+pub fn check_records<T, F>(
+    record: &Record,
+    key: &str,
+    source: &str,
+    known_keys: &[&str],
+    parse: F,
+) -> (Vec<(String, T)>, Vec<Diagnostic>)
+where
+    F: Fn(&Value) -> Result<(String, T)>,
+{
+    let source = [source, key].join(".");
+
+    let values = match record.get(key).map(Value::as_list) {
+        Some(Ok(values)) => values,
+        Some(Err(e)) => return (Vec::new(), vec![Diagnostic::error(source, e)]),
+        None => return (Vec::new(), Vec::new()),
+    };
+
+    let mut diagnostics = Vec::new();
+    let mut parsed = Vec::new();
+
+    for (index, value) in values.iter().enumerate() {
+        let source = format!("{source}[{index}]");
+
+        if let Ok(spec) = value.as_record() {
+            diagnostics.extend(unknown_keys(spec, known_keys, &source));
+        }
+
+        match parse(value) {
+            Ok(spec) => parsed.push(spec),
+            Err(e) => diagnostics.push(Diagnostic::error(source, e)),
+        }
+    }
+
+    diagnostics.extend(duplicates(
+        parsed.iter().map(|(name, _)| name.as_str()),
+        &source,
+    ));
+
+    (parsed, diagnostics)
+}
+
+fn report(diagnostics: &[Diagnostic], opts: &ValidateCommand) -> Result<()> {
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+
+    #[allow(clippy::print_stdout)]
+    {
+        diagnostics
+            .iter()
+            .for_each(|diagnostic| println!("{diagnostic}"));
+
+        if diagnostics.is_empty() {
+            println!("No problems found");
+        } else {
+            println!("Found {errors} error(s) and {warnings} warning(s)");
+        }
+    }
+
+    if errors > 0 || (opts.strict && warnings > 0) {
+        Err(mod_err!("Validation failed"))
+    } else {
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! backend_validate {
+    ($packages:ident, $config:ident, $($backend:ident),*) => {
+        [$(
+            match $packages.get(stringify!($backend)) {
+                Some(value) => {
+                    $crate::validate::validate_backend::<$backend>(stringify!($backend), value, &$config)
+                }
+                None => Vec::new(),
+            },
+        )*]
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use nu_protocol::Span;
+
+    use super::*;
+
+    #[test]
+    fn unknown_keys_reported() {
+        let record = Record::from_raw_cols_vals(
+            ["package", "pakage"]
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+            vec![
+                Value::string("foo", Span::test_data()),
+                Value::string("bar", Span::test_data()),
+            ],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let diagnostics = unknown_keys(&record, &["package"], "Arch.packages[0]");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert!(diagnostics[0].message.contains("pakage"));
+    }
+
+    #[test]
+    fn duplicates_reported_once_per_repeat() {
+        let diagnostics = duplicates(["foo", "bar", "foo", "foo"], "Arch.packages");
+        assert_eq!(diagnostics.len(), 2);
+        assert!(
+            diagnostics
+                .iter()
+                .all(|diagnostic| diagnostic.severity == Severity::Error)
+        );
+    }
+
+    #[test]
+    fn check_records_collects_all_errors() {
+        let values = ["foo", "bar"]
+            .into_iter()
+            .map(|name| Value::string(name, Span::test_data()))
+            .chain([Value::bool(true, Span::test_data())])
+            .chain([Value::string("foo", Span::test_data())])
+            .collect();
+
+        let record = Record::from_raw_cols_vals(
+            vec!["packages".to_owned()],
+            vec![Value::list(values, Span::test_data())],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let (parsed, diagnostics) = check_records(&record, "packages", "Arch", &[], |value| {
+            Ok((value.as_str()?.to_owned(), ()))
+        });
+
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].source, "Arch.packages[2]");
+        assert_eq!(diagnostics[1].source, "Arch.packages");
+    }
+
+    #[test]
+    fn check_records_not_list() {
+        let record = Record::from_raw_cols_vals(
+            vec!["packages".to_owned()],
+            vec![Value::string("foo", Span::test_data())],
+            Span::test_data(),
+            Span::test_data(),
+        )
+        .unwrap();
+
+        let (parsed, diagnostics) = check_records(&record, "packages", "Arch", &[], |value| {
+            Ok((value.as_str()?.to_owned(), ()))
+        });
+
+        assert!(parsed.is_empty());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+    }
+}